@@ -22,6 +22,10 @@ impl <BH: BlockHandler> BlockHandlerWrapper<BH> {
 pub trait BlockHandler: Send + Sync {
     fn write_blocks(&self, blocks: Vec<Block>) -> Result<()>;
     fn get_blocks(&self, blocks_name: Vec<&str>) -> Result<Vec<Block>>;
+    /// missing blocks are ignored
+    fn delete_blocks(&self, blocks_name: Vec<&str>) -> Result<()>;
+    /// sizes in bytes, without reading the blocks
+    fn get_blocks_size(&self, blocks_name: Vec<&str>) -> Result<Vec<u64>>;
 }
 
 pub struct Block {
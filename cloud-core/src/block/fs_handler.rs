@@ -48,6 +48,25 @@ impl BlockHandler for FsHandler {
 
         Ok(blocks)
     }
-    
+
+    fn delete_blocks(&self, blocks_name: Vec<&str>) -> Result<()> {
+        for block_name in blocks_name {
+            let path = self.target_dir.join(block_path_by_filename(block_name.to_string())?);
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn get_blocks_size(&self, blocks_name: Vec<&str>) -> Result<Vec<u64>> {
+        let mut blocks_size = Vec::new();
+        for block_name in blocks_name {
+            let path = self.target_dir.join(block_path_by_filename(block_name.to_string())?);
+            blocks_size.push(std::fs::metadata(path)?.len());
+        }
+        Ok(blocks_size)
+    }
 }
 
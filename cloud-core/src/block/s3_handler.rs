@@ -19,5 +19,14 @@ impl BlockHandler for S3Handler {
     fn get_blocks(&self, blocks_name: Vec<&str>) -> Result<Vec<Block>> {
         unimplemented!()
     }
+
+    // called on the error and cleanup paths too, which must not panic
+    fn delete_blocks(&self, _blocks_name: Vec<&str>) -> Result<()> {
+        Err(anyhow::anyhow!("deleting blocks is not supported by the s3 block handler yet"))
+    }
+
+    fn get_blocks_size(&self, _blocks_name: Vec<&str>) -> Result<Vec<u64>> {
+        Err(anyhow::anyhow!("block sizes are not supported by the s3 block handler yet"))
+    }
 }
 
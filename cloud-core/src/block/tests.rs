@@ -20,3 +20,28 @@ fn test_fs_handler() {
     let blocks = fs_handler.get_blocks(blocks_name).unwrap();
     assert_eq!(blocks[0].data, content);
 }
+
+#[test]
+fn test_fs_handler_delete_blocks() {
+    let uuid = Uuid::now_v7().to_string();
+    let block = Block::new(uuid.clone(), Bytes::from("Hello World"));
+
+    let fs_handler = FsHandler::new(".");
+    fs_handler.write_blocks(vec![block]).unwrap();
+    fs_handler.delete_blocks(vec![uuid.as_str()]).unwrap();
+    assert!(fs_handler.get_blocks(vec![uuid.as_str()]).is_err());
+
+    // deleting a missing block is not an error
+    fs_handler.delete_blocks(vec![uuid.as_str()]).unwrap();
+}
+
+#[test]
+fn test_fs_handler_get_blocks_size() {
+    let uuid = Uuid::now_v7().to_string();
+    let block = Block::new(uuid.clone(), Bytes::from("Hello World"));
+
+    let fs_handler = FsHandler::new(".");
+    fs_handler.write_blocks(vec![block]).unwrap();
+    assert_eq!(fs_handler.get_blocks_size(vec![uuid.as_str()]).unwrap(), vec![11]);
+    fs_handler.delete_blocks(vec![uuid.as_str()]).unwrap();
+}
@@ -2,3 +2,9 @@ pub mod users;
 pub mod files;
 pub mod file_histories;
 pub mod workspaces;
+pub mod upload_sessions;
+pub mod refresh_tokens;
+pub mod api_tokens;
+pub mod recovery_codes;
+pub mod audit_logs;
+pub mod invite_codes;
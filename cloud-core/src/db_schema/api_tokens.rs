@@ -0,0 +1,105 @@
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use uuid::Uuid;
+
+// last_used_at is written at most this often, not on every request
+const LAST_USED_PRECISION: i64 = 60;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct ApiTokens {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    // sha-256 of the token, in hex
+    pub token_hash: String,
+    // the start of the token, shown in lists
+    pub prefix: String,
+    // read, upload or write
+    pub scopes: Vec<String>,
+    // the only workspace the token can access
+    pub ws_id: Option<Uuid>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+impl ApiTokens {
+    fn from_row(row: &PgRow) -> ApiTokens {
+        ApiTokens {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            name: row.get("name"),
+            token_hash: row.get("token_hash"),
+            prefix: row.get("prefix"),
+            scopes: row.get("scopes"),
+            ws_id: row.get("ws_id"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+            last_used_at: row.get("last_used_at"),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO api_tokens (id, user_id, name, token_hash, prefix, scopes, ws_id, created_at, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(&self.name)
+        .bind(&self.token_hash)
+        .bind(&self.prefix)
+        .bind(&self.scopes)
+        .bind(self.ws_id)
+        .bind(self.created_at)
+        .bind(self.expires_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    // the tokens of a user, the newest first
+    pub async fn list(user_id: Uuid, pool: &PgPool) -> Result<Vec<ApiTokens>, sqlx::Error> {
+        let rows = sqlx::query("SELECT * FROM api_tokens WHERE user_id = $1 ORDER BY created_at DESC, id DESC")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.iter().map(ApiTokens::from_row).collect())
+    }
+
+    /// The unexpired token with the hash of a user who isn't disabled, its last use is recorded.
+    pub async fn find_valid(token_hash: &str, now: i64, pool: &PgPool) -> Result<Option<ApiTokens>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT t.* FROM api_tokens t JOIN users u ON u.id = t.user_id
+             WHERE t.token_hash = $1 AND (t.expires_at IS NULL OR t.expires_at > $2) AND NOT u.disabled",
+        )
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+        let mut token = match row {
+            Some(row) => ApiTokens::from_row(&row),
+            None => return Ok(None),
+        };
+
+        if token.last_used_at.is_none_or(|last_used_at| last_used_at + LAST_USED_PRECISION <= now) {
+            sqlx::query("UPDATE api_tokens SET last_used_at = $1 WHERE id = $2")
+                .bind(now)
+                .bind(token.id)
+                .execute(pool)
+                .await?;
+            token.last_used_at = Some(now);
+        }
+        Ok(Some(token))
+    }
+
+    // revoke a token of the user, false if the user has no such token
+    pub async fn delete(id: Uuid, user_id: Uuid, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("DELETE FROM api_tokens WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,42 @@
+use sqlx::postgres::PgPool;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct AuditLogs {
+    pub id: Uuid,
+    // none for the events of an ip address
+    pub user_id: Option<Uuid>,
+    pub event: String,
+    pub ip: Option<String>,
+    pub detail: String,
+    pub created_at: i64,
+}
+
+impl AuditLogs {
+    pub fn new(user_id: Option<Uuid>, event: &str, ip: Option<String>, detail: String, created_at: i64) -> Self {
+        Self {
+            id: Uuid::now_v7(),
+            user_id,
+            event: event.to_string(),
+            ip,
+            detail,
+            created_at,
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO audit_logs (id, user_id, event, ip, detail, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(&self.event)
+        .bind(&self.ip)
+        .bind(&self.detail)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
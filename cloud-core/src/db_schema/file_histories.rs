@@ -1,13 +1,17 @@
 use sqlx::postgres::{PgPool, PgRow};
 use sqlx::{FromRow, Row};
+use std::collections::HashMap;
+use uuid::Uuid;
 
 #[derive(Debug, FromRow)]
 pub struct FileHistories {
     id: i64,
     fid: i64,
     file_version: i64,
-    slices: Vec<String>,
-    slices_hash: Vec<String>,
+    pub slices: Vec<String>,
+    pub slices_hash: Vec<String>,
+    // sha256 of the whole file, unknown for some old or resumable uploads
+    pub file_hash: Option<String>,
     //created_at: DateTime<Utc>,
     //updated_at: DateTime<Utc>,
 }
@@ -20,6 +24,7 @@ impl FileHistories {
             file_version: row.get("file_version"),
             slices: row.get("slices"),
             slices_hash: row.get("slices_hash"),
+            file_hash: row.get("file_hash"),
         }
     }
 
@@ -32,8 +37,9 @@ impl FileHistories {
         Ok(FileHistories::from_row(&row))
     }
 
+    // the latest version of file `fid`
     pub async fn find_by_fid(fid: i64, pool: &PgPool) -> Result<FileHistories, sqlx::Error> {
-        let row = sqlx::query("SELECT * FROM file_histories WHERE fid = $1")
+        let row = sqlx::query("SELECT * FROM file_histories WHERE fid = $1 ORDER BY file_version DESC LIMIT 1")
             .bind(fid)
             .fetch_one(pool)
             .await?;
@@ -41,6 +47,64 @@ impl FileHistories {
         Ok(FileHistories::from_row(&row))
     }
 
+    // the latest version of each of the files, by fid
+    pub async fn find_latest(fids: &[i64], pool: &PgPool) -> Result<HashMap<i64, FileHistories>, sqlx::Error> {
+        let rows = sqlx::query("SELECT DISTINCT ON (fid) * FROM file_histories WHERE fid = ANY($1) \
+            ORDER BY fid, file_version DESC")
+            .bind(fids)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| {
+            let history = FileHistories::from_row(row);
+            (history.fid, history)
+        }).collect())
+    }
+
+    // the latest version of a file of `uid` with this content,
+    // so the content doesn't need to be uploaded again
+    pub async fn find_by_content(
+        uid: Uuid,
+        file_hash: &str,
+        size: i64,
+        pool: &PgPool,
+    ) -> Result<Option<FileHistories>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT h.* FROM file_histories h JOIN files f ON f.id = h.fid \
+            WHERE f.uid = $1 AND NOT f.is_deleted AND NOT f.is_dir AND f.size = $2 \
+            AND h.file_hash = $3 \
+            AND h.file_version = (SELECT max(file_version) FROM file_histories WHERE fid = f.id) \
+            LIMIT 1",
+        )
+        .bind(uid)
+        .bind(size)
+        .bind(file_hash)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.as_ref().map(FileHistories::from_row))
+    }
+
+    // names of the stored slices with these hashes in files of `uid`, by hash
+    pub async fn find_slices(
+        uid: Uuid,
+        slices_hash: &[String],
+        pool: &PgPool,
+    ) -> Result<HashMap<String, String>, sqlx::Error> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT DISTINCT ON (s.hash) s.hash, s.name \
+            FROM file_histories h JOIN files f ON f.id = h.fid, \
+            unnest(h.slices, h.slices_hash) AS s(name, hash) \
+            WHERE f.uid = $1 AND NOT f.is_deleted AND s.hash = ANY($2)",
+        )
+        .bind(uid)
+        .bind(slices_hash)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
     pub async fn insert(
         fid: i64,
         file_version: i64,
@@ -1,10 +1,105 @@
 //use crate::db_schema::file_history::FileHistory;
-use sqlx::postgres::{PgPool, PgRow};
-use sqlx::{FromRow, Row};
+use crate::db_schema::file_histories::FileHistories;
+use crate::error::Error;
+use sqlx::postgres::{PgPool, PgRow, Postgres};
+use sqlx::{FromRow, QueryBuilder, Row, Transaction};
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
 
 //use super::file_history;
 
+pub const ROOT_DIR_ID: i64 = -1;
+
+/// Names are stored and looked up in unicode NFC, so a name typed with a combining
+/// accent is the same name as the one with the precomposed character.
+/// The comparison itself goes through `name_key`, which is set by a trigger and
+/// is the lower case name in a case-insensitive workspace.
+pub fn normalize_name(filename: &str) -> String {
+    filename.nfc().collect()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    /// by extension, then by name
+    Type,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Options to list a dir page by page.
+///
+/// The cursor is the id of the last entry of the previous page, entries are ordered by
+/// the sort keys and then by id, so every entry has a stable position.
+#[derive(Debug, Default)]
+pub struct ListQuery {
+    pub sort_by: SortBy,
+    pub order: SortOrder,
+    pub dirs_first: bool,
+    pub is_dir: Option<bool>,
+    pub ext: Option<String>,
+    /// unix timestamp in seconds
+    pub modified_after: Option<i64>,
+    /// unix timestamp in seconds
+    pub modified_before: Option<i64>,
+    pub cursor: Option<i64>,
+    pub limit: i64,
+}
+
+impl ListQuery {
+    // sort keys as sql expressions, id comes last as the tie breaker
+    fn sort_keys(&self) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.dirs_first {
+            // dirs stay in front whatever the order is
+            keys.push(match self.order {
+                SortOrder::Asc => "(NOT is_dir)",
+                SortOrder::Desc => "is_dir",
+            });
+        }
+        match self.sort_by {
+            SortBy::Name => keys.push("filename"),
+            SortBy::Size => keys.push("(CASE WHEN is_dir THEN total_size ELSE size END)"),
+            SortBy::Mtime => keys.push("updated_at"),
+            SortBy::Type => {
+                keys.push("lower(coalesce(substring(filename from '\\.([^.]*)$'), ''))");
+                keys.push("filename");
+            },
+        }
+        keys.push("id");
+        keys
+    }
+
+    fn push_filters<'a>(&'a self, builder: &mut QueryBuilder<'a, Postgres>) {
+        if let Some(is_dir) = self.is_dir {
+            builder.push(" and is_dir = ").push_bind(is_dir);
+        }
+        if let Some(ext) = &self.ext {
+            let suffix = format!(".{}", ext.trim_start_matches('.').to_lowercase());
+            builder.push(" and right(lower(filename), ")
+                .push_bind(suffix.chars().count() as i32)
+                .push(") = ")
+                .push_bind(suffix);
+        }
+        if let Some(modified_after) = self.modified_after {
+            builder.push(" and updated_at >= to_timestamp(").push_bind(modified_after as f64).push(")::timestamp");
+        }
+        if let Some(modified_before) = self.modified_before {
+            builder.push(" and updated_at < to_timestamp(").push_bind(modified_before as f64).push(")::timestamp");
+        }
+    }
+}
+
 #[derive(Debug, FromRow)]
 pub struct Files {
     pub id: i64,
@@ -16,6 +111,10 @@ pub struct Files {
     pub size: i64,
     pub is_dir: bool,
     pub version: i64,
+    /// size of all files below a dir
+    pub total_size: i64,
+    /// number of files below a dir
+    pub file_count: i64,
     //created_at: DateTime<Utc>,
     //updated_at: DateTime<Utc>,
 }
@@ -23,7 +122,7 @@ pub struct Files {
 impl Files {
     pub fn root_dir(ws_id: Uuid, uid: Uuid, filename: String) -> Self {
         Files {
-            id: -1,
+            id: ROOT_DIR_ID,
             uid,
             ws_id,
             filename,
@@ -32,6 +131,8 @@ impl Files {
             is_dir: true,
             is_deleted: false,
             version: 1,
+            total_size: 0,
+            file_count: 0,
         }
     }
 
@@ -40,12 +141,14 @@ impl Files {
             id,
             uid,
             ws_id,
-            filename,
+            filename: normalize_name(&filename),
             parent_dir_id,
             size,
             is_dir,
             is_deleted: false,
             version: 1,
+            total_size: 0,
+            file_count: 0,
         }
     }
 
@@ -60,7 +163,81 @@ impl Files {
             is_dir: row.get("is_dir"),
             is_deleted: row.get("is_deleted"),
             version: row.get("version"),
+            total_size: row.get("total_size"),
+            file_count: row.get("file_count"),
+        }
+    }
+
+    /// size of a file, or total size of all files below a dir
+    pub fn disk_usage(&self) -> i64 {
+        match self.is_dir {
+            true => self.total_size,
+            false => self.size,
+        }
+    }
+
+    /// what this entry adds to the size and the file count of its ancestors
+    pub fn usage(&self) -> (i64, i64) {
+        match self.is_dir {
+            true => (self.total_size, self.file_count),
+            false => (self.size, 1),
+        }
+    }
+
+    /// add `size` and `file_count` to dir `dir_id` and all its ancestors, up to a deleted dir:
+    /// the usage of a deleted dir was taken from its ancestors already, with its children
+    pub async fn update_dir_usage(
+        dir_id: i64,
+        size: i64,
+        file_count: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        if dir_id == ROOT_DIR_ID || (size == 0 && file_count == 0) {
+            return Ok(());
         }
+
+        sqlx::query("WITH RECURSIVE ancestors AS ( \
+            SELECT id, parent_dir_id FROM files WHERE id = $1 AND NOT is_deleted \
+            UNION ALL \
+            SELECT f.id, f.parent_dir_id FROM files f JOIN ancestors a ON f.id = a.parent_dir_id \
+            WHERE NOT f.is_deleted \
+        ) UPDATE files SET total_size = total_size + $2, file_count = file_count + $3 \
+        WHERE id IN (SELECT id FROM ancestors)")
+            .bind(dir_id)
+            .bind(size)
+            .bind(file_count)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    // size and number of files of the root dir of a workspace
+    pub async fn get_root_usage(ws_id: Uuid, pool: &PgPool) -> Result<(i64, i64), sqlx::Error> {
+        let row = sqlx::query("SELECT \
+            coalesce(sum(CASE WHEN is_dir THEN total_size ELSE size END), 0)::bigint AS total_size, \
+            coalesce(sum(CASE WHEN is_dir THEN file_count ELSE 1 END), 0)::bigint AS file_count \
+            FROM files WHERE ws_id = $1 and parent_dir_id = $2 and is_deleted = false")
+            .bind(ws_id)
+            .bind(ROOT_DIR_ID)
+            .fetch_one(pool)
+            .await?;
+        Ok((row.get("total_size"), row.get("file_count")))
+    }
+
+    // size of all files of a user, in every workspace; a file under a deleted dir
+    // was deleted with it, so only the files reached from the root count
+    pub async fn get_user_usage(uid: Uuid, pool: &PgPool) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("WITH RECURSIVE paths AS ( \
+            SELECT size, parent_dir_id FROM files \
+            WHERE uid = $1 and is_dir = false and is_deleted = false \
+            UNION ALL \
+            SELECT p.size, f.parent_dir_id FROM paths p JOIN files f ON f.id = p.parent_dir_id \
+            WHERE f.is_deleted = false \
+        ) SELECT coalesce(sum(size), 0)::bigint FROM paths WHERE parent_dir_id = $2")
+            .bind(uid)
+            .bind(ROOT_DIR_ID)
+            .fetch_one(pool)
+            .await
     }
 
     // check if file exists
@@ -70,15 +247,77 @@ impl Files {
         filename: &str,
         pool: &PgPool,
     ) -> Result<Files, sqlx::Error> {
-        let row = sqlx::query("SELECT * FROM files WHERE parent_dir_id = $1 and uid = $2 and filename = $3 and is_deleted = false")
+        let row = sqlx::query("SELECT * FROM files WHERE parent_dir_id = $1 and uid = $2 \
+        and name_key = file_name_key(ws_id, $3) and is_deleted = false")
             .bind(parent_dir_id)
             .bind(uid)
-            .bind(filename)
+            .bind(normalize_name(filename))
             .fetch_one(pool)
             .await?;
         Ok(Files::from_row(&row))
     }
 
+    // find a live file or dir by name in a dir of the workspace
+    pub async fn find_by_name(
+        ws_id: Uuid,
+        parent_dir_id: i64,
+        filename: &str,
+        pool: &PgPool,
+    ) -> Result<Option<Files>, sqlx::Error> {
+        let row = sqlx::query("SELECT * FROM files WHERE ws_id = $1 and parent_dir_id = $2 \
+        and name_key = file_name_key($1, $3) and is_deleted = false")
+            .bind(ws_id)
+            .bind(parent_dir_id)
+            .bind(normalize_name(filename))
+            .fetch_optional(pool)
+            .await?;
+        Ok(row.map(|row| Files::from_row(&row)))
+    }
+
+    // resolve a path like ["photos", "2023", "a.jpg"] from the root dir of the workspace
+    pub async fn get_by_path(
+        ws_id: Uuid,
+        components: &[String],
+        pool: &PgPool,
+    ) -> Result<Option<Files>, sqlx::Error> {
+        let components: Vec<String> = components.iter().map(|component| normalize_name(component)).collect();
+        let row = sqlx::query("WITH RECURSIVE keys AS ( \
+            SELECT array(SELECT file_name_key($1, name) FROM unnest($3::text[]) WITH ORDINALITY AS c(name, i) \
+            ORDER BY i) AS names \
+        ), walk AS ( \
+            SELECT f.*, 1 AS depth FROM files f, keys k WHERE f.ws_id = $1 and f.parent_dir_id = $2 \
+            and f.name_key = k.names[1] and f.is_deleted = false \
+            UNION ALL \
+            SELECT f.*, w.depth + 1 FROM files f JOIN walk w ON f.parent_dir_id = w.id, keys k \
+            WHERE w.depth < cardinality($3) and f.ws_id = $1 and f.name_key = k.names[w.depth + 1] \
+            and f.is_deleted = false \
+        ) SELECT * FROM walk WHERE depth = cardinality($3)")
+            .bind(ws_id)
+            .bind(ROOT_DIR_ID)
+            .bind(&components)
+            .fetch_optional(pool)
+            .await?;
+        Ok(row.map(|row| Files::from_row(&row)))
+    }
+
+    // get the full path of a file or dir, like "/photos/2023/a.jpg"
+    pub async fn get_path(id: i64, pool: &PgPool) -> Result<String, sqlx::Error> {
+        if id == ROOT_DIR_ID {
+            return Ok("/".to_string());
+        }
+
+        let path: Option<String> = sqlx::query_scalar("WITH RECURSIVE ancestors AS ( \
+            SELECT id, parent_dir_id, filename, 0 AS depth FROM files WHERE id = $1 \
+            UNION ALL \
+            SELECT f.id, f.parent_dir_id, f.filename, a.depth + 1 FROM files f \
+            JOIN ancestors a ON f.id = a.parent_dir_id \
+        ) SELECT '/' || string_agg(filename, '/' ORDER BY depth DESC) FROM ancestors")
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+        path.ok_or(sqlx::Error::RowNotFound)
+    }
+
     // get file with file history by uid, id
     pub async fn get_by_uid_and_id(
         uid: Uuid,
@@ -114,6 +353,76 @@ impl Files {
         Ok(files)
     }
 
+    // list one page of a dir, return the page and the number of all matched entries,
+    // none when the cursor isn't an entry of the dir
+    pub async fn list_dir(
+        ws_id: Uuid,
+        parent_dir_id: i64,
+        uid: Uuid,
+        query: &ListQuery,
+        pool: &PgPool,
+    ) -> Result<Option<(Vec<Files>, i64)>, sqlx::Error> {
+        // a deleted entry still works as a cursor, its page goes on after it
+        if let Some(cursor) = query.cursor {
+            let found = sqlx::query("SELECT id FROM files WHERE id = $1 and ws_id = $2 and parent_dir_id = $3 and uid = $4")
+                .bind(cursor)
+                .bind(ws_id)
+                .bind(parent_dir_id)
+                .bind(uid)
+                .fetch_optional(pool)
+                .await?;
+            if found.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let mut builder = QueryBuilder::new("SELECT count(*) FROM files WHERE ws_id = ");
+        builder.push_bind(ws_id)
+            .push(" and parent_dir_id = ").push_bind(parent_dir_id)
+            .push(" and uid = ").push_bind(uid)
+            .push(" and is_deleted = false");
+        query.push_filters(&mut builder);
+        let total: i64 = builder.build().fetch_one(pool).await?.get(0);
+
+        let keys = query.sort_keys().join(", ");
+        let mut builder = QueryBuilder::new("SELECT * FROM files WHERE ws_id = ");
+        builder.push_bind(ws_id)
+            .push(" and parent_dir_id = ").push_bind(parent_dir_id)
+            .push(" and uid = ").push_bind(uid)
+            .push(" and is_deleted = false");
+        query.push_filters(&mut builder);
+        if let Some(cursor) = query.cursor {
+            let op = match query.order {
+                SortOrder::Asc => ">",
+                SortOrder::Desc => "<",
+            };
+            builder.push(format!(" and ({}) {} (SELECT {} FROM files WHERE id = ", keys, op, keys))
+                .push_bind(cursor)
+                .push(" and ws_id = ").push_bind(ws_id)
+                .push(" and parent_dir_id = ").push_bind(parent_dir_id)
+                .push(" and uid = ").push_bind(uid)
+                .push(")");
+        }
+        let order = match query.order {
+            SortOrder::Asc => " ASC",
+            SortOrder::Desc => " DESC",
+        };
+        builder.push(" ORDER BY ")
+            .push(query.sort_keys().join(&format!("{}, ", order)))
+            .push(order)
+            .push(" LIMIT ")
+            .push_bind(query.limit);
+
+        let rows = builder.build().fetch_all(pool).await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            files.push(Files::from_row(&row));
+        }
+
+        Ok(Some((files, total)))
+    }
+
     // check file or dir if owned by user
     pub async fn check_owner(uid: Uuid, id: i64, ws_id: Uuid, pool: &PgPool) -> Result<Option<Files>, sqlx::Error> {
         let row = sqlx::query("SELECT * FROM files WHERE uid = $1 and id = $2 \
@@ -133,6 +442,83 @@ impl Files {
 
     }
 
+    // get a dir with all its descendants, parents always come before their children
+    pub async fn get_tree(id: i64, pool: &PgPool) -> Result<Vec<Files>, sqlx::Error> {
+        let rows = sqlx::query("WITH RECURSIVE tree AS ( \
+            SELECT *, 0 AS depth FROM files WHERE id = $1 and is_deleted = false \
+            UNION ALL \
+            SELECT f.*, t.depth + 1 FROM files f JOIN tree t ON f.parent_dir_id = t.id \
+            WHERE f.is_deleted = false \
+        ) SELECT * FROM tree ORDER BY depth")
+            .bind(id)
+            .fetch_all(pool)
+            .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            files.push(Files::from_row(&row));
+        }
+
+        Ok(files)
+    }
+
+    // get the entries below dir `id` of the workspace down to `max_depth` levels, ordered by depth
+    pub async fn get_subtree(ws_id: Uuid, id: i64, max_depth: i32, pool: &PgPool) -> Result<Vec<Files>, sqlx::Error> {
+        let rows = sqlx::query("WITH RECURSIVE tree AS ( \
+            SELECT *, 1 AS depth FROM files WHERE ws_id = $1 and parent_dir_id = $2 and is_deleted = false \
+            UNION ALL \
+            SELECT f.*, t.depth + 1 FROM files f JOIN tree t ON f.parent_dir_id = t.id \
+            WHERE t.depth < $3 and f.is_deleted = false \
+        ) SELECT * FROM tree ORDER BY depth, filename")
+            .bind(ws_id)
+            .bind(id)
+            .bind(max_depth)
+            .fetch_all(pool)
+            .await?;
+
+        let mut files = Vec::new();
+        for row in rows {
+            files.push(Files::from_row(&row));
+        }
+
+        Ok(files)
+    }
+
+    // insert a copy of file `src_id`, the new file history points at the same slices
+    pub async fn insert_copy(
+        &self,
+        src_id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO files (id, uid, ws_id, filename, parent_dir_id, size, is_dir, version, \
+        total_size, file_count) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")
+            .bind(self.id)
+            .bind(self.uid)
+            .bind(self.ws_id)
+            .bind(&self.filename)
+            .bind(self.parent_dir_id)
+            .bind(self.size)
+            .bind(self.is_dir)
+            .bind(self.version)
+            .bind(self.total_size)
+            .bind(self.file_count)
+            .execute(&mut *tx)
+            .await?;
+
+        if !self.is_dir {
+            sqlx::query("INSERT INTO file_histories (fid, file_version, slices, slices_hash, file_hash) \
+            SELECT $1, $2, slices, slices_hash, file_hash FROM file_histories WHERE fid = $3 \
+            ORDER BY file_version DESC LIMIT 1")
+                .bind(self.id)
+                .bind(self.version)
+                .bind(src_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn insert_dir(
         &self,
         pool: &PgPool,
@@ -156,11 +542,12 @@ impl Files {
         &self,
         slice: Vec<String>,
         slices_hash: Vec<String>,
+        file_hash: Option<&str>,
         pool: &PgPool,
     ) -> Result<(), sqlx::Error> {
         let mut tx = pool.begin().await?;
-        sqlx::query("INSERT INTO files (id, uid, ws_id, filename, parent_dir_id, size, is_dir) \
-        VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
+        sqlx::query("INSERT INTO files (id, uid, ws_id, filename, parent_dir_id, size, is_dir, version) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *")
             .bind(self.id)
             .bind(self.uid)
             .bind(self.ws_id)
@@ -168,18 +555,22 @@ impl Files {
             .bind(self.parent_dir_id)
             .bind(self.size)
             .bind(self.is_dir)
+            .bind(self.version)
             .fetch_one(&mut tx)
             .await?;
 
-        sqlx::query("INSERT INTO file_histories (fid, file_version, slices, slices_hash) \
-        VALUES ($1, $2, $3, $4)")
+        sqlx::query("INSERT INTO file_histories (fid, file_version, slices, slices_hash, file_hash) \
+        VALUES ($1, $2, $3, $4, $5)")
             .bind(self.id)
             .bind(self.version)
             .bind(slice)
             .bind(slices_hash)
+            .bind(file_hash)
             .execute(&mut tx)
             .await?;
 
+        Files::update_dir_usage(self.parent_dir_id, self.size, 1, &mut tx).await?;
+
         tx.commit().await?;
         Ok(())
     }
@@ -191,8 +582,8 @@ impl Files {
             .fetch_one(&mut tx)
             .await?;
 
-        let row = sqlx::query("UPDATE files SET filename = $1 WHERE id = $4 RETURNING *")
-            .bind(filename)
+        let row = sqlx::query("UPDATE files SET filename = $1 WHERE id = $2 RETURNING *")
+            .bind(normalize_name(filename))
             .bind(self.id)
             .fetch_one(&mut tx)
             .await?;
@@ -202,44 +593,198 @@ impl Files {
         Ok(Files::from_row(&row))
     }
 
-    // update file version when file content is updated
-    pub async fn update_file_version(&self, slice: Vec<String>, slice_hash: Vec<String>, pool: &PgPool) -> Result<Files, sqlx::Error> {
+    /// Move file or dir into `target_dir_id` under `filename`, all checks run in one transaction
+    /// 1. target must be a dir of the same workspace owned by the same user
+    /// 2. a dir can't be moved into itself or its descendants
+    /// 3. filename must be unique in the target dir
+    pub async fn move_to(&self, target_dir_id: i64, filename: &str, pool: &PgPool) -> Result<Files, Error> {
+        let filename = normalize_name(filename);
         let mut tx = pool.begin().await?;
+        let row = sqlx::query("select * from files where id = $1 and is_deleted = false for update")
+            .bind(self.id)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or(Error::NotFound)?;
+        let src = Files::from_row(&row);
 
-        sqlx::query("select * from files where id = $1 for update")
+        if target_dir_id != ROOT_DIR_ID {
+            let target = sqlx::query("select * from files where id = $1 and ws_id = $2 and uid = $3 \
+            and is_deleted = false for share")
+                .bind(target_dir_id)
+                .bind(self.ws_id)
+                .bind(self.uid)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            if !target.get::<bool, _>("is_dir") {
+                return Err(Error::NotADirectory);
+            }
+
+            // walk up from the target to the root, the moved dir must not be on the way
+            let is_descendant: bool = sqlx::query_scalar("WITH RECURSIVE ancestors AS ( \
+                SELECT id, parent_dir_id FROM files WHERE id = $1 \
+                UNION ALL \
+                SELECT f.id, f.parent_dir_id FROM files f JOIN ancestors a ON f.id = a.parent_dir_id \
+            ) SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2)")
+                .bind(target_dir_id)
+                .bind(self.id)
+                .fetch_one(&mut tx)
+                .await?;
+            if is_descendant {
+                return Err(Error::IntoDescendant);
+            }
+        }
+
+        let conflict = sqlx::query("select id from files where ws_id = $1 and parent_dir_id = $2 \
+        and name_key = file_name_key($1, $3) and is_deleted = false and id <> $4")
+            .bind(self.ws_id)
+            .bind(target_dir_id)
+            .bind(&filename)
+            .bind(self.id)
+            .fetch_optional(&mut tx)
+            .await?;
+        if conflict.is_some() {
+            return Err(Error::NameConflict);
+        }
+
+        let row = sqlx::query("UPDATE files SET parent_dir_id = $1, filename = $2 WHERE id = $3 RETURNING *")
+            .bind(target_dir_id)
+            .bind(&filename)
             .bind(self.id)
             .fetch_one(&mut tx)
             .await?;
 
-        let row = sqlx::query("UPDATE files SET version = version + 1 WHERE id = $1 and version = $2 and uid = $3 RETURNING *")
+        if src.parent_dir_id != target_dir_id {
+            let (size, file_count) = src.usage();
+            Files::update_dir_usage(src.parent_dir_id, -size, -file_count, &mut tx).await?;
+            Files::update_dir_usage(target_dir_id, size, file_count, &mut tx).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Files::from_row(&row))
+    }
+
+    // update file version when file content is updated,
+    // the new version gets the next version number and its own history row
+    pub async fn update_file_version(
+        &self,
+        slice: Vec<String>,
+        slice_hash: Vec<String>,
+        size: i64,
+        file_hash: Option<&str>,
+        pool: &PgPool,
+    ) -> Result<Files, Error> {
+        let mut tx = pool.begin().await?;
+
+        let row = sqlx::query("select * from files where id = $1 and uid = $2 and is_deleted = false for update")
             .bind(self.id)
-            .bind(self.version)
             .bind(self.uid)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or(Error::NotFound)?;
+        let current = Files::from_row(&row);
+        if current.is_dir {
+            return Err(Error::NotFound);
+        }
+
+        let row = sqlx::query("UPDATE files SET version = version + 1, size = $1 WHERE id = $2 RETURNING *")
+            .bind(size)
+            .bind(self.id)
             .fetch_one(&mut tx)
             .await?;
-        
-        sqlx::query("INSERT INTO file_histories (id, file_version, slices, slices_hash) VALUES ($1, $2, $3, $4)")
+        let updated = Files::from_row(&row);
+
+        sqlx::query("INSERT INTO file_histories (fid, file_version, slices, slices_hash, file_hash) \
+        VALUES ($1, $2, $3, $4, $5)")
             .bind(self.id)
-            .bind(self.version)
+            .bind(updated.version)
             .bind(slice)
             .bind(slice_hash)
+            .bind(file_hash)
             .execute(&mut tx)
             .await?;
 
+        Files::update_dir_usage(current.parent_dir_id, size - current.size, 0, &mut tx).await?;
+
         tx.commit().await?;
 
-        Ok(Files::from_row(&row))
+        Ok(updated)
+    }
+
+    /// Move the content of `src` into this file as a new version and delete `src`,
+    /// in one transaction. Both files are locked, a concurrent change of either one
+    /// waits, and either one gone or a dir by then is `NotFound`.
+    pub async fn replace_with(&self, src: &Files, pool: &PgPool) -> Result<Files, Error> {
+        let mut tx = pool.begin().await?;
+
+        // locked in the order of the ids, like any other transaction locking both
+        let rows = sqlx::query("SELECT * FROM files WHERE id = ANY($1) and uid = $2 and is_deleted = false \
+        ORDER BY id FOR UPDATE")
+            .bind(vec![self.id, src.id])
+            .bind(self.uid)
+            .fetch_all(&mut tx)
+            .await?;
+        let locked: Vec<Files> = rows.iter().map(Files::from_row).collect();
+        let current = locked.iter().find(|file| file.id == self.id).ok_or(Error::NotFound)?;
+        let source = locked.iter().find(|file| file.id == src.id).ok_or(Error::NotFound)?;
+        if current.is_dir || source.is_dir {
+            return Err(Error::NotFound);
+        }
+
+        let row = sqlx::query("SELECT * FROM file_histories WHERE fid = $1 ORDER BY file_version DESC LIMIT 1")
+            .bind(source.id)
+            .fetch_one(&mut tx)
+            .await?;
+        let history = FileHistories::from_row(&row)?;
+
+        let row = sqlx::query("UPDATE files SET version = version + 1, size = $1 WHERE id = $2 RETURNING *")
+            .bind(source.size)
+            .bind(self.id)
+            .fetch_one(&mut tx)
+            .await?;
+        let updated = Files::from_row(&row);
+
+        sqlx::query("INSERT INTO file_histories (fid, file_version, slices, slices_hash, file_hash) \
+        VALUES ($1, $2, $3, $4, $5)")
+            .bind(self.id)
+            .bind(updated.version)
+            .bind(history.slices)
+            .bind(history.slices_hash)
+            .bind(history.file_hash)
+            .execute(&mut tx)
+            .await?;
+        Files::update_dir_usage(current.parent_dir_id, source.size - current.size, 0, &mut tx).await?;
+
+        sqlx::query("UPDATE files SET is_deleted = true WHERE id = $1")
+            .bind(source.id)
+            .execute(&mut tx)
+            .await?;
+        Files::update_dir_usage(source.parent_dir_id, -source.size, -1, &mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(updated)
     }
 
     pub async fn delete(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "UPDATE files SET is_deleted = true WHERE id = $1 and uid = $2",
+        let mut tx = pool.begin().await?;
+        let row = sqlx::query(
+            "UPDATE files SET is_deleted = true WHERE id = $1 and uid = $2 and is_deleted = false RETURNING *",
         )
         .bind(self.id)
         .bind(self.uid)
-        .execute(pool)
+        .fetch_optional(&mut tx)
         .await?;
 
+        if let Some(row) = row {
+            let deleted = Files::from_row(&row);
+            let (size, file_count) = deleted.usage();
+            Files::update_dir_usage(deleted.parent_dir_id, -size, -file_count, &mut tx).await?;
+        }
+
+        tx.commit().await?;
         Ok(())
     }
 }
@@ -0,0 +1,65 @@
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use uuid::Uuid;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct InviteCodes {
+    pub id: Uuid,
+    // sha-256 of the code, in hex
+    pub code_hash: String,
+    pub created_by: Option<Uuid>,
+    pub note: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    // the user who signed up with the code, it works once
+    pub used_by: Option<Uuid>,
+    pub used_at: Option<i64>,
+}
+
+impl InviteCodes {
+    fn from_row(row: &PgRow) -> InviteCodes {
+        InviteCodes {
+            id: row.get("id"),
+            code_hash: row.get("code_hash"),
+            created_by: row.get("created_by"),
+            note: row.get("note"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+            used_by: row.get("used_by"),
+            used_at: row.get("used_at"),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO invite_codes (id, code_hash, created_by, note, created_at, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(self.id)
+        .bind(&self.code_hash)
+        .bind(self.created_by)
+        .bind(&self.note)
+        .bind(self.created_at)
+        .bind(self.expires_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    // every code, the newest first
+    pub async fn list(pool: &PgPool) -> Result<Vec<InviteCodes>, sqlx::Error> {
+        let rows = sqlx::query("SELECT * FROM invite_codes ORDER BY created_at DESC, id DESC")
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.iter().map(InviteCodes::from_row).collect())
+    }
+
+    // false if there's no such code
+    pub async fn delete(id: Uuid, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("DELETE FROM invite_codes WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,41 @@
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use uuid::Uuid;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct RecoveryCodes {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    // argon2 hash of the code
+    pub code_hash: String,
+    pub used_at: Option<i64>,
+}
+
+impl RecoveryCodes {
+    fn from_row(row: &PgRow) -> RecoveryCodes {
+        RecoveryCodes {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            code_hash: row.get("code_hash"),
+            used_at: row.get("used_at"),
+        }
+    }
+
+    pub async fn unused(user_id: Uuid, pool: &PgPool) -> Result<Vec<RecoveryCodes>, sqlx::Error> {
+        let rows = sqlx::query("SELECT * FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.iter().map(RecoveryCodes::from_row).collect())
+    }
+
+    /// Mark the code used, false if it was used meanwhile.
+    pub async fn take(id: Uuid, now: i64, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("UPDATE recovery_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+}
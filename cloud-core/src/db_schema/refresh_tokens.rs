@@ -0,0 +1,105 @@
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use uuid::Uuid;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct RefreshTokens {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    // sha-256 of the token, in hex
+    pub token_hash: String,
+    // the token generation of the user when the token was issued
+    pub generation: i32,
+    pub expires_at: i64,
+    // set once the token is exchanged for a new one or revoked
+    pub revoked_at: Option<i64>,
+    // the token was exchanged for a new one, using it again means it may have been stolen
+    pub exchanged: bool,
+}
+
+impl RefreshTokens {
+    pub fn new(user_id: Uuid, token_hash: String, generation: i32, expires_at: i64) -> Self {
+        Self {
+            id: Uuid::now_v7(),
+            user_id,
+            token_hash,
+            generation,
+            expires_at,
+            revoked_at: None,
+            exchanged: false,
+        }
+    }
+
+    fn from_row(row: &PgRow) -> RefreshTokens {
+        RefreshTokens {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            token_hash: row.get("token_hash"),
+            generation: row.get("generation"),
+            expires_at: row.get("expires_at"),
+            revoked_at: row.get("revoked_at"),
+            exchanged: row.get("exchanged"),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO refresh_tokens (id, user_id, token_hash, generation, expires_at)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(&self.token_hash)
+        .bind(self.generation)
+        .bind(self.expires_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn find_by_hash(token_hash: &str, pool: &PgPool) -> Result<Option<RefreshTokens>, sqlx::Error> {
+        let row = sqlx::query("SELECT * FROM refresh_tokens WHERE token_hash = $1")
+            .bind(token_hash)
+            .fetch_optional(pool)
+            .await?;
+        Ok(row.as_ref().map(RefreshTokens::from_row))
+    }
+
+    /// Mark a live token exchanged and return it, `None` if it's unknown, expired or
+    /// already revoked. Only one of two concurrent calls gets the token.
+    pub async fn exchange(token_hash: &str, now: i64, pool: &PgPool) -> Result<Option<RefreshTokens>, sqlx::Error> {
+        let row = sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1, exchanged = true
+             WHERE token_hash = $2 AND revoked_at IS NULL AND expires_at > $1 RETURNING *",
+        )
+        .bind(now)
+        .bind(token_hash)
+        .fetch_optional(pool)
+        .await?;
+        Ok(row.as_ref().map(RefreshTokens::from_row))
+    }
+
+    // revoke a token of the user, nothing happens for a token of someone else
+    pub async fn revoke(token_hash: &str, user_id: Uuid, now: i64, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = $1
+             WHERE token_hash = $2 AND user_id = $3 AND revoked_at IS NULL",
+        )
+        .bind(now)
+        .bind(token_hash)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    // expired tokens are of no use, not even to spot a reused one
+    pub async fn delete_expired(user_id: Uuid, now: i64, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1 AND expires_at <= $2")
+            .bind(user_id)
+            .bind(now)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use crate::error::Error;
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use uuid::Uuid;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct UploadSessions {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub ws_id: Uuid,
+    pub filename: String,
+    pub parent_dir_id: i64,
+    pub expires_at: i64,
+    pub total_size: i64,
+    pub file_hash: String,
+    // the file a new version is uploaded for
+    pub file_id: Option<i64>,
+    // fail, rename, overwrite or skip when the name is taken
+    pub conflict: String,
+}
+
+#[derive(Debug, FromRow, Clone)]
+pub struct UploadSessionChunks {
+    pub session_id: Uuid,
+    pub block_index: i32,
+    pub block_name: String,
+    pub block_hash: String,
+    pub block_size: i64,
+    // the block belongs to a stored file and must not be deleted with the session
+    pub shared: bool,
+}
+
+impl UploadSessionChunks {
+    fn from_row(row: &PgRow) -> UploadSessionChunks {
+        UploadSessionChunks {
+            session_id: row.get("session_id"),
+            block_index: row.get("block_index"),
+            block_name: row.get("block_name"),
+            block_hash: row.get("block_hash"),
+            block_size: row.get("block_size"),
+            shared: row.get("shared"),
+        }
+    }
+}
+
+impl UploadSessions {
+    fn from_row(row: &PgRow) -> UploadSessions {
+        UploadSessions {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            ws_id: row.get("ws_id"),
+            filename: row.get("filename"),
+            parent_dir_id: row.get("parent_dir_id"),
+            expires_at: row.get("expires_at"),
+            total_size: row.get("total_size"),
+            file_hash: row.get("file_hash"),
+            file_id: row.get("file_id"),
+            conflict: row.get("conflict"),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO upload_sessions (id, user_id, ws_id, filename, parent_dir_id, expires_at, total_size, file_hash, file_id, conflict)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(self.ws_id)
+        .bind(&self.filename)
+        .bind(self.parent_dir_id)
+        .bind(self.expires_at)
+        .bind(self.total_size)
+        .bind(&self.file_hash)
+        .bind(self.file_id)
+        .bind(&self.conflict)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get(id: Uuid, pool: &PgPool) -> Result<Option<UploadSessions>, sqlx::Error> {
+        let row = sqlx::query("SELECT * FROM upload_sessions WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+        Ok(row.as_ref().map(UploadSessions::from_row))
+    }
+
+    // the received chunks of a session ordered by index
+    pub async fn chunks(id: Uuid, pool: &PgPool) -> Result<Vec<UploadSessionChunks>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT * FROM upload_session_chunks WHERE session_id = $1 ORDER BY block_index",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+        Ok(rows.iter().map(UploadSessionChunks::from_row).collect())
+    }
+
+    pub async fn touch(id: Uuid, expires_at: i64, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE upload_sessions SET expires_at = $1 WHERE id = $2")
+            .bind(expires_at)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // save a chunk and push the deadline of its session forward,
+    // the chunk with the same index is replaced and returned
+    pub async fn put_chunk(
+        chunk: &UploadSessionChunks,
+        expires_at: i64,
+        pool: &PgPool,
+    ) -> Result<Option<UploadSessionChunks>, Error> {
+        let mut tx = pool.begin().await?;
+
+        // locks the session against a concurrent take
+        let session = sqlx::query("UPDATE upload_sessions SET expires_at = $1 WHERE id = $2 RETURNING id")
+            .bind(expires_at)
+            .bind(chunk.session_id)
+            .fetch_optional(&mut tx)
+            .await?;
+        if session.is_none() {
+            return Err(Error::NotFound);
+        }
+
+        let old = sqlx::query(
+            "SELECT * FROM upload_session_chunks WHERE session_id = $1 AND block_index = $2 FOR UPDATE",
+        )
+        .bind(chunk.session_id)
+        .bind(chunk.block_index)
+        .fetch_optional(&mut tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO upload_session_chunks (session_id, block_index, block_name, block_hash, block_size, shared)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (session_id, block_index) DO UPDATE
+             SET block_name = $3, block_hash = $4, block_size = $5, shared = $6",
+        )
+        .bind(chunk.session_id)
+        .bind(chunk.block_index)
+        .bind(&chunk.block_name)
+        .bind(&chunk.block_hash)
+        .bind(chunk.block_size)
+        .bind(chunk.shared)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(old.as_ref().map(UploadSessionChunks::from_row))
+    }
+
+    // save chunks after the `expected` ones of their session if it still has exactly
+    // that many, and push the deadline forward, false when it has a different count
+    pub async fn append_chunks(
+        id: Uuid,
+        expected: i64,
+        chunks: &[UploadSessionChunks],
+        expires_at: i64,
+        pool: &PgPool,
+    ) -> Result<bool, Error> {
+        let mut tx = pool.begin().await?;
+
+        // locks the session, concurrent appends wait for each other
+        let session = sqlx::query("UPDATE upload_sessions SET expires_at = $1 WHERE id = $2 RETURNING id")
+            .bind(expires_at)
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?;
+        if session.is_none() {
+            return Err(Error::NotFound);
+        }
+
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM upload_session_chunks WHERE session_id = $1")
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+        if count != expected {
+            return Ok(false);
+        }
+
+        for chunk in chunks {
+            sqlx::query(
+                "INSERT INTO upload_session_chunks (session_id, block_index, block_name, block_hash, block_size, shared)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(id)
+            .bind(chunk.block_index)
+            .bind(&chunk.block_name)
+            .bind(&chunk.block_hash)
+            .bind(chunk.block_size)
+            .bind(chunk.shared)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // delete a session and return it with its chunks,
+    // only one of concurrent callers gets it
+    pub async fn take(
+        id: Uuid,
+        pool: &PgPool,
+    ) -> Result<Option<(UploadSessions, Vec<UploadSessionChunks>)>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        // the lock holds off the chunks put meanwhile, which the cascade would drop unreturned
+        let session = sqlx::query("SELECT * FROM upload_sessions WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?;
+        let session = match session {
+            Some(session) => UploadSessions::from_row(&session),
+            None => return Ok(None),
+        };
+
+        let chunks = sqlx::query(
+            "DELETE FROM upload_session_chunks WHERE session_id = $1 RETURNING *",
+        )
+        .bind(id)
+        .fetch_all(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        let mut chunks: Vec<UploadSessionChunks> = chunks.iter().map(UploadSessionChunks::from_row).collect();
+        chunks.sort_by_key(|chunk| chunk.block_index);
+        Ok(Some((session, chunks)))
+    }
+
+    // put back a session removed by take
+    pub async fn restore(&self, chunks: &[UploadSessionChunks], pool: &PgPool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO upload_sessions (id, user_id, ws_id, filename, parent_dir_id, expires_at, total_size, file_hash, file_id, conflict)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .bind(self.id)
+        .bind(self.user_id)
+        .bind(self.ws_id)
+        .bind(&self.filename)
+        .bind(self.parent_dir_id)
+        .bind(self.expires_at)
+        .bind(self.total_size)
+        .bind(&self.file_hash)
+        .bind(self.file_id)
+        .bind(&self.conflict)
+        .execute(&mut tx)
+        .await?;
+
+        for chunk in chunks {
+            sqlx::query(
+                "INSERT INTO upload_session_chunks (session_id, block_index, block_name, block_hash, block_size, shared)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(self.id)
+            .bind(chunk.block_index)
+            .bind(&chunk.block_name)
+            .bind(&chunk.block_hash)
+            .bind(chunk.block_size)
+            .bind(chunk.shared)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // ids of the sessions whose deadline is not after `now`
+    pub async fn expired(now: i64, pool: &PgPool) -> Result<Vec<Uuid>, sqlx::Error> {
+        let ids = sqlx::query_scalar("SELECT id FROM upload_sessions WHERE expires_at <= $1")
+            .bind(now)
+            .fetch_all(pool)
+            .await?;
+        Ok(ids)
+    }
+
+    pub async fn user_sessions(user_id: Uuid, pool: &PgPool) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM upload_sessions WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+    }
+}
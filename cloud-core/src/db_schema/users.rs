@@ -1,7 +1,9 @@
-use sqlx::postgres::{PgPool, PgRow};
-use sqlx::{FromRow, Row};
+use sqlx::postgres::{PgPool, PgRow, Postgres};
+use sqlx::{FromRow, Row, Transaction};
 use uuid::Uuid;
 
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
 
 #[derive(Debug, FromRow)]
 pub struct Users {
@@ -9,6 +11,21 @@ pub struct Users {
     pub name: String,
     pub email: String,
     pub password_hash: String,
+    // the owner of the email confirmed it
+    pub email_verified: bool,
+    // bumped to revoke every access and refresh token of the user
+    pub token_generation: i32,
+    // base32, set from the enrollment on, in use once it's enabled
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    // the time step of the last code taken
+    pub totp_last_step: i64,
+    // user or admin
+    pub role: String,
+    // can't log in, every token was revoked when it was disabled
+    pub disabled: bool,
+    // bytes the files of the user may take, none for no limit
+    pub quota: Option<i64>,
     //created_at: DateTime<Utc>,
     //updated_at: DateTime<Utc>,
 }
@@ -20,6 +37,14 @@ impl Users {
             name,
             email,
             password_hash,
+            email_verified: false,
+            token_generation: 0,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: 0,
+            role: ROLE_USER.to_string(),
+            disabled: false,
+            quota: None,
         }
     }
 
@@ -29,6 +54,14 @@ impl Users {
             name: row.get("name"),
             email: row.get("email"),
             password_hash: row.get("password_hash"),
+            email_verified: row.get("email_verified"),
+            token_generation: row.get("token_generation"),
+            totp_secret: row.get("totp_secret"),
+            totp_enabled: row.get("totp_enabled"),
+            totp_last_step: row.get("totp_last_step"),
+            role: row.get("role"),
+            disabled: row.get("disabled"),
+            quota: row.get("quota"),
         }
     }
 
@@ -50,6 +83,227 @@ impl Users {
         Ok(Users::from_row(&row))
     }
 
+    /// Users whose name or email contains `query`, ordered by email.
+    pub async fn search(query: Option<&str>, limit: i64, offset: i64, pool: &PgPool) -> Result<Vec<Users>, sqlx::Error> {
+        // the query is matched literally, not as a pattern
+        let pattern = query.map(|query| {
+            format!("%{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+        });
+        let rows = sqlx::query(
+            "SELECT * FROM users WHERE $1::text IS NULL OR name ILIKE $1 OR email ILIKE $1
+             ORDER BY email LIMIT $2 OFFSET $3",
+        )
+        .bind(pattern)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+        Ok(rows.iter().map(Users::from_row).collect())
+    }
+
+    pub async fn is_admin(id: Uuid, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND role = $2 AND NOT disabled)")
+            .bind(id)
+            .bind(ROLE_ADMIN)
+            .fetch_one(pool)
+            .await
+    }
+
+    /// Make the user with the email an admin, false if there's no such user.
+    pub async fn promote_admin(email: &str, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("UPDATE users SET role = $1 WHERE email = $2")
+            .bind(ROLE_ADMIN)
+            .bind(email)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Change what an admin manages of a user, `None` keeps the value. Disabling a
+    /// user revokes every token of it.
+    pub async fn update_account(
+        id: Uuid,
+        role: Option<&str>,
+        disabled: Option<bool>,
+        quota: Option<Option<i64>>,
+        pool: &PgPool,
+    ) -> Result<Users, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let row = sqlx::query(
+            "UPDATE users SET role = coalesce($1, role), disabled = coalesce($2, disabled),
+             quota = CASE WHEN $3 THEN $4 ELSE quota END WHERE id = $5 RETURNING *",
+        )
+        .bind(role)
+        .bind(disabled)
+        .bind(quota.is_some())
+        .bind(quota.flatten())
+        .bind(id)
+        .fetch_one(&mut tx)
+        .await?;
+        let mut user = Users::from_row(&row);
+
+        if disabled == Some(true) {
+            user.token_generation = Users::revoke_tokens_in(id, &mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    /// Delete the user with its workspaces, files and upload sessions, None if there's no
+    /// such user. Returns the blocks no file refers to anymore, for the caller to delete
+    /// once this is committed.
+    pub async fn delete(id: Uuid, pool: &PgPool) -> Result<Option<Vec<String>>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let mut blocks: Vec<String> = sqlx::query_scalar("WITH deleted AS ( \
+            DELETE FROM file_histories WHERE fid IN (SELECT id FROM files WHERE uid = $1) RETURNING slices \
+        ) SELECT DISTINCT name::text FROM deleted, unnest(deleted.slices) AS name")
+            .bind(id)
+            .fetch_all(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM files WHERE uid = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM workspaces WHERE uid = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        // the shared chunks are slices of the files
+        let chunk_blocks: Vec<String> = sqlx::query_scalar("DELETE FROM upload_session_chunks \
+            WHERE session_id IN (SELECT id FROM upload_sessions WHERE user_id = $1) AND NOT shared \
+            RETURNING block_name::text")
+            .bind(id)
+            .fetch_all(&mut tx)
+            .await?;
+        blocks.extend(chunk_blocks);
+        sqlx::query("DELETE FROM upload_sessions WHERE user_id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let res = sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        // a copy of a file may be kept by another user
+        let blocks = sqlx::query_scalar("SELECT name FROM unnest($1::text[]) AS name \
+            WHERE NOT EXISTS (SELECT 1 FROM file_histories WHERE name = ANY(slices))")
+            .bind(&blocks)
+            .fetch_all(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(blocks))
+    }
+
+    pub async fn token_generation(id: Uuid, pool: &PgPool) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT token_generation FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    // bump the token generation and revoke the refresh tokens,
+    // so every token issued before stops working
+    async fn revoke_tokens_in(id: Uuid, tx: &mut Transaction<'_, Postgres>) -> Result<i32, sqlx::Error> {
+        let generation = sqlx::query_scalar(
+            "UPDATE users SET token_generation = token_generation + 1 WHERE id = $1 RETURNING token_generation",
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = extract(epoch from now())::bigint
+             WHERE user_id = $1 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        Ok(generation)
+    }
+
+    /// Revoke every access and refresh token of the user, returns the new token generation.
+    pub async fn revoke_tokens(id: Uuid, pool: &PgPool) -> Result<i32, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let generation = Users::revoke_tokens_in(id, &mut tx).await?;
+        tx.commit().await?;
+        Ok(generation)
+    }
+
+    /// Mark the email verified if it's still the email of the user, false if it isn't
+    /// or it was verified before.
+    pub async fn verify_email(id: Uuid, email: &str, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("UPDATE users SET email_verified = true WHERE id = $1 AND email = $2 AND NOT email_verified")
+            .bind(id)
+            .bind(email)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    // start an enrollment, the secret isn't used until it's enabled
+    pub async fn set_totp_secret(id: Uuid, secret: &str, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE users SET totp_secret = $1, totp_enabled = false WHERE id = $2")
+            .bind(secret)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Enable two-factor authentication with the confirmed secret, the recovery
+    /// codes replace the ones from before.
+    pub async fn enable_totp(id: Uuid, step: i64, recovery_code_hashes: &[String], pool: &PgPool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE users SET totp_enabled = true, totp_last_step = $1 WHERE id = $2")
+            .bind(step)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        for code_hash in recovery_code_hashes {
+            sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+                .bind(Uuid::now_v7())
+                .bind(id)
+                .bind(code_hash)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn disable_totp(id: Uuid, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE users SET totp_secret = NULL, totp_enabled = false WHERE id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Take the code of a time step, false if a code of this or a later step was taken.
+    pub async fn take_totp_step(id: Uuid, step: i64, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query("UPDATE users SET totp_last_step = $1 WHERE id = $2 AND totp_last_step < $1")
+            .bind(step)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    // a new password revokes every token of the user
     pub async fn update_by_id(
         id: Uuid,
         name: Option<String>,
@@ -63,8 +317,10 @@ impl Users {
             .fetch_one(&mut tx)
             .await?;
 
+        let password_changed = password_hash.is_some();
         let row = sqlx::query(
-            "UPDATE users SET name = $1, email = $2, password_hash = $3 WHERE id = $4 RETURNING *",
+            "UPDATE users SET name = $1, email = $2, password_hash = $3,
+             email_verified = email_verified AND email = $2 WHERE id = $4 RETURNING *",
         )
         .bind(name.unwrap_or(user.get("name")))
         .bind(email.unwrap_or(user.get("email")))
@@ -72,10 +328,14 @@ impl Users {
         .bind(id)
         .fetch_one(&mut tx)
         .await?;
+        let mut user = Users::from_row(&row);
 
+        if password_changed {
+            user.token_generation = Users::revoke_tokens_in(id, &mut tx).await?;
+        }
         tx.commit().await?;
 
-        Ok(Users::from_row(&row))
+        Ok(user)
     }
 
     pub async fn insert(
@@ -94,4 +354,40 @@ impl Users {
 
         Ok(Users::from_row(&row))
     }
+
+    /// Insert the user, signed up with the invite code of the hash. `None` if the code
+    /// is unknown, used or expired, nothing is inserted then.
+    pub async fn insert_with_invite(
+        &self,
+        code_hash: &str,
+        now: i64,
+        pool: &PgPool,
+    ) -> Result<Option<Users>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let row = sqlx::query(
+            "INSERT INTO users (id, name, password_hash, email) VALUES ($1, $2, $3, $4) RETURNING *",
+        )
+        .bind(self.id)
+        .bind(&self.name)
+        .bind(&self.password_hash)
+        .bind(&self.email)
+        .fetch_one(&mut tx)
+        .await?;
+
+        let res = sqlx::query(
+            "UPDATE invite_codes SET used_by = $1, used_at = $2
+             WHERE code_hash = $3 AND used_at IS NULL AND (expires_at IS NULL OR expires_at > $2)",
+        )
+        .bind(self.id)
+        .bind(now)
+        .bind(code_hash)
+        .execute(&mut tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(None);
+        }
+        tx.commit().await?;
+
+        Ok(Some(Users::from_row(&row)))
+    }
 }
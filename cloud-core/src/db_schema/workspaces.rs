@@ -12,6 +12,9 @@ pub struct Workspaces {
     pub uid: Uuid,
     pub name: String,
     pub sync: bool,
+    /// file names are compared in lower case, chosen when the workspace is created
+    #[serde(default)]
+    pub case_insensitive: bool,
     //created_at: DateTime<Utc>,
     //updated_at: DateTime<Utc>,
 }
@@ -23,6 +26,7 @@ impl Workspaces {
             name: row.get("name"),
             uid: row.get("uid"),
             sync: row.get("sync"),
+            case_insensitive: row.get("case_insensitive"),
         }
     }
 
@@ -32,6 +36,7 @@ impl Workspaces {
             name,
             uid,
             sync,
+            case_insensitive: false,
         }
     }
 
@@ -47,12 +52,13 @@ impl Workspaces {
 
     pub async fn insert(&self, pool: &PgPool) -> Result<Workspaces, sqlx::Error> {
         let row = sqlx::query(
-            "INSERT INTO workspaces (id, name, uid, sync) VALUES ($1, $2, $3, $4) RETURNING *",
+            "INSERT INTO workspaces (id, name, uid, sync, case_insensitive) VALUES ($1, $2, $3, $4, $5) RETURNING *",
         )
         .bind(self.id)
         .bind(&self.name)
         .bind(self.uid)
         .bind(self.sync)
+        .bind(self.case_insensitive)
         .fetch_one(pool)
         .await?;
 
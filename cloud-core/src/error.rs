@@ -2,12 +2,45 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    //#[error("I/O error")]
-    //Io(#[from] std::io::Error),
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
     //#[error("JSON error")]
     //Json(#[from] serde_json::Error),
     //#[error("Invalid input")]
     //InvalidInput,
     #[error("hash not consistent")]
     HashCheckError(String),
+
+    #[error("file or directory not found")]
+    NotFound,
+
+    #[error("target is not a directory")]
+    NotADirectory,
+
+    #[error("can't move or copy a directory into itself or its descendant")]
+    IntoDescendant,
+
+    #[error("a file with the same name already exists in the target directory")]
+    NameConflict,
+
+    #[error("path is invalid")]
+    InvalidPath,
+
+    #[error("file is too large")]
+    TooLarge,
+
+    #[error("archive is invalid or not supported")]
+    InvalidArchive,
+
+    #[error("archive {0}")]
+    ArchiveLimit(&'static str),
+
+    #[error("archive entry is not a regular file or directory")]
+    UnsupportedEntry,
+
+    #[error("an error occurred with the block storage")]
+    Block(#[from] anyhow::Error),
+
+    #[error("an error occurred with the database")]
+    Sqlx(#[from] sqlx::Error),
 }
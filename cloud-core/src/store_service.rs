@@ -1,3 +1,8 @@
 pub mod cloud_file;
 pub mod cloud_block;
+pub mod cloud_copy;
+pub mod cloud_path;
+pub mod cloud_writer;
+pub mod cloud_archive;
 mod inner_utils;
+mod zip_stream;
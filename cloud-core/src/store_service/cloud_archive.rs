@@ -0,0 +1,454 @@
+use crate::block::BlockHandler;
+use crate::error::Error;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use super::cloud_path::CloudPath;
+use super::cloud_writer::{CloudWriter, WrittenFile};
+use super::zip_stream::ZipStream;
+
+// deflate can't compress more than about 1032:1, an archive which claims to
+// expand more than that has overlapping or forged entries
+const MAX_COMPRESSION_RATIO: u64 = 1024;
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "zip" => Some(Self::Zip),
+            "tar" => Some(Self::Tar),
+            _ => None,
+        }
+    }
+
+    /// guess the format from the first 512 bytes of an archive
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            return Some(Self::Zip);
+        }
+        match header.get(257..262) {
+            Some(magic) if magic == b"ustar" => Some(Self::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::Tar => "tar",
+        }
+    }
+}
+
+/// A stored file or dir to put into an archive.
+pub struct ArchiveEntry {
+    /// slash separated path inside the archive, like "photos/2023/a.jpg"
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub blocks_name: Vec<String>,
+}
+
+/// Reads the content of a stored file, one block at a time.
+pub struct BlocksReader {
+    block_handler: Arc<dyn BlockHandler>,
+    blocks_name: VecDeque<String>,
+    current: Bytes,
+}
+
+impl BlocksReader {
+    pub fn new(blocks_name: Vec<String>, block_handler: Arc<dyn BlockHandler>) -> Self {
+        Self {
+            block_handler,
+            blocks_name: blocks_name.into(),
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for BlocksReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            let block_name = match self.blocks_name.pop_front() {
+                Some(block_name) => block_name,
+                None => return Ok(0),
+            };
+            let mut blocks = self.block_handler
+                .get_blocks(vec![block_name.as_str()])
+                .map_err(io::Error::other)?;
+            self.current = blocks.remove(0).data;
+        }
+
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Write `entries` as an archive to `out` while reading their blocks,
+/// so neither the archive nor a file is ever held as a whole.
+/// Parents must come before their children.
+pub fn write_archive<W: Write>(
+    format: ArchiveFormat,
+    entries: &[ArchiveEntry],
+    mtime: u64,
+    block_handler: Arc<dyn BlockHandler>,
+    out: W,
+) -> Result<(), Error> {
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = ZipStream::new(out, mtime);
+            for entry in entries {
+                match entry.is_dir {
+                    true => zip.add_dir(&entry.path)?,
+                    false => {
+                        let content = BlocksReader::new(entry.blocks_name.clone(), block_handler.clone());
+                        zip.add_file(&entry.path, entry.size, content)?
+                    }
+                }
+            }
+            zip.finish()?;
+        }
+        ArchiveFormat::Tar => {
+            let mut tar = tar::Builder::new(out);
+            for entry in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_mtime(mtime);
+                match entry.is_dir {
+                    true => {
+                        header.set_entry_type(tar::EntryType::Directory);
+                        header.set_mode(0o755);
+                        header.set_size(0);
+                        tar.append_data(&mut header, &entry.path, io::empty())?;
+                    }
+                    false => {
+                        header.set_entry_type(tar::EntryType::Regular);
+                        header.set_mode(0o644);
+                        header.set_size(entry.size);
+                        let content = BlocksReader::new(entry.blocks_name.clone(), block_handler.clone());
+                        tar.append_data(&mut header, &entry.path, content)?;
+                    }
+                }
+            }
+            tar.into_inner()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Limits an archive must stay within to be extracted.
+pub struct ExtractLimits {
+    pub max_entries: usize,
+    /// total size of the extracted files
+    pub max_size: u64,
+}
+
+pub enum Extracted {
+    Dir(CloudPath),
+    File(CloudPath, WrittenFile),
+}
+
+pub struct ExtractedEntry {
+    /// the name of the entry in the archive
+    pub name: String,
+    pub result: Result<Extracted, Error>,
+}
+
+impl ExtractedEntry {
+    fn blocks_name(&self) -> Vec<&str> {
+        match &self.result {
+            Ok(Extracted::File(_, written)) => written.blocks_name.iter().map(|name| name.as_str()).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Write the files of an archive to blocks. An entry with an unsafe path, like
+/// "../a" or "/etc/a", or which isn't a file or dir, fails on its own.
+/// An archive beyond `limits` fails as a whole, and nothing of it is kept.
+pub fn extract_archive<R: Read + Seek>(
+    format: ArchiveFormat,
+    reader: R,
+    limits: &ExtractLimits,
+    block_handler: Arc<dyn BlockHandler>,
+) -> Result<Vec<ExtractedEntry>, Error> {
+    let mut entries = Vec::new();
+    let result = match format {
+        ArchiveFormat::Zip => extract_zip(reader, limits, block_handler.clone(), &mut entries),
+        ArchiveFormat::Tar => extract_tar(reader, limits, block_handler.clone(), &mut entries),
+    };
+
+    if let Err(e) = result {
+        let blocks_name = entries.iter().flat_map(|entry| entry.blocks_name()).collect();
+        if let Err(e) = block_handler.delete_blocks(blocks_name) {
+            log::error!("failed to delete blocks of a rejected archive: {:?}", e);
+        }
+        return Err(e);
+    }
+    Ok(entries)
+}
+
+fn extract_zip<R: Read + Seek>(
+    mut reader: R,
+    limits: &ExtractLimits,
+    block_handler: Arc<dyn BlockHandler>,
+    entries: &mut Vec<ExtractedEntry>,
+) -> Result<(), Error> {
+    let archive_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut zip = zip::ZipArchive::new(reader).map_err(|_| Error::InvalidArchive)?;
+
+    if zip.len() > limits.max_entries {
+        return Err(Error::ArchiveLimit("has too many entries"));
+    }
+    // the sizes in the central directory are checked again while reading
+    let mut declared_size = 0u64;
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i).map_err(|_| Error::InvalidArchive)?;
+        declared_size = declared_size.saturating_add(file.size());
+    }
+    if declared_size > limits.max_size {
+        return Err(Error::ArchiveLimit("expands beyond the allowed size"));
+    }
+    if declared_size > archive_size.saturating_mul(MAX_COMPRESSION_RATIO) {
+        return Err(Error::ArchiveLimit("expands beyond the allowed ratio"));
+    }
+
+    let mut remaining = limits.max_size;
+    for i in 0..zip.len() {
+        let name = zip.by_index_raw(i).map_err(|_| Error::InvalidArchive)?.name().to_string();
+        let mut file = match zip.by_index(i) {
+            Ok(file) => file,
+            // an encrypted or unsupported entry
+            Err(_) => {
+                entries.push(ExtractedEntry { name, result: Err(Error::UnsupportedEntry) });
+                continue;
+            }
+        };
+        let is_symlink = file.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000);
+        let result = match entry_path(&name) {
+            Ok(None) => continue,
+            Err(e) => Err(e),
+            Ok(Some(_)) if is_symlink => Err(Error::UnsupportedEntry),
+            Ok(Some(path)) if file.is_dir() => Ok(Extracted::Dir(path)),
+            Ok(Some(path)) => {
+                let declared = file.size();
+                let written = write_entry(&mut file, declared, remaining, block_handler.clone())?;
+                remaining -= written.size as u64;
+                Ok(Extracted::File(path, written))
+            }
+        };
+        entries.push(ExtractedEntry { name, result });
+    }
+    Ok(())
+}
+
+fn extract_tar<R: Read>(
+    reader: R,
+    limits: &ExtractLimits,
+    block_handler: Arc<dyn BlockHandler>,
+    entries: &mut Vec<ExtractedEntry>,
+) -> Result<(), Error> {
+    let mut tar = tar::Archive::new(reader);
+    let mut remaining = limits.max_size;
+    let mut count = 0;
+    for entry in tar.entries().map_err(|_| Error::InvalidArchive)? {
+        let mut entry = entry.map_err(|_| Error::InvalidArchive)?;
+        count += 1;
+        if count > limits.max_entries {
+            return Err(Error::ArchiveLimit("has too many entries"));
+        }
+
+        let name = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        let entry_type = entry.header().entry_type();
+        let result = match entry_path(&name) {
+            Ok(None) => continue,
+            Err(e) => Err(e),
+            Ok(Some(path)) if entry_type.is_dir() => Ok(Extracted::Dir(path)),
+            Ok(Some(path)) if entry_type.is_file() => {
+                let declared = entry.size();
+                if declared > remaining {
+                    return Err(Error::ArchiveLimit("expands beyond the allowed size"));
+                }
+                let written = write_entry(&mut entry, declared, remaining, block_handler.clone())?;
+                remaining -= written.size as u64;
+                Ok(Extracted::File(path, written))
+            }
+            // links, devices and the like
+            Ok(Some(_)) => Err(Error::UnsupportedEntry),
+        };
+        entries.push(ExtractedEntry { name, result });
+    }
+    Ok(())
+}
+
+// the path of an entry inside the target dir, `None` for the entry of the top dir itself, like "./"
+fn entry_path(name: &str) -> Result<Option<CloudPath>, Error> {
+    if name.starts_with('/') || name.contains('\\') || name.contains(':') || name.contains('\0') {
+        return Err(Error::InvalidPath);
+    }
+    let name = name
+        .split('/')
+        .filter(|component| *component != ".")
+        .collect::<Vec<&str>>()
+        .join("/");
+    let path = CloudPath::parse(&name)?;
+    match path.is_root() {
+        true => Ok(None),
+        false => Ok(Some(path)),
+    }
+}
+
+// write the content of an entry, which must have the size it declares
+fn write_entry<R: Read>(
+    content: &mut R,
+    declared: u64,
+    remaining: u64,
+    block_handler: Arc<dyn BlockHandler>,
+) -> Result<WrittenFile, Error> {
+    let mut writer = CloudWriter::new(block_handler, remaining.min(usize::MAX as u64) as usize);
+    let mut content = content.take(declared.saturating_add(1));
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+
+    let result = loop {
+        let n = match content.read(&mut buf) {
+            Ok(0) => break writer.finish(),
+            Ok(n) => n,
+            Err(_) => break Err(Error::InvalidArchive),
+        };
+        match writer.write(&buf[..n]) {
+            Ok(()) => {},
+            Err(Error::TooLarge) => break Err(Error::ArchiveLimit("expands beyond the allowed size")),
+            Err(e) => break Err(e),
+        }
+    };
+
+    match result {
+        Ok(written) if written.size as u64 == declared => Ok(written),
+        Ok(_) => {
+            writer.abort();
+            Err(Error::InvalidArchive)
+        }
+        Err(e) => {
+            writer.abort();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, fs_handler::FsHandler};
+    use std::io::Cursor;
+    use uuid::Uuid;
+
+    fn store(content: &'static str, block_handler: &Arc<dyn BlockHandler>) -> Vec<String> {
+        let block_name = Uuid::now_v7().to_string();
+        block_handler.write_blocks(vec![Block::new(block_name.clone(), Bytes::from(content))]).unwrap();
+        vec![block_name]
+    }
+
+    fn archive_entries(block_handler: &Arc<dyn BlockHandler>) -> Vec<ArchiveEntry> {
+        vec![
+            ArchiveEntry { path: "docs".to_string(), is_dir: true, size: 0, blocks_name: vec![] },
+            ArchiveEntry { path: "docs/a.txt".to_string(), is_dir: false, size: 5, blocks_name: store("hello", block_handler) },
+            ArchiveEntry { path: "b.txt".to_string(), is_dir: false, size: 0, blocks_name: vec![] },
+        ]
+    }
+
+    fn limits() -> ExtractLimits {
+        ExtractLimits { max_entries: 10, max_size: 1024 }
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let block_handler: Arc<dyn BlockHandler> = Arc::new(FsHandler::new("."));
+        for format in [ArchiveFormat::Zip, ArchiveFormat::Tar] {
+            let mut archive = Vec::new();
+            write_archive(format, &archive_entries(&block_handler), 1687264496, block_handler.clone(), &mut archive).unwrap();
+            assert_eq!(ArchiveFormat::detect(&archive), Some(format));
+
+            let entries = extract_archive(format, Cursor::new(archive), &limits(), block_handler.clone()).unwrap();
+            let names: Vec<&str> = entries.iter().map(|entry| entry.name.trim_end_matches('/')).collect();
+            assert_eq!(names, ["docs", "docs/a.txt", "b.txt"]);
+            match &entries[1].result {
+                Ok(Extracted::File(path, written)) => {
+                    assert_eq!(path.components(), ["docs", "a.txt"]);
+                    assert_eq!(written.hash, cloud_utils::digest::sha256_digest(&Bytes::from("hello")));
+                }
+                _ => panic!("docs/a.txt is not extracted"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_zip_readable() {
+        let block_handler: Arc<dyn BlockHandler> = Arc::new(FsHandler::new("."));
+        let mut archive = Vec::new();
+        write_archive(ArchiveFormat::Zip, &archive_entries(&block_handler), 0, block_handler, &mut archive).unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert!(zip.by_name("docs/").unwrap().is_dir());
+        let mut content = String::new();
+        zip.by_name("docs/a.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+    }
+
+    #[test]
+    fn test_extract_unsafe_entries() {
+        let block_handler: Arc<dyn BlockHandler> = Arc::new(FsHandler::new("."));
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, entry_type) in [
+            ("./", tar::EntryType::Directory),
+            ("../evil.txt", tar::EntryType::Regular),
+            ("./ok.txt", tar::EntryType::Regular),
+            ("link", tar::EntryType::Symlink),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            // set_path refuses "..", so the name is written as is
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+            tar.append(&header, io::empty()).unwrap();
+        }
+        let archive = tar.into_inner().unwrap();
+
+        let entries = extract_archive(ArchiveFormat::Tar, Cursor::new(archive), &limits(), block_handler).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(entries[0].result, Err(Error::InvalidPath)));
+        assert!(matches!(entries[1].result, Ok(Extracted::File(_, _))));
+        assert!(matches!(entries[2].result, Err(Error::UnsupportedEntry)));
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let block_handler: Arc<dyn BlockHandler> = Arc::new(FsHandler::new("."));
+        let mut archive = Vec::new();
+        write_archive(ArchiveFormat::Tar, &archive_entries(&block_handler), 0, block_handler.clone(), &mut archive).unwrap();
+
+        let too_many = ExtractLimits { max_entries: 2, max_size: 1024 };
+        let extracted = extract_archive(ArchiveFormat::Tar, Cursor::new(archive.clone()), &too_many, block_handler.clone());
+        assert!(matches!(extracted, Err(Error::ArchiveLimit(_))));
+
+        let too_large = ExtractLimits { max_entries: 10, max_size: 4 };
+        let extracted = extract_archive(ArchiveFormat::Tar, Cursor::new(archive), &too_large, block_handler.clone());
+        assert!(matches!(extracted, Err(Error::ArchiveLimit(_))));
+
+        // 10MB of zeros deflate to about 10KB, far beyond the allowed size
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("zeros", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&vec![0u8; 10 * 1024 * 1024]).unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+        let extracted = extract_archive(ArchiveFormat::Zip, Cursor::new(archive), &limits(), block_handler);
+        assert!(matches!(extracted, Err(Error::ArchiveLimit(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::block::{BlockHandler, fs_handler::FsHandler};
+use crate::block::{Block, BlockHandler, fs_handler::FsHandler};
 use anyhow::Result;
 use bytes::Bytes;
 use crate::db_schema::files::Files as DbFile;
@@ -7,6 +7,7 @@ use std::sync::Arc;
 use cloud_utils::digest;
 use super::inner_utils;
 use uuid::Uuid;
+use sha2::{Digest, Sha256};
 
 
 pub struct CloudBlock {
@@ -15,6 +16,14 @@ pub struct CloudBlock {
     pub hash: String
 }
 
+/// Blocks, size and hashes of the content of a stored file
+pub struct FileContent {
+    pub blocks_name: Vec<String>,
+    pub blocks_hash: Vec<String>,
+    pub file_size: i64,
+    pub file_hash: Option<String>,
+}
+
 impl CloudBlock {
     pub fn new(name: &str, data: Bytes) -> Self {
         let hash = digest::sha256_digest(&data);
@@ -40,15 +49,58 @@ impl CloudBlock {
         Ok((blocks_name, blocks_hash))
     }
 
+    // write the data as a single block with the name of this CloudBlock,
+    // unlike store_block it isn't cut
+    pub fn store_as_block(&self, block_handler: Arc<dyn BlockHandler>) -> Result<()> {
+        block_handler.write_blocks(vec![Block::new(self.name.clone(), self.data.clone())])
+    }
+
+    // sha256 of the blocks concatenated in order, read one block at a time
+    pub fn digest_blocks(blocks_name: &[String], block_handler: Arc<dyn BlockHandler>) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for block_name in blocks_name {
+            for block in block_handler.get_blocks(vec![block_name.as_str()])? {
+                hasher.update(&block.data);
+            }
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    // sha256 of `length` bytes from `offset` of the blocks concatenated in order,
+    // only the blocks overlapping the range are read
+    pub fn digest_range(
+        blocks_name: &[String],
+        offset: u64,
+        length: u64,
+        block_handler: Arc<dyn BlockHandler>,
+    ) -> Result<String> {
+        let blocks_size = block_handler.get_blocks_size(blocks_name.iter().map(|name| name.as_str()).collect())?;
+        let end = offset + length;
+        let mut hasher = Sha256::new();
+        let mut block_start = 0;
+        for (block_name, block_size) in blocks_name.iter().zip(blocks_size) {
+            let block_end = block_start + block_size;
+            if block_end > offset && block_start < end {
+                let block = block_handler.get_blocks(vec![block_name.as_str()])?.remove(0);
+                let from = offset.saturating_sub(block_start) as usize;
+                let to = (end.min(block_end) - block_start) as usize;
+                hasher.update(&block.data[from..to]);
+            }
+            if block_end >= end {
+                break;
+            }
+            block_start = block_end;
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
     pub async fn store_file(
         uid: Uuid,
         ws_id: Uuid,
         parent_dir_id: i64,
         id: i64,
-        blocks_name: Vec<String>,
-        blocks_hash: Vec<String>,
-        file_size: i64,
         filename: String,
+        content: FileContent,
         db: &PgPool,
     ) -> Result<()> {
         let db_file = DbFile::new(
@@ -57,11 +109,37 @@ impl CloudBlock {
             ws_id,
             filename,
             parent_dir_id,
-            file_size,
+            content.file_size,
             false
         );
-        db_file.insert_file(blocks_name, blocks_hash, db).await?;
+        db_file.insert_file(content.blocks_name, content.blocks_hash, content.file_hash.as_deref(), db).await?;
         Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::fs_handler::FsHandler;
+
+    #[test]
+    fn test_digest_range() {
+        let fs_handler: Arc<dyn BlockHandler> = Arc::new(FsHandler::new("."));
+        let blocks_name: Vec<String> = (0..3).map(|_| Uuid::now_v7().to_string()).collect();
+        let blocks = blocks_name
+            .iter()
+            .zip(["hello ", "wonderful ", "world"])
+            .map(|(name, data)| Block::new(name.clone(), Bytes::from(data)))
+            .collect();
+        fs_handler.write_blocks(blocks).unwrap();
+
+        // the range spans the three blocks
+        let digest = CloudBlock::digest_range(&blocks_name, 4, 14, fs_handler.clone()).unwrap();
+        assert_eq!(digest, digest::sha256_digest(&Bytes::from("o wonderful wo")));
+        let digest = CloudBlock::digest_range(&blocks_name, 6, 10, fs_handler.clone()).unwrap();
+        assert_eq!(digest, digest::sha256_digest(&Bytes::from("wonderful ")));
+
+        fs_handler.delete_blocks(blocks_name.iter().map(|name| name.as_str()).collect()).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::db_schema::files::{normalize_name, Files as DbFile};
+use crate::error::Error;
+use crate::utils::snowflake::SnowFlake;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+
+/// Server-side copy of a file or a dir tree.
+/// Only `files` and `file_histories` rows are duplicated, the copies share the slices of the source.
+pub struct CloudCopy {
+    pub uid: Uuid,
+    pub ws_id: Uuid,
+    pub parent_dir_id: i64,
+    pub filename: String,
+}
+
+impl CloudCopy {
+    pub fn new(uid: Uuid, ws_id: Uuid, parent_dir_id: i64, filename: String) -> Self {
+        Self {
+            uid,
+            ws_id,
+            parent_dir_id,
+            filename,
+        }
+    }
+
+    /// 1. load `src` with all its descendants
+    /// 2. insert a copy of every entry with a new id in one transaction
+    /// 3. call `on_progress(copied, total)` after every entry
+    ///
+    /// return the copy of `src`
+    pub async fn copy_tree<F, Fut>(
+        &self,
+        src: &DbFile,
+        snowflake: &Mutex<SnowFlake>,
+        pool: &PgPool,
+        mut on_progress: F,
+    ) -> Result<DbFile, Error>
+    where
+        F: FnMut(usize, usize) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let tree = DbFile::get_tree(src.id, pool).await?;
+        if tree.is_empty() {
+            return Err(Error::NotFound);
+        }
+        if tree.iter().any(|file| file.id == self.parent_dir_id) {
+            return Err(Error::IntoDescendant);
+        }
+
+        let mut tx = pool.begin().await?;
+        let conflict = sqlx::query("select id from files where ws_id = $1 and parent_dir_id = $2 \
+        and name_key = file_name_key($1, $3) and is_deleted = false")
+            .bind(self.ws_id)
+            .bind(self.parent_dir_id)
+            .bind(normalize_name(&self.filename))
+            .fetch_optional(&mut tx)
+            .await?;
+        if conflict.is_some() {
+            return Err(Error::NameConflict);
+        }
+
+        // old id => new id, used to hook children up to the copy of their parent
+        let mut new_ids: HashMap<i64, i64> = HashMap::new();
+        let total = tree.len();
+        let mut root = None;
+        for (index, file) in tree.into_iter().enumerate() {
+            let id = snowflake.lock().unwrap().next_id();
+            let (parent_dir_id, filename) = match index {
+                0 => (self.parent_dir_id, self.filename.clone()),
+                _ => (new_ids[&file.parent_dir_id], file.filename.clone()),
+            };
+
+            let mut copy = DbFile::new(id, self.uid, self.ws_id, filename, parent_dir_id, file.size, file.is_dir);
+            copy.version = file.version;
+            copy.total_size = file.total_size;
+            copy.file_count = file.file_count;
+            copy.insert_copy(file.id, &mut tx).await?;
+            new_ids.insert(file.id, id);
+
+            if index == 0 {
+                root = Some(copy);
+            }
+            on_progress(index + 1, total).await;
+        }
+
+        // the copied dirs already carry their usage, only the ancestors of the target need it
+        let root = root.unwrap();
+        let (size, file_count) = root.usage();
+        DbFile::update_dir_usage(self.parent_dir_id, size, file_count, &mut tx).await?;
+
+        tx.commit().await?;
+        Ok(root)
+    }
+}
@@ -45,7 +45,7 @@ impl CloudFile {
             self.name.clone(),
             parent_dir_id,
             0,
-            false,
+            true,
         );
 
         let dir = db_file.insert_dir(pool).await?;
@@ -80,7 +80,7 @@ impl CloudFile {
             false,
         );
 
-        db_file.insert_file(blocks_name, blocks_hash, db).await?;
+        db_file.insert_file(blocks_name, blocks_hash, Some(&self.hash), db).await?;
         Ok(db_file)
     }
 
@@ -0,0 +1,120 @@
+use crate::db_schema::files::Files as DbFile;
+use crate::error::Error;
+use crate::utils::snowflake::SnowFlake;
+use sqlx::PgPool;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+
+/// A slash separated path inside a workspace, like "/photos/2023/a.jpg".
+/// Empty components are ignored, so "photos//2023/" equals "/photos/2023".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloudPath {
+    components: Vec<String>,
+}
+
+impl CloudPath {
+    /// "." and ".." are rejected, a path is always resolved from the root dir
+    pub fn parse(path: &str) -> Result<Self, Error> {
+        let components = path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(|component| component.to_string())
+            .collect::<Vec<String>>();
+
+        if components.iter().any(|component| component == "." || component == "..") {
+            return Err(Error::InvalidPath);
+        }
+
+        Ok(Self { components })
+    }
+
+    pub fn components(&self) -> &[String] {
+        &self.components
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// components of the parent dir, empty for the root dir and its children
+    pub fn parent(&self) -> &[String] {
+        match self.components.split_last() {
+            Some((_, parent)) => parent,
+            None => &[],
+        }
+    }
+
+    /// the last component, `None` for the root dir
+    pub fn filename(&self) -> Option<&str> {
+        self.components.last().map(|filename| filename.as_str())
+    }
+
+    /// the same path with the last component replaced by `filename`
+    pub fn with_filename(&self, filename: &str) -> Self {
+        let mut components = self.parent().to_vec();
+        components.push(filename.to_string());
+        Self { components }
+    }
+
+    pub fn join(dir_path: &str, filename: &str) -> String {
+        match dir_path.ends_with('/') {
+            true => format!("{}{}", dir_path, filename),
+            false => format!("{}/{}", dir_path, filename),
+        }
+    }
+
+    /// Like `mkdir -p`, create every missing dir of `components` under `parent_dir_id`.
+    /// Return the id of the last dir, `parent_dir_id` itself if `components` is empty.
+    pub async fn mkdir_all(
+        uid: Uuid,
+        ws_id: Uuid,
+        parent_dir_id: i64,
+        components: &[String],
+        snowflake: &Mutex<SnowFlake>,
+        pool: &PgPool,
+    ) -> Result<i64, Error> {
+        let mut dir_id = parent_dir_id;
+        for name in components {
+            dir_id = match DbFile::find_by_name(ws_id, dir_id, name, pool).await? {
+                Some(file) if file.is_dir => file.id,
+                Some(_) => return Err(Error::NotADirectory),
+                None => {
+                    let id = snowflake.lock().unwrap().next_id();
+                    let dir = DbFile::new(id, uid, ws_id, name.clone(), dir_id, 0, true);
+                    match dir.insert_dir(pool).await {
+                        Ok(dir) => dir.id,
+                        // the dir may be created by a concurrent request in the meantime
+                        Err(e) => match DbFile::find_by_name(ws_id, dir_id, name, pool).await? {
+                            Some(file) if file.is_dir => file.id,
+                            _ => return Err(e.into()),
+                        },
+                    }
+                }
+            };
+        }
+
+        Ok(dir_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        let path = CloudPath::parse("/photos//2023/a.jpg").unwrap();
+        assert_eq!(path.components(), ["photos", "2023", "a.jpg"]);
+        assert_eq!(path.parent(), ["photos", "2023"]);
+        assert_eq!(path.filename(), Some("a.jpg"));
+
+        let root = CloudPath::parse("/").unwrap();
+        assert!(root.is_root());
+        assert_eq!(root.filename(), None);
+
+        assert!(CloudPath::parse("/photos/../etc").is_err());
+        assert_eq!(CloudPath::join("/", "a"), "/a");
+        assert_eq!(CloudPath::join("/photos", "a"), "/photos/a");
+    }
+}
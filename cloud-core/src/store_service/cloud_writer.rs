@@ -0,0 +1,143 @@
+use crate::block::{Block, BlockHandler};
+use crate::error::Error;
+use bytes::BytesMut;
+use cloud_utils::digest;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use super::inner_utils::BLOCK_MAX_SIZE;
+
+
+/// Blocks and hashes of a file written by `CloudWriter`.
+pub struct WrittenFile {
+    pub blocks_name: Vec<String>,
+    pub blocks_hash: Vec<String>,
+    pub blocks_size: Vec<usize>,
+    pub size: usize,
+    /// sha256 of the whole file
+    pub hash: String,
+}
+
+/// Cut a file into blocks while its bytes arrive, so it never needs to be held in memory.
+///
+/// Every block is written to the block handler as soon as it is full, and the hash of
+/// the whole file is updated on every write. Call `abort` to delete the written blocks
+/// if the upload fails.
+pub struct CloudWriter {
+    block_handler: Arc<dyn BlockHandler>,
+    max_size: usize,
+    buffer: BytesMut,
+    hasher: Sha256,
+    size: usize,
+    blocks_name: Vec<String>,
+    blocks_hash: Vec<String>,
+    blocks_size: Vec<usize>,
+}
+
+impl CloudWriter {
+    pub fn new(block_handler: Arc<dyn BlockHandler>, max_size: usize) -> Self {
+        Self {
+            block_handler,
+            max_size,
+            buffer: BytesMut::with_capacity(BLOCK_MAX_SIZE),
+            hasher: Sha256::new(),
+            size: 0,
+            blocks_name: Vec::new(),
+            blocks_hash: Vec::new(),
+            blocks_size: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.size + data.len() > self.max_size {
+            return Err(Error::TooLarge);
+        }
+        self.size += data.len();
+        self.hasher.update(data);
+
+        while !data.is_empty() {
+            let len = data.len().min(BLOCK_MAX_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.buffer.len() == BLOCK_MAX_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write the last block and return what was written
+    pub fn finish(&mut self) -> Result<WrittenFile, Error> {
+        if !self.buffer.is_empty() {
+            self.flush_block()?;
+        }
+
+        Ok(WrittenFile {
+            blocks_name: self.blocks_name.clone(),
+            blocks_hash: self.blocks_hash.clone(),
+            blocks_size: self.blocks_size.clone(),
+            size: self.size,
+            hash: format!("{:x}", self.hasher.clone().finalize()),
+        })
+    }
+
+    /// delete all blocks written so far
+    pub fn abort(&mut self) {
+        let blocks_name = self.blocks_name.iter().map(|name| name.as_str()).collect();
+        if let Err(e) = self.block_handler.delete_blocks(blocks_name) {
+            log::error!("failed to delete blocks of an aborted upload: {:?}", e);
+        }
+        self.blocks_name.clear();
+        self.blocks_hash.clear();
+        self.blocks_size.clear();
+    }
+
+    fn flush_block(&mut self) -> Result<(), Error> {
+        let data = self.buffer.split().freeze();
+        let block_name = uuid::Uuid::now_v7().to_string();
+        let block_hash = digest::sha256_digest(&data);
+        let block_size = data.len();
+
+        self.block_handler.write_blocks(vec![Block::new(block_name.clone(), data)])?;
+        self.blocks_name.push(block_name);
+        self.blocks_hash.push(block_hash);
+        self.blocks_size.push(block_size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::fs_handler::FsHandler;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_write_in_pieces() {
+        let data = Bytes::from(vec![7; BLOCK_MAX_SIZE * 2 + 10]);
+        let fs_handler = Arc::new(FsHandler::new("."));
+        let mut writer = CloudWriter::new(fs_handler.clone(), data.len());
+        for piece in data.chunks(1_000_000) {
+            writer.write(piece).unwrap();
+        }
+        let written = writer.finish().unwrap();
+
+        assert_eq!(written.size, data.len());
+        assert_eq!(written.hash, digest::sha256_digest(&data));
+        assert_eq!(written.blocks_name.len(), 3);
+        assert_eq!(written.blocks_size, vec![BLOCK_MAX_SIZE, BLOCK_MAX_SIZE, 10]);
+
+        let blocks = fs_handler.get_blocks(written.blocks_name.iter().map(|name| name.as_str()).collect()).unwrap();
+        assert_eq!(blocks[2].data.len(), 10);
+
+        writer.abort();
+        assert!(fs_handler.get_blocks(written.blocks_name.iter().map(|name| name.as_str()).collect()).is_err());
+    }
+
+    #[test]
+    fn test_write_too_large() {
+        let fs_handler = Arc::new(FsHandler::new("."));
+        let mut writer = CloudWriter::new(fs_handler, 10);
+        writer.write(b"12345").unwrap();
+        assert!(matches!(writer.write(b"123456"), Err(Error::TooLarge)));
+    }
+}
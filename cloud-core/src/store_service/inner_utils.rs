@@ -2,7 +2,7 @@ use bytes::Bytes;
 use crate::block::Block;
 use cloud_utils::digest;
 
-const BLOCK_MAX_SIZE: usize = 4 * 1024 * 1024;
+pub(crate) const BLOCK_MAX_SIZE: usize = 4 * 1024 * 1024;
 
 // #[cfg(feature = "v7")]
 pub fn cut(data: &Bytes) -> (Vec<Block>, Vec<String>) {
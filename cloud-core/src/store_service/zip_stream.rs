@@ -0,0 +1,312 @@
+use crc32fast::Hasher;
+use std::io::{self, Read, Write};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// made by unix, so the external attributes carry the unix mode
+const VERSION_MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+// sizes and crc follow the data, names are utf-8
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+struct CentralEntry {
+    name: String,
+    is_dir: bool,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+impl CentralEntry {
+    fn is_zip64(&self) -> bool {
+        self.size >= u32::MAX as u64 || self.offset >= u32::MAX as u64
+    }
+}
+
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a zip to a stream which can't seek, like a response body.
+///
+/// Entries are stored without compression and their crc and size are written in a
+/// data descriptor after the content, so nothing needs to be known beforehand.
+/// Zip64 records are used for the entries, offsets and counts which need them.
+pub(crate) struct ZipStream<W: Write> {
+    out: CountingWriter<W>,
+    entries: Vec<CentralEntry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl<W: Write> ZipStream<W> {
+    /// `mtime` is the unix time of all entries
+    pub(crate) fn new(out: W, mtime: u64) -> Self {
+        let (dos_date, dos_time) = dos_date_time(mtime);
+        Self {
+            out: CountingWriter { inner: out, written: 0 },
+            entries: Vec::new(),
+            dos_time,
+            dos_date,
+        }
+    }
+
+    /// `name` is the path of the dir, without the trailing slash
+    pub(crate) fn add_dir(&mut self, name: &str) -> io::Result<()> {
+        let name = format!("{}/", name);
+        let offset = self.out.written;
+        self.write_local_header(&name, FLAG_UTF8, false)?;
+        self.entries.push(CentralEntry { name, is_dir: true, crc: 0, size: 0, offset });
+        Ok(())
+    }
+
+    /// `size` is the size `content` is expected to have, a streaming reader takes the
+    /// size of the data descriptor from the local header, so it's decided before the content
+    pub(crate) fn add_file<R: Read>(&mut self, name: &str, size: u64, mut content: R) -> io::Result<()> {
+        let zip64 = size >= u32::MAX as u64;
+        let offset = self.out.written;
+        self.write_local_header(name, FLAG_UTF8 | FLAG_DATA_DESCRIPTOR, zip64)?;
+
+        let mut hasher = Hasher::new();
+        let mut size = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = content.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.out.write_all(&buf[..n])?;
+            size += n as u64;
+        }
+        let crc = hasher.finalize();
+        if !zip64 && size >= u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is larger than expected", name)));
+        }
+
+        let out = &mut self.out;
+        write_u32(out, DATA_DESCRIPTOR_SIGNATURE)?;
+        write_u32(out, crc)?;
+        if zip64 {
+            write_u64(out, size)?;
+            write_u64(out, size)?;
+        } else {
+            write_u32(out, size as u32)?;
+            write_u32(out, size as u32)?;
+        }
+
+        self.entries.push(CentralEntry { name: name.to_string(), is_dir: false, crc, size, offset });
+        Ok(())
+    }
+
+    /// write the central directory and return the stream
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let cd_offset = self.out.written;
+        for entry in &self.entries {
+            write_central_header(&mut self.out, entry, self.dos_time, self.dos_date)?;
+        }
+        let cd_size = self.out.written - cd_offset;
+        let count = self.entries.len() as u64;
+
+        let out = &mut self.out;
+        if count >= u16::MAX as u64 || cd_size >= u32::MAX as u64 || cd_offset >= u32::MAX as u64 {
+            let zip64_end_offset = out.written;
+            write_u32(out, ZIP64_END_SIGNATURE)?;
+            // size of the rest of the record
+            write_u64(out, 44)?;
+            write_u16(out, VERSION_MADE_BY)?;
+            write_u16(out, VERSION_ZIP64)?;
+            write_u32(out, 0)?;
+            write_u32(out, 0)?;
+            write_u64(out, count)?;
+            write_u64(out, count)?;
+            write_u64(out, cd_size)?;
+            write_u64(out, cd_offset)?;
+
+            write_u32(out, ZIP64_LOCATOR_SIGNATURE)?;
+            write_u32(out, 0)?;
+            write_u64(out, zip64_end_offset)?;
+            write_u32(out, 1)?;
+        }
+
+        write_u32(out, END_SIGNATURE)?;
+        write_u16(out, 0)?;
+        write_u16(out, 0)?;
+        write_u16(out, count.min(u16::MAX as u64) as u16)?;
+        write_u16(out, count.min(u16::MAX as u64) as u16)?;
+        write_u32(out, cd_size.min(u32::MAX as u64) as u32)?;
+        write_u32(out, cd_offset.min(u32::MAX as u64) as u32)?;
+        // comment length
+        write_u16(out, 0)?;
+
+        self.out.flush()?;
+        Ok(self.out.inner)
+    }
+
+    fn write_local_header(&mut self, name: &str, flags: u16, zip64: bool) -> io::Result<()> {
+        let out = &mut self.out;
+        write_u32(out, LOCAL_HEADER_SIGNATURE)?;
+        write_u16(out, if zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT })?;
+        write_u16(out, flags)?;
+        // stored
+        write_u16(out, 0)?;
+        write_u16(out, self.dos_time)?;
+        write_u16(out, self.dos_date)?;
+        // crc and sizes are in the data descriptor
+        write_u32(out, 0)?;
+        write_u32(out, 0)?;
+        write_u32(out, 0)?;
+        write_u16(out, name.len() as u16)?;
+        write_u16(out, if zip64 { 20 } else { 0 })?;
+        out.write_all(name.as_bytes())?;
+        if zip64 {
+            // the sizes are in the data descriptor too, which has 8 bytes for each of them
+            write_u16(out, ZIP64_EXTRA_ID)?;
+            write_u16(out, 16)?;
+            write_u64(out, 0)?;
+            write_u64(out, 0)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_central_header<W: Write>(out: &mut W, entry: &CentralEntry, dos_time: u16, dos_date: u16) -> io::Result<()> {
+    let mut extra = Vec::new();
+    if entry.size >= u32::MAX as u64 {
+        // uncompressed and compressed size
+        extra.extend_from_slice(&entry.size.to_le_bytes());
+        extra.extend_from_slice(&entry.size.to_le_bytes());
+    }
+    if entry.offset >= u32::MAX as u64 {
+        extra.extend_from_slice(&entry.offset.to_le_bytes());
+    }
+
+    let (flags, mode) = match entry.is_dir {
+        true => (FLAG_UTF8, 0o040755u32),
+        false => (FLAG_UTF8 | FLAG_DATA_DESCRIPTOR, 0o100644u32),
+    };
+    // the msdos directory bit goes with the unix mode
+    let external_attributes = mode << 16 | if entry.is_dir { 0x10 } else { 0 };
+
+    write_u32(out, CENTRAL_HEADER_SIGNATURE)?;
+    write_u16(out, VERSION_MADE_BY)?;
+    write_u16(out, if entry.is_zip64() { VERSION_ZIP64 } else { VERSION_DEFAULT })?;
+    write_u16(out, flags)?;
+    write_u16(out, 0)?;
+    write_u16(out, dos_time)?;
+    write_u16(out, dos_date)?;
+    write_u32(out, entry.crc)?;
+    write_u32(out, entry.size.min(u32::MAX as u64) as u32)?;
+    write_u32(out, entry.size.min(u32::MAX as u64) as u32)?;
+    write_u16(out, entry.name.len() as u16)?;
+    write_u16(out, if extra.is_empty() { 0 } else { extra.len() as u16 + 4 })?;
+    // comment length, disk number, internal attributes
+    write_u16(out, 0)?;
+    write_u16(out, 0)?;
+    write_u16(out, 0)?;
+    write_u32(out, external_attributes)?;
+    write_u32(out, entry.offset.min(u32::MAX as u64) as u32)?;
+    out.write_all(entry.name.as_bytes())?;
+    if !extra.is_empty() {
+        write_u16(out, ZIP64_EXTRA_ID)?;
+        write_u16(out, extra.len() as u16)?;
+        out.write_all(&extra)?;
+    }
+    Ok(())
+}
+
+// msdos date and time of a unix time, in utc, clamped to the years msdos can hold
+fn dos_date_time(unix_time: u64) -> (u16, u16) {
+    let days = (unix_time / 86400) as i64;
+    let secs = unix_time % 86400;
+
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    if year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let year = year.min(2107);
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2)) as u16;
+    (date, time)
+}
+
+fn write_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dos_date_time() {
+        // 2023-06-20 12:34:56
+        let (date, time) = dos_date_time(1687264496);
+        assert_eq!(date, (2023 - 1980) << 9 | 6 << 5 | 20);
+        assert_eq!(time, 12 << 11 | 34 << 5 | 28);
+        assert_eq!(dos_date_time(0), (1 << 5 | 1, 0));
+    }
+
+    #[test]
+    fn test_zip64_local_header() {
+        let mut zip = ZipStream::new(Vec::new(), 0);
+        zip.add_file("small", 5, &b"hello"[..]).unwrap();
+        let small_end = zip.out.written as usize;
+        zip.add_file("large", 5 << 30, &b"hello"[..]).unwrap();
+        let out = zip.finish().unwrap();
+
+        // version, extra length, and a descriptor with 4 byte sizes
+        assert_eq!(u16::from_le_bytes([out[4], out[5]]), VERSION_DEFAULT);
+        assert_eq!(u16::from_le_bytes([out[28], out[29]]), 0);
+        assert_eq!(small_end, 30 + "small".len() + 5 + 16);
+        // the extra field of a large one announces a descriptor with 8 byte sizes
+        let large = &out[small_end..];
+        assert_eq!(u16::from_le_bytes([large[4], large[5]]), VERSION_ZIP64);
+        assert_eq!(u16::from_le_bytes([large[28], large[29]]), 20);
+        assert_eq!(u16::from_le_bytes([large[35], large[36]]), ZIP64_EXTRA_ID);
+        let descriptor = 30 + "large".len() + 20 + 5;
+        assert_eq!(u32::from_le_bytes(large[descriptor..descriptor + 4].try_into().unwrap()), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u64::from_le_bytes(large[descriptor + 8..descriptor + 16].try_into().unwrap()), 5);
+        let central = descriptor + 24;
+        assert_eq!(u32::from_le_bytes(large[central..central + 4].try_into().unwrap()), CENTRAL_HEADER_SIGNATURE);
+    }
+}
@@ -1,12 +1,27 @@
+mod admin;
+mod archives;
+mod email_tokens;
 mod error;
+mod jwks;
+mod keyset;
+mod login_guard;
+pub mod mailer;
+mod paths;
+pub mod session_store;
+mod session_cookies;
 mod storages;
+mod tokens;
+mod tus;
+mod two_factor;
 mod users;
+mod validation;
 mod workspaces;
 pub mod extractor;
 
-use crate::config::Config;
+use crate::config::{Config, SameSite};
 use axum::Router;
 use cloud_core::block::fs_handler::FsHandler;
+use cloud_core::db_schema::users::Users as DbUser;
 use cloud_core::utils::snowflake::SnowFlake;
 use error::CustomError;
 use sqlx::PgPool;
@@ -16,6 +31,9 @@ use tower::ServiceBuilder;
 use tower_http::add_extension::AddExtensionLayer;
 use tower_http::trace::TraceLayer;
 use redis::Client;
+use session_store::UploadSessionStore;
+use mailer::Mailer;
+use keyset::KeySet;
 
 pub type Result<T, E = CustomError> = std::result::Result<T, E>;
 
@@ -26,18 +44,33 @@ pub struct ApiContext {
     snowflake: Arc<Mutex<SnowFlake>>,
     //block_handler: BlockHandlerWrapper<T>,
     fs_handler: Arc<FsHandler>,
-    redis_client: Arc<Client>
+    redis_client: Arc<Client>,
+    upload_sessions: Arc<dyn UploadSessionStore>,
+    mailer: Arc<dyn Mailer>,
+    // none when the access tokens are signed by the hmac key
+    keyset: Option<Arc<KeySet>>,
 }
 
 impl ApiContext {
-    pub fn new(config: Config, db: PgPool, snowflake: SnowFlake, fs_handler: FsHandler, redis_client: Client) -> Self {
-        Self {
+    /// Fails on a keyset file which can't be read or written, or cookies browsers would drop.
+    pub fn new(config: Config, db: PgPool, snowflake: SnowFlake, fs_handler: FsHandler, redis_client: Client) -> anyhow::Result<Self> {
+        if config.cookie_same_site == SameSite::None && !config.cookie_secure {
+            anyhow::bail!("cookie_same_site None needs cookie_secure");
+        }
+        let redis_client = Arc::new(redis_client);
+        let upload_sessions = session_store::new_store(&config, &db, &redis_client);
+        let mailer = mailer::new_mailer(&config)?;
+        let keyset = KeySet::open(&config)?;
+        Ok(Self {
             config: Arc::new(config),
             db,
             snowflake: Arc::new(Mutex::new(snowflake)),
             fs_handler: Arc::new(fs_handler),
-            redis_client: Arc::new(redis_client)
-        }
+            redis_client,
+            upload_sessions,
+            mailer,
+            keyset,
+        })
     }
 }
 
@@ -52,18 +85,22 @@ pub async fn serve(config: Config, db: PgPool, redis_client: Client) -> Result<(
     let url = format!("{}:{}", &config.host, config.port);
     let url = url.parse::<SocketAddr>().unwrap();
 
-    let api_ctx = ApiContext {
-        config: Arc::new(config),
-        db,
-        snowflake: Arc::new(Mutex::new(snowflake)),
-        fs_handler: Arc::new(block_handler),
-        redis_client: Arc::new(redis_client)
-    };
+    if let Some(admin_email) = &config.admin_email {
+        match DbUser::promote_admin(admin_email, &db).await? {
+            true => log::info!("{} is an admin", admin_email),
+            false => log::warn!("admin {} has no account yet", admin_email),
+        }
+    }
+
+    let api_ctx = ApiContext::new(config, db, snowflake, block_handler, redis_client)?;
 
+    storages::spawn_session_reaper(api_ctx.clone());
+    keyset::spawn_rotation(api_ctx.clone());
     let app = api_router(api_ctx);
 
     axum::Server::bind(&url)
-        .serve(app.into_make_service())
+        // the address of the client counts the failed logins
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .expect("error running HTTP server");
     Ok(())
@@ -73,7 +110,15 @@ pub fn api_router(api_ctx: ApiContext) -> Router {
     // This is the order that the modules were authored in.
     let api_router = users::router()
         .merge(storages::router())
-        .merge(workspaces::router());
+        .merge(workspaces::router())
+        .merge(paths::router())
+        .merge(tus::router())
+        .merge(archives::router())
+        .merge(tokens::router())
+        .merge(two_factor::router())
+        .merge(email_tokens::router())
+        .merge(admin::router())
+        .merge(jwks::router());
     api_router.layer(
         ServiceBuilder::new()
             .layer(AddExtensionLayer::new(api_ctx))
@@ -0,0 +1,202 @@
+use axum::extract::{Extension, Path, Query};
+use axum::routing::{delete, get, put};
+use axum::{Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use crate::api::error::CustomError;
+use crate::api::extractor::AdminUser;
+use crate::api::storages;
+use crate::api::users::hash_password;
+use crate::api::{ApiContext, Result};
+use crate::api_common::admin::{CreateInviteReq, Invite, InviteBody, ListUsersReq, SetPasswordReq, UpdateAccountReq,
+                               UserAccount, UserRole};
+use crate::api_common::users::UserBody;
+use cloud_core::block::BlockHandler;
+use cloud_core::db_schema::files::Files as DbFile;
+use cloud_core::db_schema::invite_codes::InviteCodes;
+use cloud_core::db_schema::users::Users as DbUser;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/admin/users", get(list_users))
+        .route("/api/admin/users/:id", get(get_user).put(update_user).delete(delete_user))
+        .route("/api/admin/users/:id/password", put(set_password))
+        .route("/api/admin/invites", get(list_invites).post(create_invite))
+        .route("/api/admin/invites/:id", delete(delete_invite))
+}
+
+pub(crate) fn invite_code_hash(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().as_bytes()))
+}
+
+async fn to_account(user: DbUser, ctx: &ApiContext) -> Result<UserAccount> {
+    let usage = DbFile::get_user_usage(user.id, &ctx.db).await?;
+    Ok(UserAccount {
+        id: user.id,
+        username: user.name,
+        email: user.email,
+        email_verified: user.email_verified,
+        totp_enabled: user.totp_enabled,
+        role: UserRole::parse(&user.role).unwrap_or(UserRole::User),
+        disabled: user.disabled,
+        quota: user.quota,
+        usage,
+    })
+}
+
+async fn find_user(id: Uuid, ctx: &ApiContext) -> Result<DbUser> {
+    match DbUser::find_by_id(id, &ctx.db).await {
+        Ok(user) => Ok(user),
+        Err(sqlx::Error::RowNotFound) => Err(CustomError::NotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn list_users(
+    _admin: AdminUser,
+    ctx: Extension<ApiContext>,
+    Query(req): Query<ListUsersReq>,
+) -> Result<Json<Vec<UserBody<UserAccount>>>> {
+    let limit = req.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit <= 0 || limit > MAX_PAGE_SIZE {
+        return Err(CustomError::unprocessable_entity([("limit", "must be between 1 and 500")]));
+    }
+    let offset = req.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(CustomError::unprocessable_entity([("offset", "must not be negative")]));
+    }
+    let query = req.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+
+    let mut users = Vec::new();
+    for user in DbUser::search(query, limit, offset, &ctx.db).await? {
+        users.push(UserBody { user: to_account(user, &ctx).await? });
+    }
+    Ok(Json(users))
+}
+
+async fn get_user(
+    _admin: AdminUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<UserBody<UserAccount>>> {
+    let user = find_user(id, &ctx).await?;
+    Ok(Json(UserBody { user: to_account(user, &ctx).await? }))
+}
+
+// an admin can't lock itself out, another admin has to do it
+async fn update_user(
+    AdminUser(admin): AdminUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<UserBody<UpdateAccountReq>>,
+) -> Result<Json<UserBody<UserAccount>>> {
+    let req = req.user;
+    if id == admin.user_id && (req.role == Some(UserRole::User) || req.disabled == Some(true)) {
+        return Err(CustomError::unprocessable_entity([("id", "is the current admin")]));
+    }
+    if req.quota.flatten().is_some_and(|quota| quota < 0) {
+        return Err(CustomError::unprocessable_entity([("quota", "must not be negative")]));
+    }
+
+    find_user(id, &ctx).await?;
+    let user = DbUser::update_account(id, req.role.map(|role| role.as_str()), req.disabled, req.quota, &ctx.db).await?;
+    Ok(Json(UserBody { user: to_account(user, &ctx).await? }))
+}
+
+async fn delete_user(
+    AdminUser(admin): AdminUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+) -> Result<()> {
+    if id == admin.user_id {
+        return Err(CustomError::unprocessable_entity([("id", "is the current admin")]));
+    }
+    // discard the sessions with their blocks, the redis ones aren't deleted with the user's rows
+    for session_id in ctx.upload_sessions.user_sessions(id).await? {
+        storages::discard_session(session_id, &ctx).await?;
+    }
+    let blocks = DbUser::delete(id, &ctx.db).await?.ok_or(CustomError::NotFound)?;
+    ctx.fs_handler.delete_blocks(blocks.iter().map(|name| name.as_str()).collect())?;
+    Ok(())
+}
+
+// like a password change of the user, every token of it is revoked
+async fn set_password(
+    _admin: AdminUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<UserBody<SetPasswordReq>>,
+) -> Result<()> {
+    find_user(id, &ctx).await?;
+    let password_hash = hash_password(req.user.password).await?;
+    DbUser::update_by_id(id, None, None, Some(password_hash), &ctx.db).await?;
+    Ok(())
+}
+
+fn to_invite(invite: InviteCodes, code: Option<String>) -> Invite {
+    Invite {
+        id: invite.id,
+        note: invite.note,
+        created_at: invite.created_at,
+        expires_at: invite.expires_at,
+        used_by: invite.used_by,
+        used_at: invite.used_at,
+        code,
+    }
+}
+
+// the code is only returned here, afterwards it can only be deleted
+async fn create_invite(
+    AdminUser(admin): AdminUser,
+    ctx: Extension<ApiContext>,
+    Json(req): Json<InviteBody<CreateInviteReq>>,
+) -> Result<Json<InviteBody<Invite>>> {
+    let req = req.invite;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if req.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(CustomError::unprocessable_entity([("expires_at", "must be in the future")]));
+    }
+
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = URL_SAFE_NO_PAD.encode(bytes);
+    let invite = InviteCodes {
+        id: Uuid::now_v7(),
+        code_hash: invite_code_hash(&code),
+        created_by: Some(admin.user_id),
+        note: req.note,
+        created_at: now,
+        expires_at: req.expires_at,
+        used_by: None,
+        used_at: None,
+    };
+    invite.insert(&ctx.db).await?;
+
+    Ok(Json(InviteBody { invite: to_invite(invite, Some(code)) }))
+}
+
+async fn list_invites(
+    _admin: AdminUser,
+    ctx: Extension<ApiContext>,
+) -> Result<Json<Vec<InviteBody<Invite>>>> {
+    let invites = InviteCodes::list(&ctx.db).await?;
+    Ok(Json(invites.into_iter().map(|invite| InviteBody { invite: to_invite(invite, None) }).collect()))
+}
+
+async fn delete_invite(
+    _admin: AdminUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+) -> Result<()> {
+    if !InviteCodes::delete(id, &ctx.db).await? {
+        return Err(CustomError::NotFound);
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use axum::body::StreamBody;
+use axum::extract::{Extension, Path, BodyStream, Query};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use crate::api::{extractor::AuthUser, ApiContext, Result, error::CustomError};
+use crate::api::storages::{self, Inserted, NewContent};
+use crate::api::validation;
+use crate::api_common::storages::{ArchiveReq, BatchUploadReq, BatchUploadResp, ConflictPolicy};
+use bytes::{Bytes, BytesMut};
+use cloud_core::block::BlockHandler;
+use cloud_core::db_schema::files::Files as DbFile;
+use cloud_core::db_schema::file_histories::FileHistories;
+use cloud_core::error::Error as CoreError;
+use cloud_core::store_service::cloud_archive::{self, ArchiveEntry, ArchiveFormat, Extracted, ExtractLimits};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// the archive is sent in pieces of this size
+const ARCHIVE_CHUNK_SIZE: usize = 256 * 1024;
+// pieces waiting for a slow client
+const ARCHIVE_CHANNEL_SIZE: usize = 4;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/:ws_id/archives", get(download_archive).post(upload_archive))
+}
+
+// Send the selected files and dirs as a zip or tar. The archive is written from
+// the blocks while it's sent, so its size is unknown and it's never stored.
+async fn download_archive(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(ws_id): Path<Uuid>,
+    Query(archive_req): Query<ArchiveReq>,
+) -> Result<Response> {
+    let format = match archive_req.format.as_deref() {
+        None => ArchiveFormat::Zip,
+        Some(format) => ArchiveFormat::parse(format)
+            .ok_or(CustomError::unprocessable_entity([("format", "must be zip or tar")]))?,
+    };
+    let ids = archive_req.ids
+        .split(',')
+        .map(|id| id.trim().parse::<i64>())
+        .collect::<std::result::Result<Vec<i64>, _>>()
+        .map_err(|_| CustomError::unprocessable_entity([("ids", "must be comma separated ids")]))?;
+
+    let mut selected = Vec::new();
+    for id in &ids {
+        selected.push(storages::check_file_owner(auth_user.user_id, *id, ws_id, &ctx).await?);
+    }
+    let filename = match selected.as_slice() {
+        [file] => format!("{}.{}", file.filename, format.extension()),
+        _ => format!("archive.{}", format.extension()),
+    };
+    let entries = archive_entries(selected, &ctx).await?;
+
+    let (tx, mut rx) = mpsc::channel(ARCHIVE_CHANNEL_SIZE);
+    let block_handler: Arc<dyn BlockHandler> = ctx.fs_handler.clone();
+    let mtime = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    tokio::task::spawn_blocking(move || {
+        let mut body = BodyWriter { tx: tx.clone(), buffer: BytesMut::new() };
+        if let Err(e) = cloud_archive::write_archive(format, &entries, mtime, block_handler, &mut body) {
+            log::warn!("archive download stopped: {:?}", e);
+            // the client gets a broken body instead of a truncated archive
+            let _ = tx.blocking_send(Err(io::Error::other(e)));
+        }
+    });
+    let stream = futures::stream::poll_fn(move |cx| rx.poll_recv(cx));
+
+    let content_type = match format {
+        ArchiveFormat::Zip => "application/zip",
+        ArchiveFormat::Tar => "application/x-tar",
+    };
+    let headers = [
+        (CONTENT_TYPE, content_type.to_string()),
+        (CONTENT_DISPOSITION, format!("attachment; filename*=UTF-8''{}", percent_encode(&filename))),
+    ];
+    Ok((headers, StreamBody::new(stream)).into_response())
+}
+
+// the entries of the selected files and dirs with everything below the dirs,
+// parents always come before their children
+async fn archive_entries(selected: Vec<DbFile>, ctx: &ApiContext) -> Result<Vec<ArchiveEntry>> {
+    let mut files = Vec::new();
+    let mut paths = HashMap::new();
+    for file in selected {
+        let tree = match file.is_dir {
+            true => DbFile::get_tree(file.id, &ctx.db).await?,
+            false => vec![file],
+        };
+        for (i, file) in tree.into_iter().enumerate() {
+            let path = match i {
+                0 => file.filename.clone(),
+                _ => format!("{}/{}", paths[&file.parent_dir_id], file.filename),
+            };
+            paths.insert(file.id, path.clone());
+            files.push((path, file));
+        }
+    }
+
+    let fids = files.iter().filter(|(_, file)| !file.is_dir).map(|(_, file)| file.id).collect::<Vec<i64>>();
+    let mut histories = FileHistories::find_latest(&fids, &ctx.db).await?;
+
+    Ok(files.into_iter().map(|(path, file)| ArchiveEntry {
+        path,
+        is_dir: file.is_dir,
+        size: file.size as u64,
+        blocks_name: histories.remove(&file.id).map(|history| history.slices).unwrap_or_default(),
+    }).collect())
+}
+
+// Sends what's written to the response body in pieces of ARCHIVE_CHUNK_SIZE.
+// A write blocks while the client is slow, and fails once it's gone.
+struct BodyWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buffer: BytesMut,
+}
+
+impl BodyWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .blocking_send(Ok(self.buffer.split().freeze()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone"))
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= ARCHIVE_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+// percent-encode everything but the unreserved characters, for a filename* parameter
+pub(crate) fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+// Extract an uploaded zip or tar into `parent_dir_id`, the format is detected from
+// the content. The body is kept in a temporary file, a zip can't be read as it arrives.
+// Like a batch upload, an entry may fail on its own, and the report tells how each went.
+async fn upload_archive(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(ws_id): Path<Uuid>,
+    Query(batch_upload_req): Query<BatchUploadReq>,
+    headers: HeaderMap,
+    mut stream: BodyStream,
+) -> Result<Json<BatchUploadResp>> {
+    let parent_dir = storages::check_permission(auth_user.user_id, batch_upload_req.parent_dir_id, ws_id, &ctx).await?;
+    if !parent_dir.is_dir {
+        return Err(CoreError::NotADirectory.into());
+    }
+    storages::check_content_length(&headers, &ctx)?;
+
+    let mut archive = tempfile::tempfile().map_err(anyhow::Error::from)?;
+    let mut size = 0u64;
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(|_| CustomError::BadRequest)?;
+        size += bytes.len() as u64;
+        if size > ctx.config.max_upload_size {
+            return Err(CustomError::PayloadTooLarge);
+        }
+        archive.write_all(&bytes).map_err(anyhow::Error::from)?;
+    }
+
+    let limits = ExtractLimits {
+        max_entries: ctx.config.max_extract_entries,
+        max_size: ctx.config.max_extract_size,
+    };
+    let block_handler: Arc<dyn BlockHandler> = ctx.fs_handler.clone();
+    let extracted = tokio::task::spawn_blocking(move || {
+        let mut header = Vec::with_capacity(512);
+        archive.seek(SeekFrom::Start(0))?;
+        (&mut archive).take(512).read_to_end(&mut header)?;
+        let format = ArchiveFormat::detect(&header).ok_or(CoreError::InvalidArchive)?;
+        archive.seek(SeekFrom::Start(0))?;
+        cloud_archive::extract_archive(format, archive, &limits, block_handler)
+    }).await.map_err(anyhow::Error::from)??;
+
+    let mut dirs = HashMap::new();
+    let mut entries = Vec::new();
+    for entry in extracted {
+        let stored = match entry.result {
+            Ok(extracted) => store_extracted(&ctx, auth_user.user_id, ws_id, parent_dir.id, extracted,
+                                             batch_upload_req.conflict, &mut dirs).await,
+            Err(e) => Err(e.into()),
+        };
+        entries.push(storages::batch_entry(entry.name, stored));
+    }
+
+    Ok(Json(BatchUploadResp { entries }))
+}
+
+// add an extracted dir or file below `parent_dir_id`, the blocks of a file which
+// can't be added or isn't kept are deleted
+async fn store_extracted(
+    ctx: &ApiContext,
+    user_id: Uuid,
+    ws_id: Uuid,
+    parent_dir_id: i64,
+    extracted: Extracted,
+    policy: ConflictPolicy,
+    dirs: &mut HashMap<Vec<String>, i64>,
+) -> Result<DbFile> {
+    let path = match &extracted {
+        Extracted::Dir(path) | Extracted::File(path, _) => path,
+    };
+    if let Err(e) = validation::check_path("path", path.components(), &ctx.config) {
+        if let Extracted::File(_, written) = &extracted {
+            ctx.fs_handler.delete_blocks(written.blocks_name.iter().map(|name| name.as_str()).collect())?;
+        }
+        return Err(e);
+    }
+
+    let (path, written) = match extracted {
+        Extracted::Dir(path) => {
+            let dir_id = storages::resolve_batch_dir(ctx, user_id, ws_id, parent_dir_id, path.components(), dirs).await?;
+            return DbFile::check_owner(user_id, dir_id, ws_id, &ctx.db).await?.ok_or(CustomError::NotFound);
+        }
+        Extracted::File(path, written) => (path, written),
+    };
+
+    let blocks_name = written.blocks_name.clone();
+    let stored = async {
+        let dir_id = storages::resolve_batch_dir(ctx, user_id, ws_id, parent_dir_id, path.parent(), dirs).await?;
+        let filename = path.filename().ok_or(CoreError::InvalidPath)?.to_string();
+        let id = ctx.snowflake.lock().unwrap().next_id();
+        let db_file = DbFile::new(id, user_id, ws_id, filename, dir_id, written.size as i64, false);
+        let content = NewContent {
+            blocks_name: written.blocks_name,
+            blocks_hash: written.blocks_hash,
+            file_hash: Some(written.hash),
+        };
+        storages::insert_with_policy(ctx, db_file, Some(content), policy).await
+    }.await;
+
+    if !matches!(stored, Ok(Inserted::Created(_)) | Ok(Inserted::Overwritten(_))) {
+        ctx.fs_handler.delete_blocks(blocks_name.iter().map(|name| name.as_str()).collect())?;
+    }
+    stored.map(Inserted::file)
+}
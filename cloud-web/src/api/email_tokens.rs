@@ -0,0 +1,178 @@
+use axum::extract::Extension;
+use axum::routing::post;
+use axum::{Json, Router};
+use crate::api::error::CustomError;
+use crate::api::extractor::{AuthUser, HmacSha384};
+use crate::api::mailer::Mail;
+use crate::api::users::hash_password;
+use crate::api::{ApiContext, Result};
+use crate::api_common::users::{EmailTokenReq, NewPasswordReq, PasswordResetReq};
+use cloud_core::db_schema::users::Users as DbUser;
+use hmac::Mac;
+use jwt::{SignWithKey, VerifyWithKey};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+const VERIFY_EMAIL: &str = "verify_email";
+const RESET_PASSWORD: &str = "reset_password";
+// seconds the link of a mail works
+const VERIFY_EMAIL_TTL: i64 = 24 * 60 * 60;
+const RESET_PASSWORD_TTL: i64 = 60 * 60;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/users/verification", post(resend_verification))
+        .route("/api/users/verification/confirm", post(confirm_verification))
+        .route("/api/users/password_reset", post(request_password_reset))
+        .route("/api/users/password_reset/confirm", post(reset_password))
+}
+
+// The claims of the token of a mail. The token works once: a verification is for an
+// unverified email, and a reset is for the token generation the new password moves past.
+// Without a user_id it never passes for an access token.
+#[derive(Serialize, Deserialize)]
+struct EmailClaims {
+    email_user_id: Uuid,
+    purpose: String,
+    email: String,
+    gen: i32,
+    exp: i64,
+}
+
+fn sign(user: &DbUser, purpose: &str, ttl: i64, ctx: &ApiContext) -> String {
+    let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
+        .expect("HMAC-SHA-384 can accept any key length");
+    EmailClaims {
+        email_user_id: user.id,
+        purpose: purpose.to_string(),
+        email: user.email.clone(),
+        gen: user.token_generation,
+        exp: OffsetDateTime::now_utc().unix_timestamp() + ttl,
+    }
+    .sign_with_key(&hmac)
+    .expect("HMAC signing should be infallible")
+}
+
+// the user of a valid token for `purpose`, whose email is still the one it was sent to
+async fn verify(token: &str, purpose: &str, ctx: &ApiContext) -> Result<DbUser> {
+    let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
+        .expect("HMAC-SHA-384 can accept any key length");
+    let claims: EmailClaims = token.verify_with_key(&hmac).map_err(|_| CustomError::Unauthorized)?;
+    if claims.purpose != purpose || claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
+        return Err(CustomError::Unauthorized);
+    }
+
+    let user = DbUser::find_by_id(claims.email_user_id, &ctx.db).await?;
+    if user.email != claims.email {
+        return Err(CustomError::Unauthorized);
+    }
+    if purpose == RESET_PASSWORD && user.token_generation != claims.gen {
+        return Err(CustomError::Unauthorized);
+    }
+    Ok(user)
+}
+
+/// Mail a verification link to the user. A mail which can't be sent is logged,
+/// it can be sent again at `/api/users/verification`.
+pub(crate) async fn send_verification(user: &DbUser, ctx: &ApiContext) {
+    let token = sign(user, VERIFY_EMAIL, VERIFY_EMAIL_TTL, ctx);
+    let mail = Mail {
+        to: user.email.clone(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Hi {},\n\nopen this link to verify your email, it works for 24 hours:\n{}/verify_email?token={}\n",
+            user.name, ctx.config.public_url.trim_end_matches('/'), token,
+        ),
+    };
+    if let Err(e) = ctx.mailer.send(&mail).await {
+        log::warn!("verification mail to user {} not sent: {:?}", user.id, e);
+    }
+}
+
+async fn resend_verification(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+) -> Result<()> {
+    let user = DbUser::find_by_id(auth_user.user_id, &ctx.db).await?;
+    if user.email_verified {
+        return Err(CustomError::Conflict);
+    }
+    send_verification(&user, &ctx).await;
+    Ok(())
+}
+
+async fn confirm_verification(
+    ctx: Extension<ApiContext>,
+    Json(req): Json<EmailTokenReq>,
+) -> Result<()> {
+    let user = verify(&req.token, VERIFY_EMAIL, &ctx).await?;
+    if !DbUser::verify_email(user.id, &user.email, &ctx.db).await? {
+        return Err(CustomError::Unauthorized);
+    }
+    Ok(())
+}
+
+// Mail a reset link if the email belongs to a user. The answer is the same either
+// way and doesn't wait for the mail, so it doesn't tell which emails have an account.
+// An email gets one mail per password_reset_interval, the requests in between are ignored.
+async fn request_password_reset(
+    ctx: Extension<ApiContext>,
+    Json(req): Json<PasswordResetReq>,
+) -> Result<()> {
+    let mut conn = ctx.redis_client.get_async_connection().await?;
+    let throttle_key = format!("password_reset:{}", req.email.trim().to_lowercase());
+    let first: bool = redis::cmd("SET")
+        .arg(&throttle_key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ctx.config.password_reset_interval.max(1))
+        .query_async::<_, Option<String>>(&mut conn)
+        .await?
+        .is_some();
+    if !first {
+        return Ok(());
+    }
+
+    let user = match DbUser::find_by_email(&req.email, &ctx.db).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let token = sign(&user, RESET_PASSWORD, RESET_PASSWORD_TTL, &ctx);
+    let mail = Mail {
+        to: user.email.clone(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\nopen this link to choose a new password, it works for an hour:\n{}/reset_password?token={}\n\n\
+             If you didn't ask for it, ignore this mail.\n",
+            user.name, ctx.config.public_url.trim_end_matches('/'), token,
+        ),
+    };
+    let mailer = ctx.mailer.clone();
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&mail).await {
+            log::warn!("password reset mail to user {} not sent: {:?}", user.id, e);
+        }
+    });
+    Ok(())
+}
+
+// the new password revokes every token of the user, the reset token included
+async fn reset_password(
+    ctx: Extension<ApiContext>,
+    Json(req): Json<NewPasswordReq>,
+) -> Result<()> {
+    let user = verify(&req.token, RESET_PASSWORD, &ctx).await?;
+    if req.password.is_empty() {
+        return Err(CustomError::unprocessable_entity([("password", "must not be empty")]));
+    }
+
+    let password_hash = hash_password(req.password).await?;
+    DbUser::update_by_id(user.id, None, None, Some(password_hash), &ctx.db).await?;
+    // the mail reached its owner
+    DbUser::verify_email(user.id, &user.email, &ctx.db).await?;
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::extract::multipart;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
+use cloud_core::error::Error as CoreError;
 use sqlx::error::DatabaseError;
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -35,6 +36,22 @@ pub enum CustomError {
     #[error("request path not found")]
     NotFound,
 
+    /// Return `409 Conflict`
+    #[error("a file with the same name already exists")]
+    Conflict,
+
+    /// Return `413 Payload Too Large`
+    #[error("request body is too large")]
+    PayloadTooLarge,
+
+    /// Return `507 Insufficient Storage`
+    #[error("storage quota exceeded")]
+    InsufficientStorage,
+
+    /// Return `429 Too Many Requests` with the seconds to wait in `Retry-After`
+    #[error("too many failed attempts, try again later")]
+    TooManyRequests { retry_after: u64 },
+
     #[error("error in the request body")]
     UnprocessableEntity {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
@@ -91,18 +108,65 @@ impl CustomError {
         Self::UnprocessableEntity { errors: error_map }
     }
 
-    fn status_code(&self) -> StatusCode {
+    /// A one line message, with the details of `UnprocessableEntity`,
+    /// for errors reported inside a response body.
+    pub(crate) fn detail(&self) -> String {
+        match self {
+            Self::UnprocessableEntity { errors } => {
+                let mut details = errors
+                    .iter()
+                    .flat_map(|(key, messages)| messages.iter().map(move |message| format!("{} {}", key, message)))
+                    .collect::<Vec<String>>();
+                details.sort();
+                details.join("; ")
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    pub(crate) fn status_code(&self) -> StatusCode {
         match self {
             Self::BadRequest => StatusCode::BAD_REQUEST,
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             Self::UnprocessableEntity { .. } | Self::MultipartError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Json(_) | Self::Redis(_) | Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
+/// Map errors from `cloud_core` to the matching API error.
+impl From<CoreError> for CustomError {
+    fn from(e: CoreError) -> Self {
+        match e {
+            CoreError::NotFound => Self::NotFound,
+            CoreError::NotADirectory => {
+                Self::unprocessable_entity([("parent_dir_id", "is not a directory")])
+            }
+            CoreError::IntoDescendant => {
+                Self::unprocessable_entity([("parent_dir_id", "is the file itself or one of its descendants")])
+            }
+            CoreError::NameConflict => Self::Conflict,
+            CoreError::InvalidPath => Self::unprocessable_entity([("path", "is invalid")]),
+            CoreError::TooLarge => Self::PayloadTooLarge,
+            CoreError::InvalidArchive => Self::unprocessable_entity([("archive", "is invalid or not supported")]),
+            CoreError::ArchiveLimit(reason) => Self::unprocessable_entity([("archive", reason)]),
+            CoreError::UnsupportedEntry => {
+                Self::unprocessable_entity([("entry", "is not a regular file or directory")])
+            }
+            CoreError::Io(e) => Self::Anyhow(e.into()),
+            CoreError::Sqlx(e) => Self::Sqlx(e),
+            CoreError::Block(e) => Self::Anyhow(e),
+            e @ CoreError::HashCheckError(_) => Self::Anyhow(e.into()),
+        }
+    }
+}
+
 /// Axum allows you to return `Result` from handler functions, but the error type
 /// also must be some sort of response type.
 ///
@@ -131,7 +195,10 @@ impl IntoResponse for CustomError {
                     //
                     // However, at Launchbadge we try to adhere to web standards wherever possible,
                     // if nothing else than to try to act as a vanguard of sanity on the web.
-                    [(WWW_AUTHENTICATE, HeaderValue::from_static("Token"))]
+                    [
+                        (WWW_AUTHENTICATE, HeaderValue::from_static("Token")),
+                        (WWW_AUTHENTICATE, HeaderValue::from_static("Bearer")),
+                    ]
                         .into_iter()
                         .collect::<HeaderMap>(),
                     self.to_string(),
@@ -139,6 +206,11 @@ impl IntoResponse for CustomError {
                     .into_response();
             }
 
+            Self::TooManyRequests { retry_after } => {
+                return (self.status_code(), [(RETRY_AFTER, retry_after.to_string())], self.to_string())
+                    .into_response();
+            }
+
             Self::Sqlx(ref e) => {
                 // TODO: we probably want to use `tracing` instead
                 // so that this gets linked to the HTTP request by `TraceLayer`.
@@ -1,10 +1,11 @@
 use crate::api::error::CustomError;
-use axum::extract::{Extension, FromRequestParts};
+use axum::extract::{ConnectInfo, Extension, FromRequestParts, MatchedPath, Path};
 
 use async_trait::async_trait;
 use crate::api::ApiContext;
+use crate::api::session_cookies::{self, SESSION_COOKIE};
 use axum::http::header::AUTHORIZATION;
-use axum::http::HeaderValue;
+use axum::http::{HeaderMap, HeaderValue, Method};
 use axum::http::request::Parts;
 use hmac::{Hmac, Mac};
 use jwt::{SignWithKey, VerifyWithKey};
@@ -12,24 +13,57 @@ use sha2::Sha384;
 use time::OffsetDateTime;
 use uuid::Uuid;
 use crate::api::Result;
-use std::sync::Arc;
+use cloud_core::db_schema::api_tokens::ApiTokens;
+use cloud_core::db_schema::users::Users as DbUser;
+use crate::api_common::tokens::TokenScope;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use redis::{self, AsyncCommands};
-use crate::api_common::storages::SessionInfo;
 
-const DEFAULT_SESSION_LENGTH: time::Duration = time::Duration::weeks(1);
-
-// Ideally the Realworld spec would use the `Bearer` scheme as that's relatively standard
-// and has parsers available, but it's really not that hard to parse anyway.
-const SCHEME_PREFIX: &str = "Token ";
+// `Token` is the scheme of the Realworld spec, `Bearer` the standard one most clients send.
+const SCHEME_PREFIXES: &[&str] = &["Token ", "Bearer "];
+
+/// Personal access tokens start with this, JWTs never do.
+pub const API_TOKEN_PREFIX: &str = "pat_";
+
+// routes an upload scope may use, with any method when it's None
+const UPLOAD_ROUTES: &[(Option<Method>, &str)] = &[
+    (Some(Method::POST), "/api/:ws_id/storages"),
+    (Some(Method::POST), "/api/:ws_id/storages/batch"),
+    (Some(Method::POST), "/api/:ws_id/storages/:id/delta"),
+    (Some(Method::POST), "/api/:ws_id/paths/*path"),
+    (Some(Method::POST), "/api/:ws_id/archives"),
+    (None, "/api/upload_sessions"),
+    (None, "/api/upload_sessions/chunks"),
+    (None, "/api/upload_sessions/:session_id"),
+    (None, "/api/upload_sessions/:session_id/instant"),
+    (None, "/api/tus"),
+    (None, "/api/tus/:id"),
+];
 
 /// Add this as a parameter to a handler function to require the user to be logged in.
 ///
-/// Parses a JWT from the `Authorization: Token <token>` header.
+/// Parses a JWT from the `Authorization: Token <token>` or `Authorization: Bearer <token>` header.
+/// Without the header, a cookie session is taken, see `SessionCookies`. A request
+/// of a cookie session which may change something needs the CSRF token.
+///
+/// The token is refused once the token generation of the user moved past the one
+/// it was issued in, which happens on a password change or a logout everywhere.
 ///
+/// A personal access token is taken as well, limited to the routes of its scopes
+/// and to its workspace. It never reaches the account, token and admin routes.
 pub struct AuthUser {
     pub user_id: Uuid,
+    pub generation: i32,
+    /// set when the user is authenticated by a personal access token
+    pub grant: Option<TokenGrant>,
+}
+
+pub struct TokenGrant {
+    pub scopes: Vec<TokenScope>,
+    pub ws_id: Option<Uuid>,
 }
 
 /// Add this as a parameter to a handler function to optionally check if the user is logged in.
@@ -45,35 +79,107 @@ pub struct MaybeAuthUser(pub Option<AuthUser>);
 struct AuthUserClaims {
     user_id: Uuid,
     exp: i64,
+    gen: i32,
 }
 
-type HmacSha384 = Hmac<Sha384>;
+pub(crate) type HmacSha384 = Hmac<Sha384>;
 
 impl AuthUser {
-    pub fn to_jwt(&self, ctx: &ApiContext) -> String {
-        let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
-            .expect("HMAC-SHA-384 can accept any key length");
-
-        AuthUserClaims {
+    pub fn to_jwt(&self, ctx: &ApiContext) -> Result<String> {
+        let claims = AuthUserClaims {
             user_id: self.user_id,
-            exp: (OffsetDateTime::now_utc() + DEFAULT_SESSION_LENGTH).unix_timestamp(),
+            exp: OffsetDateTime::now_utc().unix_timestamp() + ctx.config.access_token_ttl as i64,
+            gen: self.generation,
+        };
+        if let Some(keyset) = &ctx.keyset {
+            return Ok(keyset.sign(&claims)?);
         }
-        .sign_with_key(&hmac)
-        .expect("HMAC signing should be infallible")
+
+        let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
+            .expect("HMAC-SHA-384 can accept any key length");
+        Ok(claims
+            .sign_with_key(&hmac)
+            .expect("HMAC signing should be infallible"))
     }
 
     /// Attempt to parse `Self` from an `Authorization` header.
-    fn from_authorization(ctx: &ApiContext, auth_header: &HeaderValue) -> Result<Self> {
+    async fn from_authorization(ctx: &ApiContext, auth_header: &HeaderValue) -> Result<Self> {
         let auth_header = auth_header.to_str().map_err(|_| {
             CustomError::Unauthorized
         })?;
 
-        if !auth_header.starts_with(SCHEME_PREFIX) {
+        let token = SCHEME_PREFIXES
+            .iter()
+            .find_map(|prefix| auth_header.strip_prefix(prefix))
+            .ok_or(CustomError::Unauthorized)?;
+        if token.starts_with(API_TOKEN_PREFIX) {
+            return Self::from_api_token(ctx, token).await;
+        }
+        Self::from_jwt(ctx, token).await
+    }
+
+    // the header, or the cookie of a cookie session
+    async fn from_parts(ctx: &ApiContext, req: &Parts) -> Result<Self> {
+        if let Some(auth_header) = req.headers.get(AUTHORIZATION) {
+            return Self::from_authorization(ctx, auth_header).await;
+        }
+        if !ctx.config.cookie_sessions {
+            return Err(CustomError::Unauthorized);
+        }
+        // only a jwt is kept in a cookie, never a personal access token
+        let token = session_cookies::get_cookie(&req.headers, SESSION_COOKIE).ok_or(CustomError::Unauthorized)?;
+        let auth_user = Self::from_jwt(ctx, token).await?;
+
+        if !matches!(req.method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            session_cookies::check_csrf(&req.headers)?;
+        }
+        Ok(auth_user)
+    }
+
+    /// The token the request was authenticated with, like `from_parts` finds it. It's
+    /// echoed back where a user is returned, only a refresh mints a new access token.
+    pub(crate) fn presented_token(ctx: &ApiContext, headers: &HeaderMap) -> String {
+        let token = match headers.get(AUTHORIZATION) {
+            Some(auth_header) => auth_header
+                .to_str()
+                .ok()
+                .and_then(|auth_header| SCHEME_PREFIXES.iter().find_map(|prefix| auth_header.strip_prefix(prefix))),
+            None if ctx.config.cookie_sessions => session_cookies::get_cookie(headers, SESSION_COOKIE),
+            None => None,
+        };
+        token.unwrap_or_default().to_string()
+    }
+
+    // any credentials at all, valid or not
+    fn has_credentials(ctx: &ApiContext, req: &Parts) -> bool {
+        req.headers.contains_key(AUTHORIZATION)
+            || (ctx.config.cookie_sessions && session_cookies::get_cookie(&req.headers, SESSION_COOKIE).is_some())
+    }
+
+    async fn from_jwt(ctx: &ApiContext, token: &str) -> Result<Self> {
+        let claims = match &ctx.keyset {
+            Some(keyset) => keyset.verify::<AuthUserClaims>(token).ok_or(CustomError::Unauthorized)?,
+            None => Self::verify_hmac(ctx, token)?,
+        };
+        if claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
             return Err(CustomError::Unauthorized);
         }
 
-        let token = &auth_header[SCHEME_PREFIX.len()..];
+        // the tokens of a deleted user or of an older generation are revoked
+        let generation = DbUser::token_generation(claims.user_id, &ctx.db).await?;
+        if generation != Some(claims.gen) {
+            return Err(CustomError::Unauthorized);
+        }
 
+        Ok(Self {
+            user_id: claims.user_id,
+            generation: claims.gen,
+            grant: None,
+        })
+    }
+
+    // the claims of a token signed by the hmac key
+    fn verify_hmac(ctx: &ApiContext, token: &str) -> Result<AuthUserClaims> {
         let jwt =
             jwt::Token::<jwt::Header, AuthUserClaims, _>::parse_unverified(token).map_err(|_e| {
                 CustomError::Unauthorized
@@ -93,14 +199,82 @@ impl AuthUser {
         })?;
 
         let (_header, claims) = jwt.into();
-        if claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
-            return Err(CustomError::Unauthorized);
-        }
+        Ok(claims)
+    }
+
+    async fn from_api_token(ctx: &ApiContext, token: &str) -> Result<Self> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let api_token = ApiTokens::find_valid(&api_token_hash(token), now, &ctx.db)
+            .await?
+            .ok_or(CustomError::Unauthorized)?;
 
         Ok(Self {
-            user_id: claims.user_id,
+            user_id: api_token.user_id,
+            // an api token isn't bound to a generation, and can't be exchanged for a jwt
+            generation: 0,
+            grant: Some(TokenGrant {
+                scopes: api_token.scopes.iter().filter_map(|scope| TokenScope::parse(scope)).collect(),
+                ws_id: api_token.ws_id,
+            }),
         })
     }
+
+    /// Check the workspace of a request, for the handlers which don't take it from the path.
+    pub fn check_ws(&self, ws_id: Uuid) -> Result<()> {
+        match &self.grant {
+            Some(TokenGrant { ws_id: Some(granted), .. }) if *granted != ws_id => Err(CustomError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    // check a personal access token may be used on the route of the request
+    async fn check_grant<S: Send + Sync>(&self, req: &mut Parts, state: &S) -> Result<()> {
+        let grant = match &self.grant {
+            Some(grant) => grant,
+            None => return Ok(()),
+        };
+        let route = req.extensions
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_default();
+
+        if route.starts_with("/api/users") || route.starts_with("/api/tokens") || route.starts_with("/api/admin") {
+            return Err(CustomError::Forbidden);
+        }
+        if !grant.scopes.iter().any(|scope| scope_allows(*scope, &req.method, &route)) {
+            return Err(CustomError::Forbidden);
+        }
+
+        if grant.ws_id.is_some() {
+            // the workspaces of the user aren't for a token limited to one of them
+            if route.starts_with("/api/workspaces") {
+                return Err(CustomError::Forbidden);
+            }
+            if route.contains(":ws_id") {
+                let Path(params) = Path::<HashMap<String, String>>::from_request_parts(req, state)
+                    .await
+                    .map_err(|_| CustomError::Forbidden)?;
+                let ws_id = params.get("ws_id").and_then(|ws_id| Uuid::parse_str(ws_id).ok());
+                self.check_ws(ws_id.ok_or(CustomError::Forbidden)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn scope_allows(scope: TokenScope, method: &Method, route: &str) -> bool {
+    match scope {
+        TokenScope::Read => matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS),
+        TokenScope::Upload => UPLOAD_ROUTES.iter().any(|(upload_method, upload_route)| {
+            *upload_route == route && upload_method.as_ref().is_none_or(|upload_method| upload_method == method)
+        }),
+        TokenScope::Write => true,
+    }
+}
+
+/// Only the hash of a personal access token is stored.
+pub(crate) fn api_token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 impl MaybeAuthUser {
@@ -122,12 +296,9 @@ where
             .await
             .expect("BUG: ApiContext was not added as an extension");
 
-        // Get the value of the `Authorization` header, if it was sent at all.
-        let auth_header = req.headers
-            .get(AUTHORIZATION)
-            .ok_or(CustomError::Unauthorized)?;
-
-        Self::from_authorization(&ctx, auth_header)
+        let auth_user = Self::from_parts(&ctx, req).await?;
+        auth_user.check_grant(req, _state).await?;
+        Ok(auth_user)
     }
 }
 
@@ -142,17 +313,76 @@ where
         let ctx: Extension<ApiContext> = Extension::from_request_parts(req, s)
             .await
             .expect("BUG: ApiContext was not added as an extension");
-        let auth_header = req.headers
-            .get(AUTHORIZATION)
-            .ok_or(CustomError::Unauthorized)?;
-
-        //Ok(Some(AuthUser::from_authorization(&ctx, auth_header)))
+        if !AuthUser::has_credentials(&ctx, req) {
+            return Err(CustomError::Unauthorized);
+        }
 
-        let user: Option<AuthUser> = AuthUser::from_authorization(&ctx, auth_header).map(Some).unwrap_or(None);
+        let user = match AuthUser::from_parts(&ctx, req).await {
+            Ok(user) => user.check_grant(req, s).await.ok().map(|_| user),
+            Err(_) => None,
+        };
         Ok(Self(user))
     }
 }
 
+/// Add this as a parameter to a handler function to require an admin.
+///
+/// The role is looked up on every request, a demoted or disabled admin loses
+/// access right away.
+pub struct AdminUser(pub AuthUser);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: Send + Sync,
+{
+    type Rejection = CustomError;
+
+    async fn from_request_parts(req: &mut Parts, s: &S) -> Result<Self, Self::Rejection> {
+        let ctx: Extension<ApiContext> = Extension::from_request_parts(req, s)
+            .await
+            .expect("BUG: ApiContext was not added as an extension");
+        let auth_user = AuthUser::from_request_parts(req, s).await?;
+
+        if !DbUser::is_admin(auth_user.user_id, &ctx.db).await? {
+            return Err(CustomError::Forbidden);
+        }
+        Ok(Self(auth_user))
+    }
+}
+
+/// The address of the client, `None` when the server doesn't know it.
+///
+/// With `trust_forwarded_for` it's the first address of `X-Forwarded-For`.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = CustomError;
+
+    async fn from_request_parts(req: &mut Parts, s: &S) -> Result<Self, Self::Rejection> {
+        let ctx: Extension<ApiContext> = Extension::from_request_parts(req, s)
+            .await
+            .expect("BUG: ApiContext was not added as an extension");
+
+        if ctx.config.trust_forwarded_for {
+            let forwarded = req.headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+            if forwarded.is_some() {
+                return Ok(Self(forwarded));
+            }
+        }
+        let connect_info = req.extensions.get::<ConnectInfo<SocketAddr>>();
+        Ok(Self(connect_info.map(|ConnectInfo(addr)| addr.ip())))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AuthUploadInfo {
     pub session_id: Uuid,
@@ -187,26 +417,19 @@ where
             .await
             .expect("BUG: ApiContext was not added as an extension");
 
-        let auth_header = req.headers
-            .get(AUTHORIZATION)
-            .ok_or(CustomError::Unauthorized)?;
-
-        let auth_user = AuthUser::from_authorization(&ctx, auth_header)?;
+        let auth_user = AuthUser::from_parts(&ctx, req).await?;
+        auth_user.check_grant(req, _state).await?;
 
         let header_value = req.headers.get("x-cloud-session").ok_or(CustomError::Unauthorized)?;
-        let redis_client = Arc::clone(&ctx.redis_client);
-        let mut redis_conn = redis_client.get_async_connection().await?;
-
         let upload_info = AuthUploadInfo::from_header(header_value).await?;
 
-        let session_info = redis_conn
-            .get::<String, String>(upload_info.session_id.to_string())
-            .await?;
+        // the session may have been finished, aborted or reaped
+        let session_info = ctx.upload_sessions
+            .get(upload_info.session_id)
+            .await?
+            .ok_or(CustomError::Unauthorized)?
+            .info;
 
-        let session_info: SessionInfo = serde_json::from_str(&session_info).map_err(|_| {
-            CustomError::Unauthorized
-        })?;
-        
         if session_info.user_id != auth_user.user_id {
             return Err(CustomError::Unauthorized);
         }
@@ -0,0 +1,21 @@
+use axum::extract::Extension;
+use axum::http::header::CACHE_CONTROL;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use crate::api::error::CustomError;
+use crate::api::{ApiContext, Result};
+use crate::api_common::jwks::JwkSet;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/.well-known/jwks.json", get(get_jwks))
+}
+
+// other services verify the access tokens with these keys, there are none to publish
+// when the tokens are signed by the hmac key
+async fn get_jwks(ctx: Extension<ApiContext>) -> Result<impl IntoResponse> {
+    let keyset = ctx.keyset.as_ref().ok_or(CustomError::NotFound)?;
+    let cache_control = format!("public, max-age={}", ctx.config.jwt_keyset_reload);
+    Ok(([(CACHE_CONTROL, cache_control)], Json(JwkSet { keys: keyset.jwks() })))
+}
@@ -0,0 +1,352 @@
+use crate::api::ApiContext;
+use crate::api_common::jwks::Jwk;
+use crate::config::{Config, JwtAlgorithm};
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// a new key is published this long before it signs, for the verifiers caching the jwks
+const PUBLISH_AHEAD: i64 = 60 * 60;
+// an expired key is dropped from the file this long after
+const PRUNE_AFTER: i64 = 24 * 60 * 60;
+
+// a key as it's kept in the keyset file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKey {
+    kid: String,
+    // EdDSA or ES256
+    alg: String,
+    // pkcs8 der in base64url, missing for the keys of other servers this one only verifies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private_key: Option<String>,
+    // the raw ed25519 key, or the uncompressed p-256 point, in base64url
+    public_key: String,
+    created_at: i64,
+    // unix timestamp the key starts signing
+    active_from: i64,
+    // unix timestamp the key isn't trusted anymore, none until a newer key replaces it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeySetFile {
+    keys: Vec<StoredKey>,
+}
+
+struct Key {
+    stored: StoredKey,
+    alg: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+impl Key {
+    fn load(stored: StoredKey) -> anyhow::Result<Self> {
+        let alg = parse_alg(&stored.alg).ok_or_else(|| anyhow!("key {} has unsupported alg {}", stored.kid, stored.alg))?;
+        let public_key = URL_SAFE_NO_PAD.decode(&stored.public_key)
+            .with_context(|| format!("invalid public_key of key {}", stored.kid))?;
+        let decoding = match alg {
+            Algorithm::ES256 => {
+                if public_key.len() != 65 || public_key[0] != 0x04 {
+                    return Err(anyhow!("public_key of key {} is not an uncompressed p-256 point", stored.kid));
+                }
+                DecodingKey::from_ec_components(&URL_SAFE_NO_PAD.encode(&public_key[1..33]),
+                                                &URL_SAFE_NO_PAD.encode(&public_key[33..]))?
+            }
+            _ => DecodingKey::from_ed_components(&stored.public_key)?,
+        };
+
+        let encoding = match &stored.private_key {
+            Some(private_key) => {
+                let pkcs8 = URL_SAFE_NO_PAD.decode(private_key)
+                    .with_context(|| format!("invalid private_key of key {}", stored.kid))?;
+                // a broken key fails here rather than on the first login
+                if key_pair_public_key(alg, &pkcs8)? != public_key {
+                    return Err(anyhow!("private_key of key {} doesn't match its public_key", stored.kid));
+                }
+                Some(match alg {
+                    Algorithm::ES256 => EncodingKey::from_ec_der(&pkcs8),
+                    _ => EncodingKey::from_ed_der(&pkcs8),
+                })
+            }
+            None => None,
+        };
+        Ok(Self { stored, alg, encoding, decoding })
+    }
+
+    fn is_trusted(&self, now: i64) -> bool {
+        self.stored.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    fn can_sign(&self, now: i64) -> bool {
+        self.encoding.is_some() && self.stored.active_from <= now && self.is_trusted(now)
+    }
+}
+
+fn parse_alg(alg: &str) -> Option<Algorithm> {
+    match alg {
+        "EdDSA" => Some(Algorithm::EdDSA),
+        "ES256" => Some(Algorithm::ES256),
+        _ => None,
+    }
+}
+
+fn key_pair_public_key(alg: Algorithm, pkcs8: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let public_key = match alg {
+        Algorithm::ES256 => EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+            .map_err(|e| anyhow!("invalid p-256 key: {}", e))?
+            .public_key()
+            .as_ref()
+            .to_vec(),
+        _ => Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|e| anyhow!("invalid ed25519 key: {}", e))?
+            .public_key()
+            .as_ref()
+            .to_vec(),
+    };
+    Ok(public_key)
+}
+
+fn generate_key(alg: Algorithm, now: i64, active_from: i64) -> StoredKey {
+    let rng = SystemRandom::new();
+    let pkcs8 = match alg {
+        Algorithm::ES256 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng),
+        _ => Ed25519KeyPair::generate_pkcs8(&rng),
+    }
+    .expect("failed to generate a signing key");
+    let public_key = key_pair_public_key(alg, pkcs8.as_ref()).expect("a generated key is valid");
+
+    StoredKey {
+        kid: Uuid::now_v7().to_string(),
+        alg: if alg == Algorithm::ES256 { "ES256" } else { "EdDSA" }.to_string(),
+        private_key: Some(URL_SAFE_NO_PAD.encode(pkcs8.as_ref())),
+        public_key: URL_SAFE_NO_PAD.encode(public_key),
+        created_at: now,
+        active_from,
+        expires_at: None,
+    }
+}
+
+/// The keys signing and verifying the access tokens, kept in the `jwt_keyset` file.
+///
+/// The newest active key with a private key signs, its `kid` goes in the header.
+/// Every key which didn't expire verifies, so the keys of other servers can be
+/// added without their private keys. A rotation publishes the next key ahead of
+/// time and expires the replaced ones once their tokens ran out.
+pub(crate) struct KeySet {
+    path: PathBuf,
+    // of the keys this server generates
+    alg: Algorithm,
+    // seconds a key signs, 0 never rotates
+    rotation: i64,
+    // seconds a replaced key still verifies
+    token_ttl: i64,
+    keys: RwLock<Vec<Key>>,
+}
+
+impl KeySet {
+    /// The keyset of `jwt_keyset`, a missing file is created with a new key.
+    pub(crate) fn open(config: &Config) -> anyhow::Result<Option<Arc<KeySet>>> {
+        let path = match &config.jwt_keyset {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let alg = match config.jwt_algorithm {
+            JwtAlgorithm::EdDsa => Algorithm::EdDSA,
+            JwtAlgorithm::Es256 => Algorithm::ES256,
+        };
+        let keyset = KeySet {
+            path: PathBuf::from(path),
+            alg,
+            rotation: config.jwt_rotation as i64,
+            token_ttl: config.access_token_ttl as i64,
+            keys: RwLock::new(Vec::new()),
+        };
+        keyset.reload().with_context(|| format!("invalid jwt_keyset {}", path))?;
+        keyset.rotate(OffsetDateTime::now_utc().unix_timestamp())
+            .with_context(|| format!("failed to write jwt_keyset {}", path))?;
+        Ok(Some(Arc::new(keyset)))
+    }
+
+    // Every server sharing the file changes it under an exclusive lock of a file next
+    // to it, the keyset file itself is replaced by each write. The lock is released
+    // when the returned file is closed.
+    fn lock_file(&self) -> anyhow::Result<fs::File> {
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(self.path.with_extension("lock"))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(lock)
+    }
+
+    fn read_file(&self) -> anyhow::Result<Vec<Key>> {
+        let file: KeySetFile = match fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => KeySetFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        file.keys.into_iter().map(Key::load).collect()
+    }
+
+    // written to a temporary file first, a reader never sees half of it
+    fn write_file(&self, keys: &[Key]) -> anyhow::Result<()> {
+        let file = KeySetFile { keys: keys.iter().map(|key| key.stored.clone()).collect() };
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(&file)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Read the file again, for the keys other servers added. The keys stay as they
+    /// were when it can't be read.
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let keys = self.read_file()?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Add a key when there's none to sign with, or when the one signing is due to be
+    /// replaced, and drop the keys which expired a while ago. True if the file changed.
+    ///
+    /// The file is read again under the lock, a key another server added meanwhile
+    /// is kept.
+    pub(crate) fn rotate(&self, now: i64) -> anyhow::Result<bool> {
+        let _lock = self.lock_file()?;
+        let mut keys = self.keys.write().unwrap();
+        *keys = self.read_file()?;
+        let mut changed = false;
+
+        let signing = keys.iter().filter(|key| key.can_sign(now)).map(|key| key.stored.active_from).max();
+        let pending = keys.iter().any(|key| key.encoding.is_some() && key.stored.active_from > now && key.is_trusted(now));
+        let next_active_from = match signing {
+            None if !pending => Some(now),
+            Some(active_from) if !pending && self.rotation > 0 && now >= active_from + self.rotation - PUBLISH_AHEAD => {
+                Some((active_from + self.rotation).max(now))
+            }
+            _ => None,
+        };
+        if let Some(active_from) = next_active_from {
+            // the tokens of a replaced key live on until they expire
+            for key in keys.iter_mut().filter(|key| key.encoding.is_some() && key.stored.expires_at.is_none()) {
+                key.stored.expires_at = Some(active_from + self.token_ttl);
+            }
+            let key = generate_key(self.alg, now, active_from);
+            log::info!("added jwt signing key {}, it signs from {}", key.kid, active_from);
+            keys.push(Key::load(key)?);
+            changed = true;
+        }
+
+        let count = keys.len();
+        keys.retain(|key| key.stored.expires_at.is_none_or(|expires_at| expires_at + PRUNE_AFTER > now));
+        changed |= keys.len() != count;
+
+        if changed {
+            self.write_file(&keys)?;
+        }
+        Ok(changed)
+    }
+
+    pub(crate) fn sign<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let keys = self.keys.read().unwrap();
+        // the file may have been rewritten without one, until the next rotation adds it
+        let key = keys
+            .iter()
+            .filter(|key| key.can_sign(now))
+            .max_by_key(|key| key.stored.active_from)
+            .ok_or_else(|| anyhow!("jwt_keyset has no key to sign with"))?;
+
+        let mut header = Header::new(key.alg);
+        header.kid = Some(key.stored.kid.clone());
+        jsonwebtoken::encode(&header, claims, key.encoding.as_ref().unwrap())
+            .with_context(|| format!("failed to sign with jwt key {}", key.stored.kid))
+    }
+
+    /// The claims of a token signed by a trusted key, none for any other token.
+    pub(crate) fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let kid = jsonwebtoken::decode_header(token).ok()?.kid?;
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter().find(|key| key.stored.kid == kid && key.is_trusted(now))?;
+
+        // only the alg of the key, never the one the token claims
+        let mut validation = Validation::new(key.alg);
+        validation.leeway = 0;
+        jsonwebtoken::decode::<T>(token, &key.decoding, &validation).ok().map(|data| data.claims)
+    }
+
+    /// The public keys of the trusted keys, the ones not signing yet included.
+    pub(crate) fn jwks(&self) -> Vec<Jwk> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .filter(|key| key.is_trusted(now))
+            .map(|key| {
+                let (kty, crv, x, y) = match key.alg {
+                    Algorithm::ES256 => {
+                        let point = URL_SAFE_NO_PAD.decode(&key.stored.public_key).expect("checked when loaded");
+                        ("EC", "P-256", URL_SAFE_NO_PAD.encode(&point[1..33]), Some(URL_SAFE_NO_PAD.encode(&point[33..])))
+                    }
+                    _ => ("OKP", "Ed25519", key.stored.public_key.clone(), None),
+                };
+                Jwk {
+                    kty: kty.to_string(),
+                    crv: crv.to_string(),
+                    alg: key.stored.alg.clone(),
+                    kid: key.stored.kid.clone(),
+                    key_use: "sig".to_string(),
+                    x,
+                    y,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reload the keyset file every `jwt_keyset_reload` seconds, and rotate the keys
+/// when `jwt_rotation` is set.
+pub(crate) fn spawn_rotation(ctx: ApiContext) {
+    let keyset = match &ctx.keyset {
+        Some(keyset) => keyset.clone(),
+        None => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(ctx.config.jwt_keyset_reload.max(1)));
+        loop {
+            interval.tick().await;
+            // the file is read and locked without holding up a worker thread,
+            // a rotation reloads it, with the keys other servers added
+            let keyset = keyset.clone();
+            let rotated = tokio::task::spawn_blocking(move || {
+                keyset.rotate(OffsetDateTime::now_utc().unix_timestamp()).context("failed to rotate the jwt keys")
+            }).await;
+            match rotated {
+                Ok(Ok(_)) => {},
+                Ok(Err(e)) => log::error!("{:?}", e),
+                Err(e) => log::error!("jwt keyset rotation panicked: {:?}", e),
+            }
+        }
+    });
+}
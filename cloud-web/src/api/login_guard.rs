@@ -0,0 +1,119 @@
+use crate::api::error::CustomError;
+use crate::api::{ApiContext, Result};
+use cloud_core::db_schema::audit_logs::AuditLogs;
+use redis::AsyncCommands;
+use std::net::IpAddr;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// seconds the failed attempts are counted for, since the last one
+const FAILURE_WINDOW: usize = 15 * 60;
+// seconds a lockout counts towards the length of the next one
+const LOCKOUT_MEMORY: usize = 24 * 60 * 60;
+const LOCKED_OUT_EVENT: &str = "login_locked_out";
+
+/// The account and the address a login attempt counts against.
+///
+/// Both have their own failure counter in redis. Too many failures lock the account
+/// or the address out, each lockout within a day twice as long as the one before.
+pub(crate) struct LoginAttempt {
+    account: String,
+    ip: Option<IpAddr>,
+}
+
+// what failures are counted for, the account or the address
+struct Subject {
+    scope: &'static str,
+    id: String,
+    max_failures: u64,
+}
+
+impl Subject {
+    fn key(&self, kind: &str) -> String {
+        format!("login_{}:{}:{}", kind, self.scope, self.id)
+    }
+}
+
+impl LoginAttempt {
+    pub(crate) fn new(email: &str, ip: Option<IpAddr>) -> Self {
+        Self { account: email.trim().to_lowercase(), ip }
+    }
+
+    fn subjects(&self, ctx: &ApiContext) -> Vec<Subject> {
+        let mut subjects = vec![Subject {
+            scope: "account",
+            id: self.account.clone(),
+            max_failures: ctx.config.login_max_failures,
+        }];
+        if let Some(ip) = self.ip {
+            subjects.push(Subject {
+                scope: "ip",
+                id: ip.to_string(),
+                max_failures: ctx.config.login_max_ip_failures,
+            });
+        }
+        subjects
+    }
+
+    /// Fail with `TooManyRequests` while the account or the address is locked out.
+    pub(crate) async fn check(&self, ctx: &ApiContext) -> Result<()> {
+        let mut conn = ctx.redis_client.get_async_connection().await?;
+        for subject in self.subjects(ctx) {
+            let ttl: i64 = conn.ttl(subject.key("lock")).await?;
+            if ttl >= 0 {
+                return Err(CustomError::TooManyRequests { retry_after: ttl.max(1) as u64 });
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a failed attempt, the account or the address with too many is locked out.
+    pub(crate) async fn fail(&self, user_id: Option<Uuid>, ctx: &ApiContext) -> Result<()> {
+        let mut conn = ctx.redis_client.get_async_connection().await?;
+        for subject in self.subjects(ctx) {
+            let failures_key = subject.key("failures");
+            let (failures,): (u64,) = redis::pipe()
+                .atomic()
+                .incr(&failures_key, 1)
+                .expire(&failures_key, FAILURE_WINDOW).ignore()
+                .query_async(&mut conn)
+                .await?;
+            if failures < subject.max_failures {
+                continue;
+            }
+
+            let lockouts_key = subject.key("lockouts");
+            let (lockouts,): (u32,) = redis::pipe()
+                .atomic()
+                .incr(&lockouts_key, 1)
+                .expire(&lockouts_key, LOCKOUT_MEMORY).ignore()
+                .del(&failures_key).ignore()
+                .query_async(&mut conn)
+                .await?;
+            let lockout = ctx.config.login_lockout
+                .saturating_mul(1u64 << (lockouts - 1).min(32))
+                .min(ctx.config.login_max_lockout);
+            conn.set_ex::<_, _, ()>(subject.key("lock"), 1, lockout as usize).await?;
+
+            log::warn!("login of {} {} locked out for {} seconds", subject.scope, subject.id, lockout);
+            AuditLogs::new(
+                if subject.scope == "account" { user_id } else { None },
+                LOCKED_OUT_EVENT,
+                self.ip.map(|ip| ip.to_string()),
+                format!("{} {} locked out for {} seconds after {} failed logins", subject.scope, subject.id, lockout, failures),
+                OffsetDateTime::now_utc().unix_timestamp(),
+            )
+            .insert(&ctx.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// A login forgives the failures of the account, not the ones of the address.
+    pub(crate) async fn succeed(&self, ctx: &ApiContext) -> Result<()> {
+        let mut conn = ctx.redis_client.get_async_connection().await?;
+        let subject = &self.subjects(ctx)[0];
+        conn.del::<_, ()>(subject.key("failures")).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+pub mod file_mailer;
+pub mod smtp_mailer;
+
+use crate::api::Result;
+use crate::config::{Config, MailerKind};
+use anyhow::Context;
+use async_trait::async_trait;
+use file_mailer::FileMailer;
+use smtp_mailer::SmtpMailer;
+use std::sync::Arc;
+
+/// A plain text mail to one recipient.
+#[derive(Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the mails of the accounts, like the email verifications and password resets.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<()>;
+}
+
+pub(crate) fn new_mailer(config: &Config) -> anyhow::Result<Arc<dyn Mailer>> {
+    Ok(match config.mailer {
+        MailerKind::Smtp => {
+            let smtp_url = config.smtp_url.as_deref().context("smtp_url is required by the smtp mailer")?;
+            Arc::new(SmtpMailer::new(smtp_url, &config.mail_from).context("invalid smtp_url or mail_from")?)
+        }
+        MailerKind::File => Arc::new(FileMailer::new(Some(config.mail_dir.clone().into()))),
+        MailerKind::Log => Arc::new(FileMailer::new(None)),
+    })
+}
@@ -0,0 +1,37 @@
+use super::{Mail, Mailer};
+use crate::api::Result;
+use async_trait::async_trait;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Writes each mail to a file of `dir`, only readable by the server, or to the
+/// log without it, for local testing without a mail server.
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+        match &self.dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await.map_err(anyhow::Error::from)?;
+                tokio::fs::set_permissions(dir, Permissions::from_mode(0o700)).await.map_err(anyhow::Error::from)?;
+                // named in the order they are sent
+                let path = dir.join(format!("{}.eml", Uuid::now_v7()));
+                tokio::fs::write(path, content).await.map_err(anyhow::Error::from)?;
+            }
+            None => log::info!("mail sent\n{}", content),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use axum::extract::{Extension, Path, BodyStream, Query};
+use axum::routing::get;
+use axum::{Json, Router};
+use crate::api::{extractor::AuthUser, ApiContext, Result, error::CustomError};
+use crate::api::{storages, validation, workspaces};
+use crate::api_common::storages::{StorageBody, Storage, CreatePathReq, MovePathReq};
+use cloud_core::db_schema::files::{Files as DbFile, ROOT_DIR_ID};
+use cloud_core::store_service::cloud_path::CloudPath;
+use axum::http::HeaderMap;
+use uuid::Uuid;
+
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/:ws_id/paths/*path", get(get_by_path).post(create_by_path).put(move_by_path))
+}
+
+// resolve path components to a file or dir, the root dir is returned for empty components
+async fn resolve(user_id: Uuid, ws_id: Uuid, components: &[String], ctx: &ApiContext) -> Result<DbFile> {
+    let ws = workspaces::check_ws_owner(user_id, ws_id, ctx).await?;
+    if components.is_empty() {
+        return Ok(DbFile::root_dir(ws_id, user_id, ws.name));
+    }
+
+    DbFile::get_by_path(ws_id, components, &ctx.db)
+        .await?
+        .ok_or(CustomError::NotFound)
+}
+
+// get the id of the parent dir of path, the missing dirs are created if `parents` is set
+async fn resolve_parent_dir(user_id: Uuid, ws_id: Uuid, path: &CloudPath, parents: bool, ctx: &ApiContext) -> Result<i64> {
+    if parents {
+        workspaces::check_ws_owner(user_id, ws_id, ctx).await?;
+        let dir_id = CloudPath::mkdir_all(user_id, ws_id, ROOT_DIR_ID, path.parent(), &ctx.snowflake, &ctx.db).await?;
+        return Ok(dir_id);
+    }
+
+    let dir = resolve(user_id, ws_id, path.parent(), ctx).await?;
+    if !dir.is_dir {
+        return Err(CustomError::unprocessable_entity([("path", "parent is not a directory")]));
+    }
+    Ok(dir.id)
+}
+
+// check the names a request may create: every dir on the way with `parents`,
+// otherwise only the last one
+fn check_new_names(field: &'static str, path: &CloudPath, parents: bool, ctx: &ApiContext) -> Result<()> {
+    let components = match parents {
+        true => path.components(),
+        false => &path.components()[path.parent().len()..],
+    };
+    validation::check_path(field, components, &ctx.config)
+}
+
+fn to_storage(db_file: DbFile, path: &CloudPath) -> StorageBody<Storage> {
+    StorageBody {
+        storage: Storage::new(
+            db_file.id,
+            db_file.filename.clone(),
+            db_file.is_dir,
+            db_file.parent_dir_id,
+            db_file.disk_usage() as usize
+        ).with_path(format!("/{}", path.components().join("/")))
+    }
+}
+
+async fn get_by_path(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path((ws_id, path)): Path<(Uuid, String)>,
+) -> Result<Json<StorageBody<Storage>>> {
+    let path = CloudPath::parse(&path)?;
+    let db_file = resolve(auth_user.user_id, ws_id, path.components(), &ctx).await?;
+
+    Ok(Json(to_storage(db_file, &path)))
+}
+
+// create a file with the request body, or a dir if `is_dir` is set
+// with `parents`, the missing parent dirs are created and an existing dir is not an error
+async fn create_by_path(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path((ws_id, path)): Path<(Uuid, String)>,
+    Query(create_path_req): Query<CreatePathReq>,
+    headers: HeaderMap,
+    stream: BodyStream
+) -> Result<Json<StorageBody<Storage>>> {
+    let path = CloudPath::parse(&path)?;
+    let filename = path.filename().ok_or(CustomError::BadRequest)?.to_string();
+    check_new_names("path", &path, create_path_req.parents, &ctx)?;
+    storages::check_content_length(&headers, &ctx)?;
+
+    if create_path_req.is_dir && create_path_req.parents {
+        workspaces::check_ws_owner(auth_user.user_id, ws_id, &ctx).await?;
+        let dir_id = CloudPath::mkdir_all(auth_user.user_id, ws_id, ROOT_DIR_ID, path.components(),
+                                          &ctx.snowflake, &ctx.db).await?;
+        let dir = DbFile::check_owner(auth_user.user_id, dir_id, ws_id, &ctx.db)
+            .await?
+            .ok_or(CustomError::NotFound)?;
+        return Ok(Json(to_storage(dir, &path)));
+    }
+
+    let parent_dir_id = resolve_parent_dir(auth_user.user_id, ws_id, &path, create_path_req.parents, &ctx).await?;
+
+    let id = ctx.snowflake.lock().unwrap().next_id();
+    let db_file = DbFile::new(id, auth_user.user_id, ws_id, filename, parent_dir_id, 0, create_path_req.is_dir);
+    let db_file = match create_path_req.is_dir {
+        true => storages::insert_with_policy(&ctx, db_file, None, create_path_req.conflict).await?,
+        false => storages::store_stream(&ctx, stream, db_file, create_path_req.conflict).await?,
+    }.file();
+
+    // the name differs from the path when it's renamed
+    let path = path.with_filename(&db_file.filename);
+    Ok(Json(to_storage(db_file, &path)))
+}
+
+// move or rename a file or dir to `destination`
+async fn move_by_path(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path((ws_id, path)): Path<(Uuid, String)>,
+    move_path_req: Json<MovePathReq>
+) -> Result<Json<StorageBody<Storage>>> {
+    let path = CloudPath::parse(&path)?;
+    if path.is_root() {
+        return Err(CustomError::BadRequest);
+    }
+    let db_file = resolve(auth_user.user_id, ws_id, path.components(), &ctx).await?;
+
+    let destination = CloudPath::parse(&move_path_req.destination)?;
+    let filename = destination.filename().ok_or(CustomError::BadRequest)?;
+    check_new_names("destination", &destination, move_path_req.parents, &ctx)?;
+    let parent_dir_id = resolve_parent_dir(auth_user.user_id, ws_id, &destination, move_path_req.parents, &ctx).await?;
+
+    let db_file = storages::move_with_policy(&ctx, db_file, parent_dir_id, filename, move_path_req.conflict).await?;
+
+    let destination = destination.with_filename(&db_file.filename);
+    Ok(Json(to_storage(db_file, &destination)))
+}
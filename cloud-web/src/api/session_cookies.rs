@@ -0,0 +1,118 @@
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::{IntoResponseParts, ResponseParts};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use crate::api::error::CustomError;
+use crate::api::{ApiContext, Result};
+use crate::api_common::users::User;
+use rand::RngCore;
+use std::convert::Infallible;
+
+/// The access token of a cookie session.
+pub(crate) const SESSION_COOKIE: &str = "mycloud_session";
+/// The refresh token of a cookie session, only sent to the account routes.
+pub(crate) const REFRESH_COOKIE: &str = "mycloud_refresh";
+/// Readable by the web app, which echoes it in `CSRF_HEADER` on every change.
+pub(crate) const CSRF_COOKIE: &str = "mycloud_csrf";
+pub(crate) const CSRF_HEADER: &str = "x-csrf-token";
+
+const REFRESH_PATH: &str = "/api/users";
+
+/// The `Set-Cookie` headers of a response, none unless `cookie_sessions` is on.
+///
+/// A cookie session keeps the access and the refresh token in HttpOnly cookies,
+/// scripts of the page can't read them. Requests of other sites carry the cookies
+/// too, so a change needs the double-submitted CSRF token, which only the page can
+/// read from its cookie.
+pub(crate) struct SessionCookies(Vec<HeaderValue>);
+
+impl SessionCookies {
+    pub(crate) fn none() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Cookies with the tokens of a session which just started.
+    pub(crate) fn issue(user: &User, ctx: &ApiContext) -> Self {
+        let refresh_token = match (&user.refresh_token, ctx.config.cookie_sessions) {
+            (Some(refresh_token), true) => refresh_token,
+            _ => return Self::none(),
+        };
+        let mut csrf_token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut csrf_token);
+
+        let ttl = ctx.config.refresh_token_ttl;
+        Self(vec![
+            cookie(SESSION_COOKIE, &user.token, "/", ttl, true, ctx),
+            cookie(REFRESH_COOKIE, refresh_token, REFRESH_PATH, ttl, true, ctx),
+            cookie(CSRF_COOKIE, &URL_SAFE_NO_PAD.encode(csrf_token), "/", ttl, false, ctx),
+        ])
+    }
+
+    /// Cookies which remove the ones of a session.
+    pub(crate) fn clear(ctx: &ApiContext) -> Self {
+        if !ctx.config.cookie_sessions {
+            return Self::none();
+        }
+        Self(vec![
+            cookie(SESSION_COOKIE, "", "/", 0, true, ctx),
+            cookie(REFRESH_COOKIE, "", REFRESH_PATH, 0, true, ctx),
+            cookie(CSRF_COOKIE, "", "/", 0, false, ctx),
+        ])
+    }
+}
+
+impl IntoResponseParts for SessionCookies {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        for value in self.0 {
+            res.headers_mut().append(SET_COOKIE, value);
+        }
+        Ok(res)
+    }
+}
+
+// the session cookie outlives the access token in it, an expired one is refused
+// like an expired header, and the app refreshes with the cookie of the refresh token
+fn cookie(name: &str, value: &str, path: &str, max_age: u64, http_only: bool, ctx: &ApiContext) -> HeaderValue {
+    let mut cookie = format!("{}={}; Path={}; Max-Age={}; SameSite={}", name, value, path, max_age,
+                             ctx.config.cookie_same_site.as_str());
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if ctx.config.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    HeaderValue::from_str(&cookie).expect("tokens are valid cookie values")
+}
+
+/// The value of a cookie of the request.
+pub(crate) fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// Fail with `Forbidden` unless `CSRF_HEADER` repeats the CSRF cookie.
+pub(crate) fn check_csrf(headers: &HeaderMap) -> Result<()> {
+    let cookie = get_cookie(headers, CSRF_COOKIE).ok_or(CustomError::Forbidden)?;
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(CustomError::Forbidden)?;
+
+    // compared in constant time, not to leak how much of a guess is right
+    let same = cookie.len() == header.len()
+        && cookie.bytes().zip(header.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    match same {
+        true => Ok(()),
+        false => Err(CustomError::Forbidden),
+    }
+}
@@ -0,0 +1,65 @@
+pub mod pg_store;
+pub mod redis_store;
+
+use crate::api::Result;
+use crate::api_common::storages::{BlockInfo, SessionInfo};
+use crate::config::{Config, SessionStoreKind};
+use async_trait::async_trait;
+use pg_store::PgStore;
+use redis::Client;
+use redis_store::RedisStore;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// An upload session with the chunks received so far.
+#[derive(Debug)]
+pub struct UploadSession {
+    pub info: SessionInfo,
+    // ordered by block_index
+    pub chunks: Vec<BlockInfo>,
+    // unix timestamp after which the session is garbage-collected
+    pub expires_at: i64,
+}
+
+/// Keeps the state of chunked uploads between requests.
+///
+/// Deadlines are unix timestamps computed by the caller, a store only records them.
+#[async_trait]
+pub trait UploadSessionStore: Send + Sync {
+    async fn create(&self, session_id: Uuid, info: &SessionInfo, expires_at: i64) -> Result<()>;
+
+    async fn get(&self, session_id: Uuid) -> Result<Option<UploadSession>>;
+
+    /// Save a chunk and push the deadline forward. A chunk with the same index is
+    /// replaced and returned, so the caller can delete its block.
+    /// Fails with `NotFound` if the session is gone.
+    async fn put_chunk(&self, session_id: Uuid, chunk: &BlockInfo, expires_at: i64) -> Result<Option<BlockInfo>>;
+
+    /// Save chunks after the `expected` ones of a session if it still has exactly that
+    /// many, and push the deadline forward. False when another request added chunks
+    /// meanwhile, nothing is saved then. Fails with `NotFound` if the session is gone.
+    async fn append_chunks(&self, session_id: Uuid, expected: usize, chunks: &[BlockInfo], expires_at: i64) -> Result<bool>;
+
+    async fn touch(&self, session_id: Uuid, expires_at: i64) -> Result<()>;
+
+    /// Remove the session and return it. Only one of concurrent callers gets it,
+    /// which makes finishing, aborting and reaping a session exclusive.
+    async fn take(&self, session_id: Uuid) -> Result<Option<UploadSession>>;
+
+    /// Put back a session removed by `take`.
+    async fn restore(&self, session_id: Uuid, session: &UploadSession) -> Result<()>;
+
+    /// Ids of the sessions whose deadline is not after `now`.
+    async fn expired(&self, now: i64) -> Result<Vec<Uuid>>;
+
+    /// Ids of the sessions of a user.
+    async fn user_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>>;
+}
+
+pub(crate) fn new_store(config: &Config, db: &PgPool, redis_client: &Arc<Client>) -> Arc<dyn UploadSessionStore> {
+    match config.upload_session_store {
+        SessionStoreKind::Redis => Arc::new(RedisStore::new(Arc::clone(redis_client))),
+        SessionStoreKind::Postgres => Arc::new(PgStore::new(db.clone())),
+    }
+}
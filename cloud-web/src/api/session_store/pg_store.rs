@@ -0,0 +1,132 @@
+use super::{UploadSession, UploadSessionStore};
+use crate::api::Result;
+use crate::api_common::storages::{BlockInfo, ConflictPolicy, SessionInfo};
+use async_trait::async_trait;
+use cloud_core::db_schema::upload_sessions::{UploadSessionChunks, UploadSessions};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Stores sessions in the upload_sessions and upload_session_chunks tables,
+/// for deployments without redis.
+pub struct PgStore {
+    db: PgPool,
+}
+
+impl PgStore {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+fn to_db_session(session_id: Uuid, info: &SessionInfo, expires_at: i64) -> UploadSessions {
+    UploadSessions {
+        id: session_id,
+        user_id: info.user_id,
+        ws_id: info.ws_id,
+        filename: info.filename.clone(),
+        parent_dir_id: info.parent_dir_id,
+        expires_at,
+        total_size: info.total_size as i64,
+        file_hash: info.file_hash.clone(),
+        file_id: info.file_id,
+        conflict: info.conflict.as_str().to_string(),
+    }
+}
+
+fn to_db_chunk(session_id: Uuid, chunk: &BlockInfo) -> UploadSessionChunks {
+    UploadSessionChunks {
+        session_id,
+        block_index: chunk.block_index as i32,
+        block_name: chunk.block_name.clone(),
+        block_hash: chunk.block_hash.clone(),
+        block_size: chunk.block_size as i64,
+        shared: chunk.shared,
+    }
+}
+
+fn from_db_chunk(chunk: UploadSessionChunks) -> BlockInfo {
+    BlockInfo {
+        block_name: chunk.block_name,
+        block_index: chunk.block_index as usize,
+        block_size: chunk.block_size as usize,
+        block_hash: chunk.block_hash,
+        shared: chunk.shared,
+    }
+}
+
+fn from_db_session(session: UploadSessions, chunks: Vec<UploadSessionChunks>) -> UploadSession {
+    UploadSession {
+        info: SessionInfo {
+            user_id: session.user_id,
+            ws_id: session.ws_id,
+            filename: session.filename,
+            parent_dir_id: session.parent_dir_id,
+            total_size: session.total_size as u64,
+            file_hash: session.file_hash,
+            file_id: session.file_id,
+            // written by to_db_session, an unknown value can't be there
+            conflict: ConflictPolicy::parse(&session.conflict).unwrap_or_default(),
+        },
+        chunks: chunks.into_iter().map(from_db_chunk).collect(),
+        expires_at: session.expires_at,
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for PgStore {
+    async fn create(&self, session_id: Uuid, info: &SessionInfo, expires_at: i64) -> Result<()> {
+        to_db_session(session_id, info, expires_at).insert(&self.db).await?;
+        Ok(())
+    }
+
+    async fn get(&self, session_id: Uuid) -> Result<Option<UploadSession>> {
+        let session = match UploadSessions::get(session_id, &self.db).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        let chunks = UploadSessions::chunks(session_id, &self.db).await?;
+        Ok(Some(from_db_session(session, chunks)))
+    }
+
+    async fn put_chunk(&self, session_id: Uuid, chunk: &BlockInfo, expires_at: i64) -> Result<Option<BlockInfo>> {
+        let old = UploadSessions::put_chunk(&to_db_chunk(session_id, chunk), expires_at, &self.db).await?;
+        Ok(old.map(from_db_chunk))
+    }
+
+    async fn append_chunks(&self, session_id: Uuid, expected: usize, chunks: &[BlockInfo], expires_at: i64) -> Result<bool> {
+        let chunks: Vec<UploadSessionChunks> = chunks
+            .iter()
+            .map(|chunk| to_db_chunk(session_id, chunk))
+            .collect();
+        Ok(UploadSessions::append_chunks(session_id, expected as i64, &chunks, expires_at, &self.db).await?)
+    }
+
+    async fn touch(&self, session_id: Uuid, expires_at: i64) -> Result<()> {
+        UploadSessions::touch(session_id, expires_at, &self.db).await?;
+        Ok(())
+    }
+
+    async fn take(&self, session_id: Uuid) -> Result<Option<UploadSession>> {
+        let session = UploadSessions::take(session_id, &self.db).await?;
+        Ok(session.map(|(session, chunks)| from_db_session(session, chunks)))
+    }
+
+    async fn restore(&self, session_id: Uuid, session: &UploadSession) -> Result<()> {
+        let chunks: Vec<UploadSessionChunks> = session.chunks
+            .iter()
+            .map(|chunk| to_db_chunk(session_id, chunk))
+            .collect();
+        to_db_session(session_id, &session.info, session.expires_at)
+            .restore(&chunks, &self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn expired(&self, now: i64) -> Result<Vec<Uuid>> {
+        Ok(UploadSessions::expired(now, &self.db).await?)
+    }
+
+    async fn user_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        Ok(UploadSessions::user_sessions(user_id, &self.db).await?)
+    }
+}
@@ -0,0 +1,250 @@
+use super::{UploadSession, UploadSessionStore};
+use crate::api::{error::CustomError, Result};
+use crate::api_common::storages::{BlockInfo, SessionInfo};
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// sorted set of upload session ids scored by the unix timestamp they expire at
+const UPLOAD_SESSIONS_KEY: &str = "upload_sessions";
+// times an append is tried again when a watched key changed meanwhile
+const MAX_APPEND_ATTEMPTS: usize = 8;
+// redis drops the keys of a session this long after its deadline,
+// so the reaper gets to delete the blocks first
+const UPLOAD_SESSION_GRACE: i64 = 60 * 60;
+
+/// Stores a session as its info under the session id and its chunks
+/// in a hash of chunk index to `BlockInfo`.
+pub struct RedisStore {
+    redis_client: Arc<Client>,
+}
+
+impl RedisStore {
+    pub fn new(redis_client: Arc<Client>) -> Self {
+        Self { redis_client }
+    }
+
+    async fn get_conn(&self) -> Result<redis::aio::Connection> {
+        Ok(self.redis_client.get_async_connection().await?)
+    }
+}
+
+fn chunks_key(session_id: &str) -> String {
+    format!("{}_chunks", session_id)
+}
+
+fn key_ttl(expires_at: i64) -> usize {
+    (expires_at - OffsetDateTime::now_utc().unix_timestamp() + UPLOAD_SESSION_GRACE).max(1) as usize
+}
+
+fn parse_chunks(chunk_values: Vec<String>) -> Result<Vec<BlockInfo>> {
+    let mut chunks = Vec::new();
+    for chunk_value in chunk_values {
+        chunks.push(serde_json::from_str::<BlockInfo>(chunk_value.as_str())?);
+    }
+    chunks.sort_by_key(|chunk| chunk.block_index);
+    Ok(chunks)
+}
+
+#[async_trait]
+impl UploadSessionStore for RedisStore {
+    async fn create(&self, session_id: Uuid, info: &SessionInfo, expires_at: i64) -> Result<()> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+
+        redis::pipe()
+            .atomic()
+            .set_ex(&session_id, serde_json::to_string(info)?, key_ttl(expires_at)).ignore()
+            .zadd(UPLOAD_SESSIONS_KEY, &session_id, expires_at).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, session_id: Uuid) -> Result<Option<UploadSession>> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+
+        let (info, chunk_values, expires_at): (Option<String>, Vec<String>, Option<f64>) = redis::pipe()
+            .atomic()
+            .get(&session_id)
+            .hvals(chunks_key(&session_id))
+            .zscore(UPLOAD_SESSIONS_KEY, &session_id)
+            .query_async(&mut conn)
+            .await?;
+
+        let info = match info {
+            Some(info) => serde_json::from_str::<SessionInfo>(info.as_str())?,
+            None => return Ok(None),
+        };
+        Ok(Some(UploadSession {
+            info,
+            chunks: parse_chunks(chunk_values)?,
+            expires_at: expires_at.unwrap_or_default() as i64,
+        }))
+    }
+
+    async fn put_chunk(&self, session_id: Uuid, chunk: &BlockInfo, expires_at: i64) -> Result<Option<BlockInfo>> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+        let chunks_key = chunks_key(&session_id);
+        let key_ttl = key_ttl(expires_at);
+
+        // the old chunk is read and replaced in the same transaction
+        let (exists, old): (bool, Option<String>) = redis::pipe()
+            .atomic()
+            .exists(&session_id)
+            .hget(&chunks_key, chunk.block_index)
+            .hset(&chunks_key, chunk.block_index, serde_json::to_string(chunk)?).ignore()
+            .expire(&session_id, key_ttl).ignore()
+            .expire(&chunks_key, key_ttl).ignore()
+            .zadd(UPLOAD_SESSIONS_KEY, &session_id, expires_at).ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        if !exists {
+            // the session was taken meanwhile, don't leave the chunk behind
+            redis::pipe()
+                .atomic()
+                .del(&chunks_key).ignore()
+                .zrem(UPLOAD_SESSIONS_KEY, &session_id).ignore()
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            return Err(CustomError::NotFound);
+        }
+
+        match old {
+            Some(old) => Ok(Some(serde_json::from_str::<BlockInfo>(old.as_str())?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn append_chunks(&self, session_id: Uuid, expected: usize, chunks: &[BlockInfo], expires_at: i64) -> Result<bool> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+        let chunks_key = chunks_key(&session_id);
+        let key_ttl = key_ttl(expires_at);
+
+        // the count is checked and the chunks are added in a transaction which
+        // fails if the chunks or the session changed since the check
+        for _ in 0..MAX_APPEND_ATTEMPTS {
+            redis::cmd("WATCH").arg(&session_id).arg(&chunks_key).query_async::<_, ()>(&mut conn).await?;
+            let (exists, count): (bool, usize) = redis::pipe()
+                .exists(&session_id)
+                .hlen(&chunks_key)
+                .query_async(&mut conn)
+                .await?;
+            if !exists || count != expected {
+                redis::cmd("UNWATCH").query_async::<_, ()>(&mut conn).await?;
+                return match exists {
+                    true => Ok(false),
+                    false => Err(CustomError::NotFound),
+                };
+            }
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for chunk in chunks {
+                pipe.hset(&chunks_key, chunk.block_index, serde_json::to_string(chunk)?).ignore();
+            }
+            let appended: Option<()> = pipe
+                .expire(&session_id, key_ttl).ignore()
+                .expire(&chunks_key, key_ttl).ignore()
+                .zadd(UPLOAD_SESSIONS_KEY, &session_id, expires_at).ignore()
+                .query_async(&mut conn)
+                .await?;
+            if appended.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn touch(&self, session_id: Uuid, expires_at: i64) -> Result<()> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+        let key_ttl = key_ttl(expires_at);
+
+        redis::pipe()
+            .atomic()
+            .expire(&session_id, key_ttl).ignore()
+            .expire(chunks_key(&session_id), key_ttl).ignore()
+            .zadd(UPLOAD_SESSIONS_KEY, &session_id, expires_at).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn take(&self, session_id: Uuid) -> Result<Option<UploadSession>> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+        let chunks_key = chunks_key(&session_id);
+
+        let (info, chunk_values, expires_at, deleted): (Option<String>, Vec<String>, Option<f64>, i64) = redis::pipe()
+            .atomic()
+            .get(&session_id)
+            .hvals(&chunks_key)
+            .zscore(UPLOAD_SESSIONS_KEY, &session_id)
+            .del(&session_id)
+            .del(&chunks_key).ignore()
+            .zrem(UPLOAD_SESSIONS_KEY, &session_id).ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        // another caller deleted the session key first
+        let info = match info {
+            Some(info) if deleted == 1 => serde_json::from_str::<SessionInfo>(info.as_str())?,
+            _ => return Ok(None),
+        };
+        Ok(Some(UploadSession {
+            info,
+            chunks: parse_chunks(chunk_values)?,
+            expires_at: expires_at.unwrap_or_default() as i64,
+        }))
+    }
+
+    async fn restore(&self, session_id: Uuid, session: &UploadSession) -> Result<()> {
+        let mut conn = self.get_conn().await?;
+        let session_id = session_id.to_string();
+        let chunks_key = chunks_key(&session_id);
+        let key_ttl = key_ttl(session.expires_at);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set_ex(&session_id, serde_json::to_string(&session.info)?, key_ttl).ignore()
+            .zadd(UPLOAD_SESSIONS_KEY, &session_id, session.expires_at).ignore();
+        for chunk in &session.chunks {
+            pipe.hset(&chunks_key, chunk.block_index, serde_json::to_string(chunk)?).ignore();
+        }
+        pipe.expire(&chunks_key, key_ttl).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn expired(&self, now: i64) -> Result<Vec<Uuid>> {
+        let mut conn = self.get_conn().await?;
+        let session_ids: Vec<String> = conn.zrangebyscore(UPLOAD_SESSIONS_KEY, "-inf", now).await?;
+        Ok(session_ids.iter().filter_map(|session_id| Uuid::parse_str(session_id).ok()).collect())
+    }
+
+    // sessions aren't indexed by user, every session is read
+    async fn user_sessions(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let mut conn = self.get_conn().await?;
+        let session_ids: Vec<String> = conn.zrangebyscore(UPLOAD_SESSIONS_KEY, "-inf", "+inf").await?;
+        let mut user_sessions = Vec::new();
+        for session_id in session_ids {
+            let info: Option<String> = conn.get(&session_id).await?;
+            let info = match info {
+                Some(info) => serde_json::from_str::<SessionInfo>(info.as_str())?,
+                None => continue,
+            };
+            if info.user_id == user_id {
+                user_sessions.extend(Uuid::parse_str(&session_id).ok());
+            }
+        }
+        Ok(user_sessions)
+    }
+}
@@ -1,38 +1,75 @@
 use std::collections::HashMap;
-use axum::extract::{Extension, Path, Multipart, BodyStream, Query};
+use axum::extract::{Extension, Path, Multipart, BodyStream, Query, DefaultBodyLimit};
+use axum::extract::multipart::Field;
 use axum::{Json, Router, debug_handler};
 use axum::routing::{get, post, put, delete};
-use crate::api::{extractor::{AuthUser, AuthUploadInfo}, ApiContext, Result, error::CustomError};
+use crate::api::{extractor::{AuthUser, AuthUploadInfo}, ApiContext, Result, error::{CustomError, ResultExt}};
 use crate::api_common::storages::{StorageBody, Storage, Session, CreateSessionReq, UpdateFileReq,
                                   SessionInfo, BlockInfo, UploadFinishReq, ListStorageReq,
-                                  UploadFileReq};
-use crate::api::workspaces;
-use cloud_core::db_schema::files::Files as DbFile;
-use cloud_core::store_service::{cloud_file::CloudFile, cloud_block::CloudBlock};
+                                  UploadFileReq, MoveFileReq, CopyFileReq, CopyJob, ListStorageResp,
+                                  TreeReq, TreeNode, SessionStatus, InstantChallenge,
+                                  InstantUploadReq, DeltaReq, DeltaResp, BatchUploadReq, BatchEntry,
+                                  BatchUploadResp, ConflictPolicy};
+use crate::api::{validation, workspaces};
+use cloud_core::block::BlockHandler;
+use cloud_core::error::Error as CoreError;
+use cloud_core::db_schema::files::{Files as DbFile, ListQuery};
+use cloud_core::db_schema::users::Users as DbUser;
+use cloud_core::db_schema::file_histories::FileHistories;
+use cloud_core::store_service::{cloud_block::CloudBlock, cloud_copy::CloudCopy,
+                                cloud_path::CloudPath, cloud_writer::{CloudWriter, WrittenFile}};
 use std::sync::Arc;
 use axum::headers::{Header, HeaderValue};
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::CONTENT_LENGTH;
 use uuid::Uuid;
 use bytes::{Bytes, BytesMut};
 use redis::{self, AsyncCommands};
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use serde_json;
 use crate::api;
+use crate::api::session_store::UploadSession;
+use std::time::Duration;
+use time::OffsetDateTime;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 const ROOT_DIR_ID: i64 = -1;
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+const MAX_TREE_DEPTH: i32 = 16;
+const COPY_JOB_TTL: usize = 24 * 60 * 60;
+// write copy progress to redis every COPY_PROGRESS_STEP entries
+const COPY_PROGRESS_STEP: usize = 100;
+const UPLOAD_SESSION_REAP_INTERVAL: Duration = Duration::from_secs(60);
+// max bytes of the range an instant upload must prove to have
+const INSTANT_CHALLENGE_SIZE: u64 = 64 * 1024;
+// the unique index on the name of a live file in its dir
+pub(crate) const FILES_NAME_KEY: &str = "files_ws_id_parent_dir_id_name_key";
+// numbered names tried by ConflictPolicy::Rename before giving up
+const MAX_RENAME_ATTEMPTS: usize = 100;
 
 pub fn router() -> Router {
     Router::new()
         .route("/api/:ws_id/storages", post(create_storage).get(list_storage))
+        // each file is limited by max_upload_size while it's stored
+        .route("/api/:ws_id/storages/batch", post(batch_upload).layer(DefaultBodyLimit::disable()))
         .route("/api/:ws_id/storages/:id", get(get_storage)
             .delete(delete_storage).put(update_file_info))
+        .route("/api/:ws_id/storages/:id/move", post(move_storage))
+        .route("/api/:ws_id/storages/:id/copy", post(copy_storage))
+        .route("/api/:ws_id/storages/:id/tree", get(get_storage_tree))
+        .route("/api/:ws_id/storages/:id/delta", post(create_delta_session))
+        .route("/api/copy_jobs/:job_id", get(get_copy_job))
         .route("/api/upload_sessions", post(create_session))
         .route("/api/upload_sessions/chunks", post(upload_chunk))
-        .route("/api/upload_sessions/:session_id", post(finish_upload))
+        .route("/api/upload_sessions/:session_id", post(finish_upload)
+            .get(get_session).delete(abort_session))
+        .route("/api/upload_sessions/:session_id/instant", post(instant_upload))
 }
 
 // TODO: convert it to extractor
-async fn check_file_owner(user_id: Uuid, id: i64, ws_id: Uuid, ctx: &Extension<ApiContext>) -> Result<DbFile> {
+pub(crate) async fn check_file_owner(user_id: Uuid, id: i64, ws_id: Uuid, ctx: &Extension<ApiContext>) -> Result<DbFile> {
     let db_file = DbFile::check_owner(user_id, id, ws_id, &ctx.db).await?;
 
     // if db_file is None, return Error::Forbidden
@@ -43,7 +80,7 @@ async fn check_file_owner(user_id: Uuid, id: i64, ws_id: Uuid, ctx: &Extension<A
     Ok(db_file.unwrap())
 }
 
-async fn check_permission(user_id: Uuid, parent_dir_id: i64, ws_id: Uuid, ctx: &Extension<ApiContext>) -> Result<DbFile> {
+pub(crate) async fn check_permission(user_id: Uuid, parent_dir_id: i64, ws_id: Uuid, ctx: &Extension<ApiContext>) -> Result<DbFile> {
     if parent_dir_id == -1 {
         let ws = workspaces::check_ws_owner(user_id, ws_id, &ctx).await?;
         Ok(DbFile::root_dir(ws_id, user_id, ws.name))
@@ -52,33 +89,366 @@ async fn check_permission(user_id: Uuid, parent_dir_id: i64, ws_id: Uuid, ctx: &
     }
 }
 
+/// Fail with `InsufficientStorage` if `size` more bytes don't fit in the quota of the user.
+pub(crate) async fn check_quota(user_id: Uuid, size: u64, ctx: &ApiContext) -> Result<()> {
+    let quota = match DbUser::find_by_id(user_id, &ctx.db).await?.quota {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+    let usage = DbFile::get_user_usage(user_id, &ctx.db).await?;
+    if usage.saturating_add(size as i64) > quota {
+        return Err(CustomError::InsufficientStorage);
+    }
+    Ok(())
+}
+
+// the usage a new version of a file adds, a smaller version doesn't free any before it's stored
+fn version_growth(db_file: &DbFile, new_size: u64) -> u64 {
+    new_size.saturating_sub(db_file.size.max(0) as u64)
+}
+
 async fn create_session(
     ctx: Extension<ApiContext>,
     auth_user: AuthUser,
     data: Json<CreateSessionReq>,
 ) -> Result<Json<Session>> {
     let session_id = Uuid::now_v7();
-    // redis set session_id [user_id, filename, parent_dir_id]
-    let redis_client = Arc::clone(&ctx.redis_client);
-    let mut conn = redis_client.get_async_connection().await?;
+
+    if !is_sha256_hex(&data.file_hash) {
+        return Err(CustomError::unprocessable_entity([("file_hash", "must be a hex sha256")]));
+    }
+    validation::check_name("filename", &data.filename, &ctx.config)?;
 
     let session_info = SessionInfo {
         user_id: auth_user.user_id,
         ws_id: data.ws_id,
         filename: data.filename.clone(),
         parent_dir_id: data.parent_dir_id,
+        total_size: data.total_size,
+        file_hash: data.file_hash.to_lowercase(),
+        file_id: None,
+        conflict: data.conflict,
     };
+    auth_user.check_ws(data.ws_id)?;
     check_permission(auth_user.user_id, data.parent_dir_id, data.ws_id, &ctx).await?;
+    check_quota(auth_user.user_id, data.total_size, &ctx).await?;
 
-    conn.set(session_id.to_string(), serde_json::to_string(&session_info)?).await?;
+    ctx.upload_sessions.create(session_id, &session_info, session_deadline(&ctx)).await?;
+
+    // the content is already stored, the client may skip uploading it
+    let mut challenge = None;
+    if session_info.total_size > 0 {
+        let history = FileHistories::find_by_content(
+            auth_user.user_id,
+            &session_info.file_hash,
+            session_info.total_size as i64,
+            &ctx.db
+        ).await?;
+        if history.is_some() {
+            challenge = Some(instant_challenge(session_id, session_info.total_size, &ctx));
+        }
+    }
 
     Ok(Json(Session{
-        session_id
+        session_id,
+        challenge,
     }))
 }
 
-// check if session_id exists in redis and owned by this user
-// first get session_id from redis and check owner
+// Start uploading a new version of a file: the server keeps the slices it
+// already has for this user and replies with the ones the client must upload
+// to the session. The session is finished like any other upload.
+async fn create_delta_session(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path((ws_id, id)): Path<(Uuid, i64)>,
+    Json(req): Json<DeltaReq>,
+) -> Result<Json<DeltaResp>> {
+    if !is_sha256_hex(&req.file_hash) {
+        return Err(CustomError::unprocessable_entity([("file_hash", "must be a hex sha256")]));
+    }
+    if req.slices_hash.len() != req.slices_size.len() {
+        return Err(CustomError::unprocessable_entity([("slices_size", "must have one size per slice")]));
+    }
+    if !req.slices_hash.iter().all(|hash| is_sha256_hex(hash)) {
+        return Err(CustomError::unprocessable_entity([("slices_hash", "must be hex sha256")]));
+    }
+
+    let db_file = check_file_owner(auth_user.user_id, id, ws_id, &ctx).await?;
+    if db_file.is_dir {
+        return Err(CustomError::BadRequest);
+    }
+
+    let slices_hash: Vec<String> = req.slices_hash.iter().map(|hash| hash.to_lowercase()).collect();
+    let known = FileHistories::find_slices(auth_user.user_id, &slices_hash, &ctx.db).await?;
+    let mut shared = vec![];
+    let mut missing = vec![];
+    for (index, hash) in slices_hash.into_iter().enumerate() {
+        match known.get(&hash) {
+            Some(block_name) => shared.push((index, hash, block_name.clone())),
+            None => missing.push(index),
+        }
+    }
+
+    // the shared slices are never uploaded, their sizes are the stored ones and not
+    // the claimed ones, the sizes of the missing slices are checked on finishing
+    let shared_size = ctx.fs_handler.get_blocks_size(shared.iter().map(|(_, _, block_name)| block_name.as_str()).collect())?;
+    let total_size = shared_size.iter().sum::<u64>()
+        + missing.iter().map(|index| req.slices_size[*index] as u64).sum::<u64>();
+
+    check_quota(auth_user.user_id, version_growth(&db_file, total_size), &ctx).await?;
+
+    let session_id = Uuid::now_v7();
+    let session_info = SessionInfo {
+        user_id: auth_user.user_id,
+        ws_id,
+        filename: db_file.filename.clone(),
+        parent_dir_id: db_file.parent_dir_id,
+        total_size,
+        file_hash: req.file_hash.to_lowercase(),
+        file_id: Some(id),
+        conflict: ConflictPolicy::Fail,
+    };
+    let expires_at = session_deadline(&ctx);
+    ctx.upload_sessions.create(session_id, &session_info, expires_at).await?;
+
+    for ((block_index, block_hash, block_name), block_size) in shared.into_iter().zip(shared_size) {
+        let block_info = BlockInfo {
+            block_name,
+            block_index,
+            block_size: block_size as usize,
+            block_hash,
+            shared: true,
+        };
+        ctx.upload_sessions.put_chunk(session_id, &block_info, expires_at).await?;
+    }
+
+    Ok(Json(DeltaResp {
+        session_id,
+        missing,
+    }))
+}
+
+// The range is derived from the session id with the server key, so it can't be
+// known before the session is created and doesn't need to be stored.
+// Knowing the hash of a file isn't enough to get a copy of it this way.
+fn instant_challenge(session_id: Uuid, total_size: u64, ctx: &ApiContext) -> InstantChallenge {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(ctx.config.hmac_key.as_bytes())
+        .expect("HMAC-SHA-256 can accept any key length");
+    hmac.update(session_id.as_bytes());
+    let digest = hmac.finalize().into_bytes();
+
+    let mut seed = [0u8; 8];
+    seed.copy_from_slice(&digest[..8]);
+    let offset = u64::from_be_bytes(seed) % total_size;
+    InstantChallenge {
+        offset,
+        length: INSTANT_CHALLENGE_SIZE.min(total_size - offset),
+    }
+}
+
+// create the file of a session from already stored content with the same hash,
+// the client proves to have the content by hashing the challenged range
+async fn instant_upload(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    Json(req): Json<InstantUploadReq>,
+) -> Result<Json<StorageBody<Storage>>> {
+    let session = get_user_session(session_id, auth_user.user_id, &ctx).await?;
+    if session.info.total_size == 0 {
+        return Err(CustomError::NotFound);
+    }
+    let history = FileHistories::find_by_content(
+        auth_user.user_id,
+        &session.info.file_hash,
+        session.info.total_size as i64,
+        &ctx.db
+    ).await?.ok_or(CustomError::NotFound)?;
+
+    let challenge = instant_challenge(session_id, session.info.total_size, &ctx);
+    let proof = CloudBlock::digest_range(&history.slices, challenge.offset, challenge.length, ctx.fs_handler.clone())?;
+    if proof != req.proof.to_lowercase() {
+        return Err(CustomError::Forbidden);
+    }
+
+    // only one request gets to finish the session
+    let session = ctx.upload_sessions.take(session_id).await?.ok_or(CustomError::NotFound)?;
+    let id = ctx.snowflake.lock().unwrap().next_id();
+    let db_file = DbFile::new(
+        id,
+        auth_user.user_id,
+        session.info.ws_id,
+        session.info.filename.clone(),
+        session.info.parent_dir_id,
+        session.info.total_size as i64,
+        false
+    );
+    // the file shares the slices of the stored content
+    let content = NewContent {
+        blocks_name: history.slices,
+        blocks_hash: history.slices_hash,
+        file_hash: Some(session.info.file_hash.clone()),
+    };
+    let db_file = match insert_with_policy(&ctx, db_file, Some(content), session.info.conflict).await {
+        Ok(inserted) => inserted.file(),
+        Err(e) => {
+            ctx.upload_sessions.restore(session_id, &session).await?;
+            return Err(e);
+        }
+    };
+
+    // chunks uploaded before are not needed
+    ctx.fs_handler.delete_blocks(session_blocks(&session))?;
+
+    Ok(Json(StorageBody {
+        storage: Storage::new(
+            db_file.id,
+            db_file.filename.clone(),
+            db_file.is_dir,
+            db_file.parent_dir_id,
+            db_file.disk_usage() as usize
+        )
+    }))
+}
+
+fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// check the received chunks against the declared file before they are read:
+// the indexes must be 0..total_chunk_num, all chunks but the last one must have
+// the same size, and the sizes must add up to the declared total size
+fn check_session_chunks(session: &UploadSession, total_chunk_num: usize) -> Result<()> {
+    if let Some(index) = (0..total_chunk_num).find(|index| {
+        session.chunks.get(*index).map(|chunk| chunk.block_index) != Some(*index)
+    }) {
+        return Err(CustomError::unprocessable_entity([("chunks", format!("chunk {} is missing", index))]));
+    }
+    if session.chunks.len() != total_chunk_num {
+        return Err(CustomError::unprocessable_entity([("chunks", "more chunks than total_chunk_num")]));
+    }
+
+    if let Some((first, rest)) = session.chunks.split_first() {
+        let inconsistent = match rest.split_last() {
+            Some((last, middle)) => {
+                middle.iter().any(|chunk| chunk.block_size != first.block_size)
+                    || last.block_size == 0
+                    || last.block_size > first.block_size
+            },
+            None => false,
+        };
+        if inconsistent {
+            return Err(CustomError::unprocessable_entity([("chunks", "chunk sizes are inconsistent")]));
+        }
+    }
+
+    let file_size: usize = session.chunks.iter().map(|chunk| chunk.block_size).sum();
+    if file_size as u64 != session.info.total_size {
+        return Err(CustomError::unprocessable_entity([("total_size", "doesn't match the uploaded chunks")]));
+    }
+    Ok(())
+}
+
+// the deadline of a session with activity now
+pub(crate) fn session_deadline(ctx: &ApiContext) -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp() + ctx.config.upload_session_ttl as i64
+}
+
+// get a session, a session of another user is treated as missing
+pub(crate) async fn get_user_session(session_id: Uuid, user_id: Uuid, ctx: &ApiContext) -> Result<UploadSession> {
+    match ctx.upload_sessions.get(session_id).await? {
+        Some(session) if session.info.user_id == user_id => Ok(session),
+        _ => Err(CustomError::NotFound),
+    }
+}
+
+// the blocks uploaded to a session, without the slices shared with stored files
+pub(crate) fn session_blocks(session: &UploadSession) -> Vec<&str> {
+    session.chunks.iter()
+        .filter(|chunk| !chunk.shared)
+        .map(|chunk| chunk.block_name.as_str())
+        .collect()
+}
+
+// take a session out of the store and delete its blocks
+pub(crate) async fn discard_session(session_id: Uuid, ctx: &ApiContext) -> Result<()> {
+    if let Some(session) = ctx.upload_sessions.take(session_id).await? {
+        ctx.fs_handler.delete_blocks(session_blocks(&session))?;
+    }
+    Ok(())
+}
+
+/// Remove the upload sessions whose deadline has passed, together with their blocks.
+/// Returns the number of removed sessions.
+pub(crate) async fn reap_upload_sessions(ctx: &ApiContext) -> Result<usize> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let session_ids = ctx.upload_sessions.expired(now).await?;
+
+    let mut reaped = 0;
+    for session_id in session_ids {
+        let session = match ctx.upload_sessions.take(session_id).await? {
+            Some(session) => session,
+            None => continue,
+        };
+        // the session was touched since it was listed
+        if session.expires_at > now {
+            ctx.upload_sessions.restore(session_id, &session).await?;
+            continue;
+        }
+        ctx.fs_handler.delete_blocks(session_blocks(&session))?;
+        reaped += 1;
+    }
+    Ok(reaped)
+}
+
+// run reap_upload_sessions every UPLOAD_SESSION_REAP_INTERVAL until the server stops
+pub(crate) fn spawn_session_reaper(ctx: ApiContext) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(UPLOAD_SESSION_REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match reap_upload_sessions(&ctx).await {
+                Ok(0) => {},
+                Ok(reaped) => log::info!("reaped {} expired upload sessions", reaped),
+                Err(e) => log::error!("failed to reap upload sessions: {:?}", e),
+            }
+        }
+    });
+}
+
+// list the received chunks of a session
+async fn get_session(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<SessionStatus>> {
+    let session = get_user_session(session_id, auth_user.user_id, &ctx).await?;
+
+    Ok(Json(SessionStatus {
+        session_id,
+        ws_id: session.info.ws_id,
+        filename: session.info.filename,
+        parent_dir_id: session.info.parent_dir_id,
+        total_size: session.info.total_size,
+        file_hash: session.info.file_hash,
+        chunks: session.chunks.iter().map(|b| b.block_index).collect(),
+        expires_at: session.expires_at,
+    }))
+}
+
+// abort a session and delete the uploaded blocks
+async fn abort_session(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+) -> Result<()> {
+    get_user_session(session_id, auth_user.user_id, &ctx).await?;
+    discard_session(session_id, &ctx).await
+}
+
+// check if session_id exists and owned by this user
+// first get session_id from the session store and check owner
 // put chunk to storage
 #[debug_handler]
 async fn upload_chunk(
@@ -108,77 +478,118 @@ async fn upload_chunk(
         return Err(CustomError::BadRequest.into());
     }
 
-    cloud_block.store_block(fs_handler).await?;
+    // the chunk is kept as one block named after block_name, which is
+    // what the session records
+    cloud_block.store_as_block(fs_handler.clone())?;
 
-    let redis_client = Arc::clone(&ctx.redis_client);
-    let mut conn = redis_client.get_async_connection().await?;
-
-    let block_key = format!("{}_{}", auth_upload_info.session_id, auth_upload_info.chunk_num);
     let block_info = BlockInfo {
         block_name,
         block_index: auth_upload_info.chunk_num,
         block_hash: auth_upload_info.hash,
-        block_size: auth_upload_info.chunk_size
+        block_size: auth_upload_info.chunk_size,
+        shared: false,
+    };
+    let put = ctx.upload_sessions
+        .put_chunk(auth_upload_info.session_id, &block_info, session_deadline(&ctx))
+        .await;
+
+    // a re-sent chunk replaces the old one, and a chunk of a finished
+    // session isn't kept
+    let stale_block = match put {
+        Ok(old) => old.filter(|old| !old.shared).map(|old| old.block_name),
+        Err(e) => {
+            fs_handler.delete_blocks(vec![block_info.block_name.as_str()])?;
+            return Err(e);
+        }
     };
-    conn.set(block_key, serde_json::to_string(&block_info)?).await?;
+    if let Some(stale_block) = stale_block {
+        fs_handler.delete_blocks(vec![stale_block.as_str()])?;
+    }
 
     Ok(())
 }
 
-// check if session_id exists in redis and owned by this user
-// first get session_id from redis and check owner
+// check if session_id exists and owned by this user
+// first get session_id from the session store and check owner
 // write record to db
 async fn finish_upload(
     ctx: Extension<ApiContext>,
     auth_user: AuthUser,
-    Path(session_id): Path<String>,
+    Path(session_id): Path<Uuid>,
     upload_finish_req: Json<UploadFinishReq>
 ) -> Result<()> {
-    let redis_client = Arc::clone(&ctx.redis_client);
-    let mut conn = redis_client.get_async_connection().await?;
-    let sesson_value: String = conn.get(session_id.as_str()).await?;
-    if sesson_value.is_empty() {
-        return Err(CustomError::BadRequest.into());
+    let session = get_user_session(session_id, auth_user.user_id, &ctx).await?;
+    if let Err(e) = check_session_chunks(&session, upload_finish_req.total_chunk_num) {
+        // the session is kept, so the missing chunks can still be uploaded
+        // until it's aborted or expires
+        ctx.upload_sessions.touch(session_id, session_deadline(&ctx)).await?;
+        return Err(e);
     }
-    let session_info: SessionInfo = serde_json::from_str(sesson_value.as_str())?;
 
-    if session_info.user_id != auth_user.user_id {
-        return Err(CustomError::BadRequest.into());
-    }
-
-    let block_key_tpl = format!("{}_{}", session_id, "*");
-    let block_keys: Vec<String> = conn.keys(block_key_tpl).await?;
-
-    if block_keys.len() != upload_finish_req.total_chunk_num {
-        //TODO: clean up redis data and block data
-        return Err(CustomError::BadRequest.into());
+    // only one request gets to finish the session, check again what it got
+    let session = ctx.upload_sessions.take(session_id).await?.ok_or(CustomError::NotFound)?;
+    if let Err(e) = check_session_chunks(&session, upload_finish_req.total_chunk_num) {
+        ctx.upload_sessions.restore(session_id, &session).await?;
+        return Err(e);
     }
 
-    let mut block_infos = Vec::new();
-    for block_key in block_keys {
-        let block_value: String = conn.get(block_key).await?;
-        let block_info: BlockInfo = serde_json::from_str(block_value.as_str())?;
-        block_infos.push(block_info);
+    let blocks_name: Vec<String> = session.chunks.iter().map(|chunk| chunk.block_name.clone()).collect();
+    let file_hash = CloudBlock::digest_blocks(&blocks_name, ctx.fs_handler.clone());
+    let file_hash = match file_hash {
+        Ok(file_hash) => file_hash,
+        Err(e) => {
+            ctx.upload_sessions.restore(session_id, &session).await?;
+            return Err(e.into());
+        }
+    };
+    if file_hash != session.info.file_hash {
+        ctx.upload_sessions.restore(session_id, &session).await?;
+        return Err(CustomError::unprocessable_entity([("file_hash", "doesn't match the uploaded chunks")]));
     }
-    
-    // get block name and hash to blocks_name and blocks_hash
-    let mut blocks_name = Vec::new();
-    let mut blocks_hash = Vec::new();
-    let mut file_size: usize = 0;
 
-    block_infos.sort_by(|a, b| a.block_index.cmp(&b.block_index));
-    for block_info in block_infos {
-        blocks_name.push(block_info.block_name);
-        blocks_hash.push(block_info.block_hash);
-        file_size += block_info.block_size as usize;
-    }
+    let blocks_hash = session.chunks.iter().map(|chunk| chunk.block_hash.clone()).collect();
 
-    let snowflake = Arc::clone(&ctx.snowflake);
-    let id = snowflake.lock().unwrap().next_id();
+    let stored = match session.info.file_id {
+        // a delta upload, the chunks are the slices of the new version
+        Some(file_id) => {
+            let db_file = check_file_owner(auth_user.user_id, file_id, session.info.ws_id, &ctx).await;
+            match db_file {
+                Ok(db_file) => match check_quota(auth_user.user_id, version_growth(&db_file, session.info.total_size), &ctx).await {
+                    Ok(()) => db_file.update_file_version(blocks_name, blocks_hash,
+                                                          session.info.total_size as i64,
+                                                          Some(&session.info.file_hash),
+                                                          &ctx.db).await
+                        .map(Inserted::Overwritten)
+                        .map_err(CustomError::from),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            }
+        },
+        None => {
+            let snowflake = Arc::clone(&ctx.snowflake);
+            let id = snowflake.lock().unwrap().next_id();
 
-    CloudBlock::store_file(auth_user.user_id,  session_info.ws_id, session_info.parent_dir_id,
-                           id, blocks_name, blocks_hash, file_size as i64,
-                           session_info.filename, &ctx.db).await?;
+            let db_file = DbFile::new(id, auth_user.user_id, session.info.ws_id, session.info.filename.clone(),
+                                      session.info.parent_dir_id, session.info.total_size as i64, false);
+            let content = NewContent {
+                blocks_name,
+                blocks_hash,
+                file_hash: Some(session.info.file_hash.clone()),
+            };
+            insert_with_policy(&ctx, db_file, Some(content), session.info.conflict).await
+        },
+    };
+    match stored {
+        // the existing file is kept, the uploaded chunks are not needed
+        Ok(inserted) if inserted.is_skipped() => ctx.fs_handler.delete_blocks(session_blocks(&session))?,
+        Ok(_) => {},
+        Err(e) => {
+            // give the session back, so finishing can be retried
+            ctx.upload_sessions.restore(session_id, &session).await?;
+            return Err(e);
+        }
+    }
     Ok(())
 }
 
@@ -197,66 +608,389 @@ async fn create_storage(
     // let upload_file_req = serde_json::from_str::<UploadFileReq>("{\"filename\":\"test_dir\",\"is_dir\":true,\"parent_dir_id\":-1}")?;
     let upload_file_req = serde_json::from_str::<UploadFileReq>(upload_file_req)?;
 
-    let mut size = 0;
-    let mut data = BytesMut::new();
-    if !upload_file_req.is_dir {
-        while let Some(bytes) = stream.next().await {
-            let bytes = bytes.unwrap();
-            data.extend_from_slice(&bytes);
-        }
-        size = data.len();
-    }
+    validation::check_name("filename", &upload_file_req.filename, &ctx.config)?;
+    check_content_length(&headers, &ctx)?;
     check_permission(auth_user.user_id, upload_file_req.parent_dir_id, ws_id, &ctx).await?;
 
-    if upload_file_req.filename == "" ||
-        (upload_file_req.filename == "" && !upload_file_req.is_dir && data.len() == 0) ||
-        (upload_file_req.filename != "" && upload_file_req.is_dir && data.len() != 0)
-    {
-        return Err(CustomError::BadRequest.into());
-    }
-
     let snowflake = Arc::clone(&ctx.snowflake);
     let id = snowflake.lock().unwrap().next_id();
 
-    let mut cloud_file = CloudFile::new(
-        upload_file_req.filename.as_str(),
-        Bytes::from(data),
+    let db_file = DbFile::new(
+        id,
+        auth_user.user_id,
+        ws_id,
+        upload_file_req.filename.clone(),
+        upload_file_req.parent_dir_id,
+        0,
         upload_file_req.is_dir
     );
-
-    match upload_file_req.is_dir {
+    let inserted = match upload_file_req.is_dir {
         true => {
-            cloud_file.create_new_dir(
-                ws_id,
-                auth_user.user_id,
-                upload_file_req.parent_dir_id,
-                id,
-                &ctx.db
-            ).await?;
+            // a dir has no content
+            if let Some(bytes) = stream.next().await {
+                if !bytes.map_err(|_| CustomError::BadRequest)?.is_empty() {
+                    return Err(CustomError::BadRequest);
+                }
+            }
+            insert_with_policy(&ctx, db_file, None, upload_file_req.conflict).await?
         },
-        false => {
-            let fs_handler = Arc::clone(&ctx.fs_handler);
-            cloud_file.store_new_file(
-                ws_id,
-                auth_user.user_id,
-                upload_file_req.parent_dir_id,
-                id,
-                fs_handler,
-                &ctx.db
-            ).await?;
-        }
+        false => store_stream(&ctx, stream, db_file, upload_file_req.conflict).await?,
     };
+    let db_file = inserted.file();
     Ok(Json(StorageBody {
         storage: Storage::new(
-            id,
-            upload_file_req.filename,
-            upload_file_req.is_dir,
-            upload_file_req.parent_dir_id,
-            size
+            db_file.id,
+            db_file.filename.clone(),
+            db_file.is_dir,
+            db_file.parent_dir_id,
+            db_file.disk_usage() as usize
         )
     }))
 }
 
+// Store the parts of a multipart body under `parent_dir_id`. The filename of each
+// part is its path relative to that dir, like "photos/2023/a.jpg", and the missing
+// dirs are created on the way. A path ending with "/" creates an empty dir.
+// A failed entry doesn't stop the others, each gets its own result in the report.
+async fn batch_upload(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(ws_id): Path<Uuid>,
+    Query(batch_upload_req): Query<BatchUploadReq>,
+    mut multipart: Multipart,
+) -> Result<Json<BatchUploadResp>> {
+    let parent_dir = check_permission(auth_user.user_id, batch_upload_req.parent_dir_id, ws_id, &ctx).await?;
+    if !parent_dir.is_dir {
+        return Err(CoreError::NotADirectory.into());
+    }
+
+    let mut dirs = HashMap::new();
+    let mut entries = vec![];
+    while let Some(field) = multipart.next_field().await? {
+        let path = field.file_name().unwrap_or_default().to_string();
+        let stored = store_batch_entry(&ctx, auth_user.user_id, ws_id, &batch_upload_req, &path, field, &mut dirs).await;
+        entries.push(batch_entry(path, stored));
+    }
+
+    Ok(Json(BatchUploadResp { entries }))
+}
+
+// the report of one entry of a batch
+pub(crate) fn batch_entry(path: String, stored: Result<DbFile>) -> BatchEntry {
+    match stored {
+        Ok(db_file) => BatchEntry {
+            path,
+            status: StatusCode::OK.as_u16(),
+            storage: Some(Storage::new(
+                db_file.id,
+                db_file.filename.clone(),
+                db_file.is_dir,
+                db_file.parent_dir_id,
+                db_file.disk_usage() as usize
+            )),
+            error: None,
+        },
+        Err(e) => {
+            let status = e.status_code();
+            if status.is_server_error() {
+                log::error!("batch entry {} failed: {:?}", path, e);
+            }
+            BatchEntry {
+                path,
+                status: status.as_u16(),
+                storage: None,
+                error: Some(e.detail()),
+            }
+        }
+    }
+}
+
+// the id of the dir at `components` below `parent_dir_id`, the missing dirs are created.
+// `dirs` keeps the dirs already resolved in a batch, by their components.
+pub(crate) async fn resolve_batch_dir(
+    ctx: &ApiContext,
+    user_id: Uuid,
+    ws_id: Uuid,
+    parent_dir_id: i64,
+    components: &[String],
+    dirs: &mut HashMap<Vec<String>, i64>,
+) -> Result<i64> {
+    if let Some(dir_id) = dirs.get(components) {
+        return Ok(*dir_id);
+    }
+    let dir_id = CloudPath::mkdir_all(user_id, ws_id, parent_dir_id, components, &ctx.snowflake, &ctx.db).await?;
+    dirs.insert(components.to_vec(), dir_id);
+    Ok(dir_id)
+}
+
+async fn store_batch_entry(
+    ctx: &ApiContext,
+    user_id: Uuid,
+    ws_id: Uuid,
+    batch_upload_req: &BatchUploadReq,
+    path: &str,
+    field: Field<'_>,
+    dirs: &mut HashMap<Vec<String>, i64>,
+) -> Result<DbFile> {
+    let cloud_path = CloudPath::parse(path)?;
+    validation::check_path("path", cloud_path.components(), &ctx.config)?;
+    let filename = cloud_path.filename().ok_or(CoreError::InvalidPath)?.to_string();
+    let is_dir = path.ends_with('/');
+    let dir_components = match is_dir {
+        true => cloud_path.components(),
+        false => cloud_path.parent(),
+    };
+
+    let dir_id = resolve_batch_dir(ctx, user_id, ws_id, batch_upload_req.parent_dir_id, dir_components, dirs).await?;
+    if is_dir {
+        return DbFile::check_owner(user_id, dir_id, ws_id, &ctx.db).await?.ok_or(CustomError::NotFound);
+    }
+
+    let id = ctx.snowflake.lock().unwrap().next_id();
+    let db_file = DbFile::new(id, user_id, ws_id, filename, dir_id, 0, false);
+    Ok(store_stream(ctx, field, db_file, batch_upload_req.conflict).await?.file())
+}
+
+// reject a body larger than `max_upload_size` before reading it
+pub(crate) fn check_content_length(headers: &HeaderMap, ctx: &ApiContext) -> Result<()> {
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    match content_length {
+        Some(content_length) if content_length > ctx.config.max_upload_size => Err(CustomError::PayloadTooLarge),
+        _ => Ok(()),
+    }
+}
+
+/// Cut the body into blocks and write them while it arrives, then insert `db_file`
+/// following `policy`. The body is never held in memory as a whole, and the written
+/// blocks are deleted if the upload fails or the existing file is kept.
+pub(crate) async fn store_stream<S, E>(
+    ctx: &ApiContext,
+    mut stream: S,
+    mut db_file: DbFile,
+    policy: ConflictPolicy,
+) -> Result<Inserted>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    // don't take the whole body to find out it isn't wanted
+    let existing = DbFile::find_by_name(db_file.ws_id, db_file.parent_dir_id, &db_file.filename, &ctx.db).await?;
+    match (policy, existing) {
+        (ConflictPolicy::Fail, Some(_)) => return Err(CustomError::Conflict),
+        (ConflictPolicy::Skip, Some(existing)) => return Ok(Inserted::Skipped(existing)),
+        _ => {},
+    }
+
+    let mut writer = BlockingWriter::new(ctx, ctx.config.max_upload_size as usize);
+
+    let result = async {
+        while let Some(bytes) = stream.next().await {
+            let bytes = bytes.map_err(|e| anyhow::anyhow!("failed to read the upload: {}", e))?;
+            writer.write(bytes).await?;
+        }
+        let written = writer.finish().await?;
+        db_file.size = written.size as i64;
+        let content = NewContent {
+            blocks_name: written.blocks_name,
+            blocks_hash: written.blocks_hash,
+            file_hash: Some(written.hash),
+        };
+        insert_with_policy(ctx, db_file, Some(content), policy).await
+    }.await;
+
+    if !matches!(result, Ok(Inserted::Created(_)) | Ok(Inserted::Overwritten(_))) {
+        writer.abort().await;
+    }
+    result
+}
+
+/// A `CloudWriter` writing its block files on the blocking threads, so an upload
+/// doesn't hold up the async workers.
+pub(crate) struct BlockingWriter(Option<CloudWriter>);
+
+impl BlockingWriter {
+    pub(crate) fn new(ctx: &ApiContext, max_size: usize) -> Self {
+        Self(Some(CloudWriter::new(ctx.fs_handler.clone(), max_size)))
+    }
+
+    async fn run<T, F>(&mut self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut CloudWriter) -> T + Send + 'static,
+    {
+        let mut writer = self.0.take().ok_or_else(|| anyhow::anyhow!("the writer was lost to a panic"))?;
+        let (writer, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut writer);
+            (writer, result)
+        }).await.map_err(anyhow::Error::from)?;
+        self.0 = Some(writer);
+        Ok(result)
+    }
+
+    pub(crate) async fn write(&mut self, bytes: Bytes) -> Result<()> {
+        Ok(self.run(move |writer| writer.write(&bytes)).await??)
+    }
+
+    pub(crate) async fn finish(&mut self) -> Result<WrittenFile> {
+        Ok(self.run(|writer| writer.finish()).await??)
+    }
+
+    /// delete all blocks written so far
+    pub(crate) async fn abort(&mut self) {
+        if let Err(e) = self.run(|writer| writer.abort()).await {
+            log::error!("failed to abort an upload: {:?}", e);
+        }
+    }
+}
+
+/// Blocks and hashes of the content of a new file
+pub(crate) struct NewContent {
+    pub(crate) blocks_name: Vec<String>,
+    pub(crate) blocks_hash: Vec<String>,
+    pub(crate) file_hash: Option<String>,
+}
+
+/// What became of a new file or dir under its `ConflictPolicy`
+pub(crate) enum Inserted {
+    Created(DbFile),
+    /// the content is the new version of the existing file
+    Overwritten(DbFile),
+    /// the existing file or dir is kept, the new content isn't used
+    Skipped(DbFile),
+}
+
+impl Inserted {
+    pub(crate) fn file(self) -> DbFile {
+        match self {
+            Self::Created(db_file) | Self::Overwritten(db_file) | Self::Skipped(db_file) => db_file,
+        }
+    }
+
+    pub(crate) fn is_skipped(&self) -> bool {
+        matches!(self, Self::Skipped(_))
+    }
+}
+
+// "report.pdf" => "report (n).pdf", a name without extension or starting with a dot gets
+// the number at the end. The stem is cut short if the name would get too long.
+fn numbered_name(filename: &str, n: usize) -> String {
+    let (stem, ext) = match filename.rfind('.') {
+        Some(dot) if dot > 0 => filename.split_at(dot),
+        _ => (filename, ""),
+    };
+    let number = format!(" ({})", n);
+    let mut stem_len = validation::MAX_NAME_BYTES.saturating_sub(number.len() + ext.len()).min(stem.len());
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+    format!("{}{}{}", &stem[..stem_len], number, ext)
+}
+
+/// Insert `db_file`, a file with `content` or a dir without. A name collision is
+/// caught by the unique constraint and resolved by `policy`.
+pub(crate) async fn insert_with_policy(
+    ctx: &ApiContext,
+    mut db_file: DbFile,
+    content: Option<NewContent>,
+    policy: ConflictPolicy,
+) -> Result<Inserted> {
+    // checked again, the upload may have been started before the quota was lowered
+    if content.is_some() {
+        check_quota(db_file.uid, db_file.size as u64, ctx).await?;
+    }
+    let filename = db_file.filename.clone();
+    for attempt in 0..=MAX_RENAME_ATTEMPTS {
+        if attempt > 0 {
+            db_file.filename = numbered_name(&filename, attempt);
+        }
+        let inserted = match &content {
+            Some(content) => db_file
+                .insert_file(content.blocks_name.clone(), content.blocks_hash.clone(),
+                             content.file_hash.as_deref(), &ctx.db)
+                .await,
+            None => db_file.insert_dir(&ctx.db).await.map(|_| ()),
+        };
+        match inserted.on_constraint(FILES_NAME_KEY, |_| CustomError::Conflict) {
+            Ok(()) => return Ok(Inserted::Created(db_file)),
+            Err(CustomError::Conflict) if policy == ConflictPolicy::Rename => continue,
+            Err(CustomError::Conflict) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // the existing entry may be gone again in the meantime
+    let existing = match policy {
+        ConflictPolicy::Skip | ConflictPolicy::Overwrite => {
+            DbFile::find_by_name(db_file.ws_id, db_file.parent_dir_id, &filename, &ctx.db).await?
+        },
+        _ => None,
+    };
+    match (policy, existing, content) {
+        (ConflictPolicy::Skip, Some(existing), _) => Ok(Inserted::Skipped(existing)),
+        // a dir has nothing to overwrite, the existing one is used
+        (ConflictPolicy::Overwrite, Some(existing), None) if existing.is_dir => Ok(Inserted::Skipped(existing)),
+        (ConflictPolicy::Overwrite, Some(existing), Some(content)) if !existing.is_dir => {
+            let updated = existing.update_file_version(content.blocks_name, content.blocks_hash, db_file.size,
+                                                       content.file_hash.as_deref(), &ctx.db).await?;
+            Ok(Inserted::Overwritten(updated))
+        },
+        _ => Err(CustomError::Conflict),
+    }
+}
+
+/// Move `src` into `target_dir_id` under `filename`, a name collision is resolved by
+/// `policy`. Overwriting makes the content of `src` the new version of the existing
+/// file and deletes `src`, dirs are never overwritten.
+pub(crate) async fn move_with_policy(
+    ctx: &ApiContext,
+    src: DbFile,
+    target_dir_id: i64,
+    filename: &str,
+    policy: ConflictPolicy,
+) -> Result<DbFile> {
+    for attempt in 0..=MAX_RENAME_ATTEMPTS {
+        let name = match attempt {
+            0 => filename.to_string(),
+            n => numbered_name(filename, n),
+        };
+        match src.move_to(target_dir_id, &name, &ctx.db).await.on_constraint(FILES_NAME_KEY, |_| CustomError::Conflict) {
+            Ok(moved) => return Ok(moved),
+            Err(CustomError::Conflict) if policy == ConflictPolicy::Rename => continue,
+            Err(CustomError::Conflict) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let existing = match policy {
+        ConflictPolicy::Skip | ConflictPolicy::Overwrite => {
+            DbFile::find_by_name(src.ws_id, target_dir_id, filename, &ctx.db).await?
+        },
+        _ => None,
+    };
+    match (policy, existing) {
+        (ConflictPolicy::Skip, Some(existing)) => Ok(existing),
+        (ConflictPolicy::Overwrite, Some(existing)) if !existing.is_dir && !src.is_dir => {
+            Ok(existing.replace_with(&src, &ctx.db).await?)
+        },
+        _ => Err(CustomError::Conflict),
+    }
+}
+
+// the first numbered name which is free in the dir
+async fn free_name(ctx: &ApiContext, ws_id: Uuid, parent_dir_id: i64, filename: &str) -> Result<String> {
+    for n in 1..=MAX_RENAME_ATTEMPTS {
+        let name = numbered_name(filename, n);
+        if DbFile::find_by_name(ws_id, parent_dir_id, &name, &ctx.db).await?.is_none() {
+            return Ok(name);
+        }
+    }
+    Err(CustomError::Conflict)
+}
+
 // check if file exists and owned by this user
 async fn update_file_info(
     auth_user: AuthUser,
@@ -264,20 +998,187 @@ async fn update_file_info(
     Path((ws_id, id)): Path<(Uuid, i64)>,
     update_file_req: Json<UpdateFileReq>
 ) -> Result<Json<StorageBody<Storage>>> {
+    validation::check_name("filename", &update_file_req.filename, &ctx.config)?;
     let db_file = check_file_owner(auth_user.user_id, id, ws_id, &ctx).await?;
-    db_file.update_file_info(&update_file_req.filename, &ctx.db).await?;
+    // the name is stored normalized
+    let db_file = db_file.update_file_info(&update_file_req.filename, &ctx.db)
+        .await
+        .on_constraint(FILES_NAME_KEY, |_| CustomError::Conflict)?;
 
     Ok(Json(StorageBody {
-        storage: Storage::new(db_file.id, update_file_req.filename.clone(), db_file.is_dir, db_file.parent_dir_id, db_file.size as usize)
+        storage: Storage::new(db_file.id, db_file.filename.clone(), db_file.is_dir, db_file.parent_dir_id, db_file.disk_usage() as usize)
     }))
 }
 
+// move file or dir to another dir of the same workspace, optionally renaming it
+async fn move_storage(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Path((ws_id, id)): Path<(Uuid, i64)>,
+    move_file_req: Json<MoveFileReq>
+) -> Result<Json<StorageBody<Storage>>> {
+    let db_file = check_file_owner(auth_user.user_id, id, ws_id, &ctx).await?;
+
+    // the current name is kept as it is, even if it predates the rules
+    let filename = match &move_file_req.filename {
+        Some(filename) => {
+            validation::check_name("filename", filename, &ctx.config)?;
+            filename.as_str()
+        },
+        None => db_file.filename.as_str(),
+    };
+
+    let filename = filename.to_string();
+    let db_file = move_with_policy(&ctx, db_file, move_file_req.parent_dir_id, &filename, move_file_req.conflict).await?;
+
+    Ok(Json(StorageBody {
+        storage: Storage::new(db_file.id, db_file.filename.clone(), db_file.is_dir, db_file.parent_dir_id, db_file.disk_usage() as usize)
+    }))
+}
+
+// copy file or dir tree in background, optionally into another workspace of the user
+// the progress can be checked with the returned job id
+async fn copy_storage(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Path((ws_id, id)): Path<(Uuid, i64)>,
+    copy_file_req: Json<CopyFileReq>
+) -> Result<Json<CopyJob>> {
+    let db_file = check_file_owner(auth_user.user_id, id, ws_id, &ctx).await?;
+
+    let target_ws_id = copy_file_req.ws_id.unwrap_or(ws_id);
+    auth_user.check_ws(target_ws_id)?;
+    let target_dir = check_permission(auth_user.user_id, copy_file_req.parent_dir_id, target_ws_id, &ctx).await?;
+    if !target_dir.is_dir {
+        return Err(CustomError::unprocessable_entity([("parent_dir_id", "is not a directory")]));
+    }
+    check_quota(auth_user.user_id, db_file.disk_usage() as u64, &ctx).await?;
+
+    if let Some(filename) = &copy_file_req.filename {
+        validation::check_name("filename", filename, &ctx.config)?;
+    }
+    let filename = copy_file_req.filename.clone().unwrap_or_else(|| db_file.filename.clone());
+
+    // a collision is resolved before the job starts, a name taken while it runs fails the job
+    let existing = DbFile::find_by_name(target_ws_id, copy_file_req.parent_dir_id, &filename, &ctx.db).await?;
+    let (filename, done) = match (copy_file_req.conflict, existing) {
+        (_, None) => (filename, None),
+        (ConflictPolicy::Rename, Some(_)) => {
+            (free_name(&ctx, target_ws_id, copy_file_req.parent_dir_id, &filename).await?, None)
+        },
+        (ConflictPolicy::Skip, Some(existing)) => (filename, Some((existing, 0))),
+        (ConflictPolicy::Overwrite, Some(existing)) if !existing.is_dir && !db_file.is_dir => {
+            let history = FileHistories::find_by_fid(db_file.id, &ctx.db).await?;
+            let updated = existing.update_file_version(history.slices, history.slices_hash, db_file.size,
+                                                       history.file_hash.as_deref(), &ctx.db).await?;
+            (filename, Some((updated, 1)))
+        },
+        _ => return Err(CustomError::Conflict),
+    };
+
+    let mut copy_job = CopyJob {
+        job_id: Uuid::now_v7(),
+        user_id: auth_user.user_id,
+        status: "running".to_string(),
+        total: 0,
+        copied: 0,
+        id: None,
+    };
+    if let Some((target, copied)) = &done {
+        copy_job.status = "done".to_string();
+        (copy_job.copied, copy_job.total) = (*copied, 1);
+        copy_job.id = Some(target.id.to_string());
+    }
+    let redis_client = Arc::clone(&ctx.redis_client);
+    let mut conn = redis_client.get_multiplexed_async_connection().await?;
+    conn.set_ex::<_, _, ()>(copy_job_key(copy_job.job_id), serde_json::to_string(&copy_job)?, COPY_JOB_TTL).await?;
+    if done.is_some() {
+        return Ok(Json(copy_job));
+    }
+
+    let cloud_copy = CloudCopy::new(auth_user.user_id, target_ws_id, copy_file_req.parent_dir_id, filename);
+    tokio::spawn(run_copy_job(ctx.0.clone(), conn, copy_job.clone(), db_file, cloud_copy));
+
+    Ok(Json(copy_job))
+}
+
+async fn run_copy_job(
+    ctx: ApiContext,
+    mut conn: redis::aio::MultiplexedConnection,
+    mut copy_job: CopyJob,
+    src: DbFile,
+    cloud_copy: CloudCopy,
+) {
+    let key = copy_job_key(copy_job.job_id);
+    let mut progress = (0, 0);
+    let result = cloud_copy.copy_tree(&src, &ctx.snowflake, &ctx.db, |copied, total| {
+        progress = (copied, total);
+        let mut conn = conn.clone();
+        let key = key.clone();
+        let value = match copied % COPY_PROGRESS_STEP == 0 || copied == total {
+            true => serde_json::to_string(&CopyJob { total, copied, ..copy_job.clone() }).ok(),
+            false => None,
+        };
+        async move {
+            if let Some(value) = value {
+                if let Err(e) = conn.set_ex::<_, _, ()>(key, value, COPY_JOB_TTL).await {
+                    log::error!("failed to update copy job progress: {:?}", e);
+                }
+            }
+        }
+    }).await;
+
+    (copy_job.copied, copy_job.total) = progress;
+    match result {
+        Ok(copy) => {
+            copy_job.status = "done".to_string();
+            copy_job.id = Some(copy.id.to_string());
+        },
+        Err(e) => {
+            log::error!("copy job {} failed: {:?}", copy_job.job_id, e);
+            copy_job.status = "failed".to_string();
+        }
+    }
+
+    let value = match serde_json::to_string(&copy_job) {
+        Ok(value) => value,
+        Err(e) => {
+            log::error!("failed to serialize copy job {}: {:?}", copy_job.job_id, e);
+            return;
+        }
+    };
+    if let Err(e) = conn.set_ex::<_, _, ()>(key, value, COPY_JOB_TTL).await {
+        log::error!("failed to update copy job {}: {:?}", copy_job.job_id, e);
+    }
+}
+
+async fn get_copy_job(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<CopyJob>> {
+    let redis_client = Arc::clone(&ctx.redis_client);
+    let mut conn = redis_client.get_async_connection().await?;
+    let value: Option<String> = conn.get(copy_job_key(job_id)).await?;
+    let copy_job: CopyJob = serde_json::from_str(&value.ok_or(CustomError::NotFound)?)?;
+
+    if copy_job.user_id != auth_user.user_id {
+        return Err(CustomError::Forbidden);
+    }
+
+    Ok(Json(copy_job))
+}
+
+fn copy_job_key(job_id: Uuid) -> String {
+    format!("copy_job_{}", job_id)
+}
+
 async fn list_storage(
     ctx: Extension<ApiContext>,
     auth_user: AuthUser,
     Path(ws_id): Path<Uuid>,
     Query(list_storage_req): Query<ListStorageReq>
-) -> Result<Json<Vec<StorageBody<Storage>>>> {
+) -> Result<Json<ListStorageResp>> {
     let dir_id = list_storage_req.parent_dir_id.unwrap_or(ROOT_DIR_ID);
 
     let dir = check_permission(auth_user.user_id, dir_id, ws_id, &ctx).await?;
@@ -286,26 +1187,118 @@ async fn list_storage(
         return Err(CustomError::NotFound);
     }
 
-    let files = DbFile::get_by_parent_dir_id_and_uid(dir_id, auth_user.user_id, &ctx.db).await?;
+    let limit = list_storage_req.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit <= 0 || limit > MAX_PAGE_SIZE {
+        return Err(CustomError::unprocessable_entity([("limit", "must be between 1 and 1000")]));
+    }
+    let cursor = match &list_storage_req.cursor {
+        Some(cursor) => Some(cursor.parse::<i64>().map_err(|_| CustomError::BadRequest)?),
+        None => None,
+    };
+    let is_dir = match list_storage_req.file_type.as_deref() {
+        Some("dir") => Some(true),
+        Some("file") => Some(false),
+        Some(_) => return Err(CustomError::unprocessable_entity([("type", "must be file or dir")])),
+        None => None,
+    };
+
+    let query = ListQuery {
+        sort_by: list_storage_req.sort.unwrap_or_default(),
+        order: list_storage_req.order.unwrap_or_default(),
+        dirs_first: list_storage_req.dirs_first.unwrap_or(false),
+        is_dir,
+        ext: list_storage_req.ext.clone(),
+        modified_after: list_storage_req.modified_after,
+        modified_before: list_storage_req.modified_before,
+        cursor,
+        // fetch one more entry to know if there is a next page
+        limit: limit + 1,
+    };
+    let (mut files, total) = DbFile::list_dir(ws_id, dir_id, auth_user.user_id, &query, &ctx.db)
+        .await?
+        .ok_or_else(|| CustomError::unprocessable_entity([("cursor", "is not an entry of the dir")]))?;
+    let next_cursor = match files.len() as i64 > limit {
+        true => {
+            files.truncate(limit as usize);
+            files.last().map(|file| file.id.to_string())
+        },
+        false => None,
+    };
+    let dir_path = DbFile::get_path(dir_id, &ctx.db).await?;
 
     let mut storages = Vec::new();
     for file in files {
+        let path = CloudPath::join(&dir_path, &file.filename);
         storages.push(
             StorageBody {
                 storage: Storage::new(
                     file.id,
-                    file.filename,
+                    file.filename.clone(),
                     file.is_dir,
                     file.parent_dir_id,
-                    file.size as usize
-                )
+                    file.disk_usage() as usize
+                ).with_path(path)
             }
         )
     }
 
-    Ok(Json(storages))
+    Ok(Json(ListStorageResp {
+        storages,
+        total,
+        next_cursor,
+    }))
+}
+
+
+// get the tree below a dir with the total size and the number of files of every entry,
+// with id -1 it's the disk usage of the whole workspace
+async fn get_storage_tree(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path((ws_id, id)): Path<(Uuid, i64)>,
+    Query(tree_req): Query<TreeReq>
+) -> Result<Json<TreeNode>> {
+    let depth = tree_req.depth.unwrap_or(1);
+    if !(1..=MAX_TREE_DEPTH).contains(&depth) {
+        return Err(CustomError::unprocessable_entity([("depth", format!("must be between 1 and {}", MAX_TREE_DEPTH))]));
+    }
+
+    let mut dir = check_permission(auth_user.user_id, id, ws_id, &ctx).await?;
+    if !dir.is_dir {
+        return Err(CustomError::NotFound);
+    }
+    if id == ROOT_DIR_ID {
+        (dir.total_size, dir.file_count) = DbFile::get_root_usage(ws_id, &ctx.db).await?;
+    }
+
+    let mut children: HashMap<i64, Vec<DbFile>> = HashMap::new();
+    for file in DbFile::get_subtree(ws_id, id, depth, &ctx.db).await? {
+        children.entry(file.parent_dir_id).or_default().push(file);
+    }
+
+    Ok(Json(build_tree(dir, &mut children)))
 }
 
+fn build_tree(file: DbFile, children: &mut HashMap<i64, Vec<DbFile>>) -> TreeNode {
+    let nodes = children
+        .remove(&file.id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|child| build_tree(child, children))
+        .collect();
+
+    TreeNode {
+        file_count: file.usage().1.to_string(),
+        storage: Storage::new(
+            file.id,
+            file.filename.clone(),
+            file.is_dir,
+            file.parent_dir_id,
+            file.disk_usage() as usize
+        ),
+        children: nodes,
+    }
+}
 
 // download file
 async fn get_storage(
@@ -314,16 +1307,17 @@ async fn get_storage(
     Path((ws_id, id)): Path<(Uuid, i64)>,
 ) -> Result<Json<StorageBody<Storage>>> {
     let db_file = check_file_owner(auth_user.user_id, id, ws_id, &ctx).await?;
+    let path = DbFile::get_path(db_file.id, &ctx.db).await?;
 
     Ok(Json(
         StorageBody {
             storage: Storage::new(
                 db_file.id,
-                db_file.filename,
+                db_file.filename.clone(),
                 db_file.is_dir,
                 db_file.parent_dir_id,
-                db_file.size as usize
-            )
+                db_file.disk_usage() as usize
+            ).with_path(path)
         }
     ))
 }
@@ -0,0 +1,99 @@
+use axum::extract::{Extension, Path};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use crate::api::extractor::{self, AuthUser, API_TOKEN_PREFIX};
+use crate::api::{workspaces, ApiContext, Result, error::CustomError};
+use crate::api_common::tokens::{ApiToken, CreateTokenReq, TokenBody, TokenScope};
+use cloud_core::db_schema::api_tokens::ApiTokens;
+use rand::RngCore;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// the start of a token shown in lists, the prefix and a few random characters
+const SHOWN_PREFIX_LEN: usize = API_TOKEN_PREFIX.len() + 6;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/tokens", get(list_tokens).post(create_token))
+        .route("/api/tokens/:id", delete(revoke_token))
+}
+
+fn to_api_token(token: ApiTokens, secret: Option<String>) -> ApiToken {
+    ApiToken {
+        id: token.id,
+        name: token.name,
+        prefix: token.prefix,
+        scopes: token.scopes.iter().filter_map(|scope| TokenScope::parse(scope)).collect(),
+        ws_id: token.ws_id,
+        created_at: token.created_at,
+        expires_at: token.expires_at,
+        last_used_at: token.last_used_at,
+        secret,
+    }
+}
+
+// the token is only returned here, afterwards it can only be revoked
+async fn create_token(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Json(req): Json<TokenBody<CreateTokenReq>>,
+) -> Result<Json<TokenBody<ApiToken>>> {
+    let req = req.token;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    if req.name.trim().is_empty() {
+        return Err(CustomError::unprocessable_entity([("name", "must not be empty")]));
+    }
+    if req.scopes.is_empty() {
+        return Err(CustomError::unprocessable_entity([("scopes", "must not be empty")]));
+    }
+    if req.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(CustomError::unprocessable_entity([("expires_at", "must be in the future")]));
+    }
+    if let Some(ws_id) = req.ws_id {
+        workspaces::check_ws_owner(auth_user.user_id, ws_id, &ctx).await?;
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = format!("{}{}", API_TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+
+    let mut scopes = req.scopes.iter().map(|scope| scope.as_str().to_string()).collect::<Vec<_>>();
+    scopes.sort();
+    scopes.dedup();
+    let token = ApiTokens {
+        id: Uuid::now_v7(),
+        user_id: auth_user.user_id,
+        name: req.name,
+        token_hash: extractor::api_token_hash(&secret),
+        prefix: secret[..SHOWN_PREFIX_LEN].to_string(),
+        scopes,
+        ws_id: req.ws_id,
+        created_at: now,
+        expires_at: req.expires_at,
+        last_used_at: None,
+    };
+    token.insert(&ctx.db).await?;
+
+    Ok(Json(TokenBody { token: to_api_token(token, Some(secret)) }))
+}
+
+async fn list_tokens(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+) -> Result<Json<Vec<TokenBody<ApiToken>>>> {
+    let tokens = ApiTokens::list(auth_user.user_id, &ctx.db).await?;
+    Ok(Json(tokens.into_iter().map(|token| TokenBody { token: to_api_token(token, None) }).collect()))
+}
+
+async fn revoke_token(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Path(id): Path<Uuid>,
+) -> Result<()> {
+    if !ApiTokens::delete(id, auth_user.user_id, &ctx.db).await? {
+        return Err(CustomError::NotFound);
+    }
+    Ok(())
+}
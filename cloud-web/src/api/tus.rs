@@ -0,0 +1,340 @@
+// tus 1.0 resumable uploads, see https://tus.io/protocols/resumable-upload
+//
+// A tus upload is an upload session whose chunks are appended in order,
+// every PATCH body is cut into blocks which become the next chunks.
+// The offset of an upload is the total size of its chunks.
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+use axum::extract::{BodyStream, Extension, Path};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{head, post};
+use axum::Router;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use cloud_core::block::BlockHandler;
+use cloud_core::db_schema::files::Files as DbFile;
+use futures::StreamExt;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use uuid::Uuid;
+use crate::api::{error::CustomError, extractor::AuthUser, storages, validation, ApiContext, Result};
+use crate::api::session_store::UploadSession;
+use crate::api::storages::{BlockingWriter, NewContent};
+use crate::api_common::storages::{BlockInfo, ConflictPolicy, SessionInfo};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,checksum,termination";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+const TUS_CONTENT_TYPE: &str = "application/offset+octet-stream";
+// returned when Upload-Checksum doesn't match the received data
+const CHECKSUM_MISMATCH: u16 = 460;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/tus", post(create_upload).options(tus_options))
+        .route("/api/tus/:id", head(get_offset)
+            .patch(append_upload).delete(terminate_upload).options(tus_options))
+}
+
+fn tus_response(status: StatusCode, headers: Vec<(&'static str, String)>) -> Response {
+    let mut header_map = HeaderMap::new();
+    header_map.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            header_map.insert(name, value);
+        }
+    }
+    (status, header_map).into_response()
+}
+
+// every request but OPTIONS must use the version we speak
+fn check_tus_resumable(headers: &HeaderMap) -> Option<Response> {
+    match headers.get("tus-resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => None,
+        _ => Some(tus_response(StatusCode::PRECONDITION_FAILED, vec![("tus-version", TUS_VERSION.to_string())])),
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse::<u64>().ok()
+}
+
+fn http_date(unix_timestamp: i64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(unix_timestamp.max(0) as u64))
+}
+
+// Upload-Metadata is a comma separated list of `key base64(value)`, the value may be missing
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next()?.to_string();
+        let value = match parts.next() {
+            Some(value) => String::from_utf8(BASE64.decode(value.trim()).ok()?).ok()?,
+            None => String::new(),
+        };
+        metadata.insert(key, value);
+    }
+    Some(metadata)
+}
+
+fn upload_offset(session: &UploadSession) -> u64 {
+    session.chunks.iter().map(|chunk| chunk.block_size as u64).sum()
+}
+
+async fn tus_options(ctx: Extension<ApiContext>) -> Response {
+    tus_response(StatusCode::NO_CONTENT, vec![
+        ("tus-version", TUS_VERSION.to_string()),
+        ("tus-extension", TUS_EXTENSIONS.to_string()),
+        ("tus-max-size", ctx.config.max_upload_size.to_string()),
+        ("tus-checksum-algorithm", TUS_CHECKSUM_ALGORITHMS.to_string()),
+    ])
+}
+
+// creation extension, the target comes from the metadata:
+// filename (or name), ws_id and optionally parent_dir_id and conflict
+async fn create_upload(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if let Some(response) = check_tus_resumable(&headers) {
+        return Ok(response);
+    }
+    // the length must be known, Upload-Defer-Length isn't supported
+    let total_size = header_u64(&headers, "upload-length").ok_or(CustomError::BadRequest)?;
+    if total_size > ctx.config.max_upload_size {
+        return Err(CustomError::PayloadTooLarge);
+    }
+
+    let metadata = match headers.get("upload-metadata") {
+        Some(value) => parse_metadata(value.to_str().map_err(|_| CustomError::BadRequest)?)
+            .ok_or(CustomError::BadRequest)?,
+        None => HashMap::new(),
+    };
+    let filename = metadata.get("filename")
+        .or_else(|| metadata.get("name"))
+        .filter(|filename| !filename.is_empty())
+        .ok_or(CustomError::BadRequest)?
+        .clone();
+    validation::check_name("filename", &filename, &ctx.config)?;
+    let ws_id = metadata.get("ws_id")
+        .and_then(|ws_id| Uuid::parse_str(ws_id).ok())
+        .ok_or(CustomError::BadRequest)?;
+    let parent_dir_id = match metadata.get("parent_dir_id") {
+        Some(parent_dir_id) => parent_dir_id.parse::<i64>().map_err(|_| CustomError::BadRequest)?,
+        None => -1,
+    };
+    let conflict = match metadata.get("conflict") {
+        Some(conflict) => ConflictPolicy::parse(conflict).ok_or(CustomError::BadRequest)?,
+        None => ConflictPolicy::Fail,
+    };
+    auth_user.check_ws(ws_id)?;
+    storages::check_permission(auth_user.user_id, parent_dir_id, ws_id, &ctx).await?;
+    storages::check_quota(auth_user.user_id, total_size, &ctx).await?;
+
+    let session_id = Uuid::now_v7();
+    let session_info = SessionInfo {
+        user_id: auth_user.user_id,
+        ws_id,
+        filename,
+        parent_dir_id,
+        total_size,
+        file_hash: String::new(),
+        file_id: None,
+        conflict,
+    };
+    let expires_at = storages::session_deadline(&ctx);
+    ctx.upload_sessions.create(session_id, &session_info, expires_at).await?;
+
+    // an empty file is complete as soon as it's created
+    if total_size == 0 {
+        finish_upload(session_id, &ctx).await?;
+    }
+
+    Ok(tus_response(StatusCode::CREATED, vec![
+        ("location", format!("/api/tus/{}", session_id)),
+        ("upload-expires", http_date(expires_at)),
+    ]))
+}
+
+async fn get_offset(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if let Some(response) = check_tus_resumable(&headers) {
+        return Ok(response);
+    }
+    let session = storages::get_user_session(session_id, auth_user.user_id, &ctx).await?;
+
+    Ok(tus_response(StatusCode::OK, vec![
+        ("upload-offset", upload_offset(&session).to_string()),
+        ("upload-length", session.info.total_size.to_string()),
+        ("upload-expires", http_date(session.expires_at)),
+        ("cache-control", "no-store".to_string()),
+    ]))
+}
+
+enum Checksum {
+    Sha1(Vec<u8>),
+    Sha256(Vec<u8>),
+}
+
+// Upload-Checksum is `algorithm base64(digest)`
+fn parse_checksum(headers: &HeaderMap) -> Result<Option<Checksum>> {
+    let value = match headers.get("upload-checksum") {
+        Some(value) => value.to_str().map_err(|_| CustomError::BadRequest)?,
+        None => return Ok(None),
+    };
+    let (algorithm, digest) = value.split_once(' ').ok_or(CustomError::BadRequest)?;
+    let digest = BASE64.decode(digest.trim()).map_err(|_| CustomError::BadRequest)?;
+    match algorithm {
+        "sha1" => Ok(Some(Checksum::Sha1(digest))),
+        "sha256" => Ok(Some(Checksum::Sha256(digest))),
+        _ => Err(CustomError::BadRequest),
+    }
+}
+
+async fn append_upload(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    headers: HeaderMap,
+    mut stream: BodyStream,
+) -> Result<Response> {
+    if let Some(response) = check_tus_resumable(&headers) {
+        return Ok(response);
+    }
+    if headers.get("content-type").and_then(|v| v.to_str().ok()) != Some(TUS_CONTENT_TYPE) {
+        return Ok(tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, vec![]));
+    }
+    let offset = header_u64(&headers, "upload-offset").ok_or(CustomError::BadRequest)?;
+    let checksum = parse_checksum(&headers)?;
+
+    let session = storages::get_user_session(session_id, auth_user.user_id, &ctx).await?;
+    let current_offset = upload_offset(&session);
+    if offset != current_offset {
+        return Ok(tus_response(StatusCode::CONFLICT, vec![]));
+    }
+
+    let remaining = (session.info.total_size - current_offset) as usize;
+    let mut writer = BlockingWriter::new(&ctx, remaining);
+    let mut sha1 = Sha1::new();
+    let mut sha256 = Sha256::new();
+    let mut read_error = None;
+    while let Some(bytes) = stream.next().await {
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                read_error = Some(e);
+                break;
+            }
+        };
+        sha1.update(&bytes);
+        sha256.update(&bytes);
+        if let Err(e) = writer.write(bytes).await {
+            writer.abort().await;
+            return Err(e);
+        }
+    }
+    let written = match writer.finish().await {
+        Ok(written) => written,
+        Err(e) => {
+            writer.abort().await;
+            return Err(e);
+        }
+    };
+
+    // with a checksum only a complete and matching body is kept,
+    // without one whatever was received before an error is kept
+    if let Some(checksum) = checksum {
+        let matched = match checksum {
+            Checksum::Sha1(digest) => sha1.finalize().as_slice() == digest.as_slice(),
+            Checksum::Sha256(digest) => sha256.finalize().as_slice() == digest.as_slice(),
+        };
+        if read_error.is_some() || !matched {
+            writer.abort().await;
+            let status = StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap();
+            return Ok(tus_response(status, vec![]));
+        }
+    }
+
+    // another request may have appended to the upload meanwhile, the chunks are only
+    // added if the session still has the ones the offset was checked with
+    let start_index = session.chunks.len();
+    let expires_at = storages::session_deadline(&ctx);
+    let chunks: Vec<BlockInfo> = written.blocks_name.iter().enumerate().map(|(i, block_name)| BlockInfo {
+        block_name: block_name.clone(),
+        block_index: start_index + i,
+        block_size: written.blocks_size[i],
+        block_hash: written.blocks_hash[i].clone(),
+        shared: false,
+    }).collect();
+    match ctx.upload_sessions.append_chunks(session_id, start_index, &chunks, expires_at).await {
+        Ok(true) => {},
+        Ok(false) => {
+            writer.abort().await;
+            return Ok(tus_response(StatusCode::CONFLICT, vec![]));
+        }
+        Err(e) => {
+            writer.abort().await;
+            return Err(e);
+        }
+    }
+
+    if let Some(e) = read_error {
+        log::warn!("tus upload {} interrupted: {}", session_id, e);
+        return Err(CustomError::BadRequest);
+    }
+
+    let new_offset = current_offset + written.size as u64;
+    if new_offset == session.info.total_size {
+        finish_upload(session_id, &ctx).await?;
+    }
+
+    Ok(tus_response(StatusCode::NO_CONTENT, vec![
+        ("upload-offset", new_offset.to_string()),
+        ("upload-expires", http_date(expires_at)),
+    ]))
+}
+
+// termination extension
+async fn terminate_upload(
+    ctx: Extension<ApiContext>,
+    auth_user: AuthUser,
+    Path(session_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if let Some(response) = check_tus_resumable(&headers) {
+        return Ok(response);
+    }
+    storages::get_user_session(session_id, auth_user.user_id, &ctx).await?;
+    storages::discard_session(session_id, &ctx).await?;
+    Ok(tus_response(StatusCode::NO_CONTENT, vec![]))
+}
+
+// turn a completely received upload into a file
+async fn finish_upload(session_id: Uuid, ctx: &ApiContext) -> Result<()> {
+    let session = ctx.upload_sessions.take(session_id).await?.ok_or(CustomError::NotFound)?;
+    let blocks_name = session.chunks.iter().map(|chunk| chunk.block_name.clone()).collect();
+    let blocks_hash = session.chunks.iter().map(|chunk| chunk.block_hash.clone()).collect();
+    let id = ctx.snowflake.lock().unwrap().next_id();
+
+    let db_file = DbFile::new(id, session.info.user_id, session.info.ws_id, session.info.filename.clone(),
+                              session.info.parent_dir_id, session.info.total_size as i64, false);
+    let content = NewContent { blocks_name, blocks_hash, file_hash: None };
+    match storages::insert_with_policy(ctx, db_file, Some(content), session.info.conflict).await {
+        // the existing file is kept, the uploaded blocks are not needed
+        Ok(inserted) if inserted.is_skipped() => ctx.fs_handler.delete_blocks(storages::session_blocks(&session))?,
+        Ok(_) => {},
+        Err(e) => {
+            // give the session back, so the last PATCH can be retried
+            ctx.upload_sessions.restore(session_id, &session).await?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use axum::extract::Extension;
+use axum::routing::post;
+use axum::{Json, Router};
+use crate::api::archives::percent_encode;
+use crate::api::error::CustomError;
+use crate::api::extractor::{AuthUser, ClientIp, HmacSha384};
+use crate::api::login_guard::LoginAttempt;
+use crate::api::session_cookies::SessionCookies;
+use crate::api::users::{hash_password, session_response, verify_password};
+use crate::api::{ApiContext, Result};
+use crate::api_common::users::{RecoveryCodes, TotpCodeReq, TotpEnrollment, TwoFactorBody, TwoFactorChallenge,
+                               TwoFactorLoginReq, User, UserBody};
+use cloud_core::db_schema::recovery_codes::RecoveryCodes as DbRecoveryCodes;
+use cloud_core::db_schema::users::Users as DbUser;
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::net::IpAddr;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+// what authenticator apps expect: a code of 6 digits every 30 seconds
+const TOTP_PERIOD: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// the codes of the steps next to the current one are taken too, for clocks which drift
+const TOTP_SKEW: i64 = 1;
+const TOTP_SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+// seconds to answer the challenge of a login
+const CHALLENGE_TTL: i64 = 5 * 60;
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/api/users/two_factor", post(enroll).delete(disable))
+        .route("/api/users/two_factor/confirm", post(confirm))
+        .route("/api/users/login/two_factor", post(login_two_factor))
+}
+
+// The claims of a challenge token. Without a user_id it never passes for an access
+// token, and an access token never passes for a challenge.
+#[derive(Serialize, Deserialize)]
+struct ChallengeClaims {
+    challenge_user_id: Uuid,
+    gen: i32,
+    exp: i64,
+}
+
+/// The challenge of a login with a right password, answered with a code of the
+/// authenticator app or a recovery code.
+pub(crate) fn challenge(user: &DbUser, ctx: &ApiContext) -> TwoFactorChallenge {
+    let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
+        .expect("HMAC-SHA-384 can accept any key length");
+    let expires_at = OffsetDateTime::now_utc().unix_timestamp() + CHALLENGE_TTL;
+
+    let challenge_token = ChallengeClaims {
+        challenge_user_id: user.id,
+        gen: user.token_generation,
+        exp: expires_at,
+    }
+    .sign_with_key(&hmac)
+    .expect("HMAC signing should be infallible");
+
+    TwoFactorChallenge { challenge_token, expires_at }
+}
+
+// Start an enrollment with a new secret, it's used once a code of it is confirmed.
+// Enrolling again before that replaces the secret.
+async fn enroll(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+) -> Result<Json<TwoFactorBody<TotpEnrollment>>> {
+    let user = DbUser::find_by_id(auth_user.user_id, &ctx.db).await?;
+    if user.totp_enabled {
+        return Err(CustomError::Conflict);
+    }
+
+    let mut bytes = [0u8; TOTP_SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = base32::encode(BASE32, &bytes);
+    DbUser::set_totp_secret(user.id, &secret, &ctx.db).await?;
+
+    let issuer = percent_encode(&ctx.config.totp_issuer);
+    let provisioning_uri = format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, percent_encode(&user.email), secret, issuer, TOTP_DIGITS, TOTP_PERIOD,
+    );
+
+    Ok(Json(TwoFactorBody {
+        two_factor: TotpEnrollment { secret, provisioning_uri },
+    }))
+}
+
+// confirm the enrollment with a code of the app, the answer has the recovery codes
+async fn confirm(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    Json(req): Json<TotpCodeReq>,
+) -> Result<Json<TwoFactorBody<RecoveryCodes>>> {
+    let user = DbUser::find_by_id(auth_user.user_id, &ctx.db).await?;
+    if user.totp_enabled {
+        return Err(CustomError::Conflict);
+    }
+    let secret = user.totp_secret
+        .as_deref()
+        .and_then(|secret| base32::decode(BASE32, secret))
+        .ok_or(CustomError::unprocessable_entity([("two_factor", "must be enrolled first")]))?;
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let step = verify_totp(&secret, req.code.trim(), now, 0)
+        .ok_or(CustomError::unprocessable_entity([("code", "is invalid")]))?;
+
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut code_hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = new_recovery_code();
+        code_hashes.push(hash_password(normalize_recovery_code(&code)).await?);
+        recovery_codes.push(code);
+    }
+    DbUser::enable_totp(user.id, step, &code_hashes, &ctx.db).await?;
+
+    Ok(Json(TwoFactorBody {
+        two_factor: RecoveryCodes { recovery_codes },
+    }))
+}
+
+// turning it off takes a code as well, a stolen session alone isn't enough
+async fn disable(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<TotpCodeReq>,
+) -> Result<()> {
+    let user = DbUser::find_by_id(auth_user.user_id, &ctx.db).await?;
+    if !user.totp_enabled {
+        return Err(CustomError::unprocessable_entity([("two_factor", "is not enabled")]));
+    }
+    guard_second_factor(&user, &req.code, ip, &ctx).await?;
+    DbUser::disable_totp(user.id, &ctx.db).await?;
+    Ok(())
+}
+
+// the second step of a login, the session starts here
+async fn login_two_factor(
+    ctx: Extension<ApiContext>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<TwoFactorLoginReq>,
+) -> Result<(SessionCookies, Json<UserBody<User>>)> {
+    let hmac = HmacSha384::new_from_slice(ctx.config.hmac_key.as_bytes())
+        .expect("HMAC-SHA-384 can accept any key length");
+    let claims: ChallengeClaims = req.challenge_token
+        .as_str()
+        .verify_with_key(&hmac)
+        .map_err(|_| CustomError::Unauthorized)?;
+    if claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
+        return Err(CustomError::Unauthorized);
+    }
+
+    // a password change or a logout everywhere ends the pending logins too
+    let user = DbUser::find_by_id(claims.challenge_user_id, &ctx.db).await?;
+    if user.token_generation != claims.gen || !user.totp_enabled {
+        return Err(CustomError::Unauthorized);
+    }
+    guard_second_factor(&user, &req.code, ip, &ctx).await?;
+
+    session_response(user, &ctx).await
+}
+
+// guessing codes counts against the account like guessing passwords
+async fn guard_second_factor(user: &DbUser, code: &str, ip: Option<IpAddr>, ctx: &ApiContext) -> Result<()> {
+    let attempt = LoginAttempt::new(&user.email, ip);
+    attempt.check(ctx).await?;
+    match check_second_factor(user, code, ctx).await {
+        Ok(()) => attempt.succeed(ctx).await,
+        Err(CustomError::Unauthorized) => {
+            attempt.fail(Some(user.id), ctx).await?;
+            Err(CustomError::Unauthorized)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// take a code of the app or an unused recovery code, each works once
+async fn check_second_factor(user: &DbUser, code: &str, ctx: &ApiContext) -> Result<()> {
+    let code = code.trim();
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    if code.len() == TOTP_DIGITS as usize && code.bytes().all(|byte| byte.is_ascii_digit()) {
+        let secret = user.totp_secret
+            .as_deref()
+            .and_then(|secret| base32::decode(BASE32, secret))
+            .ok_or(CustomError::Unauthorized)?;
+        let step = verify_totp(&secret, code, now, user.totp_last_step).ok_or(CustomError::Unauthorized)?;
+        return match DbUser::take_totp_step(user.id, step, &ctx.db).await? {
+            true => Ok(()),
+            false => Err(CustomError::Unauthorized),
+        };
+    }
+
+    let code = normalize_recovery_code(code);
+    for recovery_code in DbRecoveryCodes::unused(user.id, &ctx.db).await? {
+        match verify_password(code.clone(), recovery_code.code_hash).await {
+            Ok(()) if DbRecoveryCodes::take(recovery_code.id, now, &ctx.db).await? => return Ok(()),
+            Ok(()) | Err(CustomError::Unauthorized) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(CustomError::Unauthorized)
+}
+
+// the code of a time step, RFC 6238 with HMAC-SHA-1
+fn totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC can accept any key length");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// the step of the matching code, only the steps after `last_step` count
+fn verify_totp(secret: &[u8], code: &str, now: i64, last_step: i64) -> Option<i64> {
+    let current = now / TOTP_PERIOD;
+    (current - TOTP_SKEW..=current + TOTP_SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+// ten base32 characters like "abcde-fghij"
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 7];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = base32::encode(BASE32, &bytes).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+// codes are typed by hand, the dash, spaces and case don't matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
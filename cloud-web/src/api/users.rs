@@ -2,13 +2,25 @@ use rand;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash};
 use axum::extract::Extension;
+use axum::http::HeaderMap;
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use crate::api::error::{CustomError, ResultExt};
-use crate::api::extractor::AuthUser;
+use crate::api::extractor::{AuthUser, ClientIp};
+use crate::api::login_guard::LoginAttempt;
+use crate::api::session_cookies::{self, SessionCookies, REFRESH_COOKIE};
 use crate::api::{ApiContext, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use cloud_core::db_schema::refresh_tokens::RefreshTokens;
 use cloud_core::db_schema::users::Users as DbUser;
-use crate::api_common::users::{NewUser, LoginUser, UpdateUser, User, UserBody};
+use crate::api::{admin, email_tokens, two_factor};
+use crate::api_common::users::{NewUser, LoginUser, LoginResp, LogoutReq, RefreshReq, TwoFactorBody, UpdateUser, User, UserBody};
+use crate::config::Registration;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use time::OffsetDateTime;
 
 
 pub fn router() -> Router {
@@ -16,59 +28,167 @@ pub fn router() -> Router {
         .route("/api/users", post(create_user)
             .get(get_current_user).put(update_user))
         .route("/api/users/login", post(login_user))
+        .route("/api/users/refresh", post(refresh_user))
+        .route("/api/users/logout", post(logout_user))
 }
 
 
 async fn create_user(
     ctx: Extension<ApiContext>,
     Json(req): Json<UserBody<NewUser>>,
-) -> Result<Json<UserBody<User>>> {
+) -> Result<(SessionCookies, Json<UserBody<User>>)> {
+    let invite_code = match ctx.config.registration {
+        Registration::Open => None,
+        Registration::Invite => Some(req.user.invite_code.clone()
+            .ok_or_else(|| CustomError::unprocessable_entity([("invite_code", "is required")]))?),
+        Registration::Closed => return Err(CustomError::Forbidden),
+    };
     let password_hash = hash_password(req.user.password).await?;
 
     let user = DbUser::new(req.user.username.clone(), req.user.email.clone(),
                            password_hash.clone());
-    user.insert(&ctx.db).await?;
+    let user = match invite_code {
+        Some(code) => {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            user.insert_with_invite(&admin::invite_code_hash(&code), now, &ctx.db)
+                .await?
+                .ok_or_else(|| CustomError::unprocessable_entity([("invite_code", "is invalid or used")]))?
+        }
+        None => user.insert(&ctx.db).await?,
+    };
+    email_tokens::send_verification(&user, &ctx).await;
 
-    Ok(Json(UserBody {
-        user: User {
-            email: req.user.email,
-            token: AuthUser { user_id: user.id }.to_jwt(&ctx),
-            username: req.user.username,
-        },
-    }))
+    session_response(user, &ctx).await
 }
 
 async fn login_user(
     ctx: Extension<ApiContext>,
+    ClientIp(ip): ClientIp,
     Json(req): Json<UserBody<LoginUser>>,
-) -> Result<Json<UserBody<User>>> {
-    let user = DbUser::find_by_email(&req.user.email, &ctx.db).await?;
+) -> Result<(SessionCookies, Json<LoginResp>)> {
+    let attempt = LoginAttempt::new(&req.user.email, ip);
+    attempt.check(&ctx).await?;
 
-    verify_password(req.user.password, user.password_hash).await?;
+    let user = match DbUser::find_by_email(&req.user.email, &ctx.db).await {
+        Ok(user) => Some(user),
+        Err(sqlx::Error::RowNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    // an unknown email takes as long as a wrong password, not to tell which emails exist
+    let password_hash = match &user {
+        Some(user) => user.password_hash.clone(),
+        None => dummy_password_hash().await?,
+    };
+    let verified = verify_password(req.user.password, password_hash).await;
+    let user = match (user, verified) {
+        // the password is right, telling the user is disabled gives nothing away
+        (Some(user), Ok(())) if user.disabled => return Err(CustomError::Forbidden),
+        (Some(user), Ok(())) => user,
+        (user, Ok(()) | Err(CustomError::Unauthorized)) => {
+            attempt.fail(user.map(|user| user.id), &ctx).await?;
+            return Err(CustomError::Unauthorized);
+        }
+        (_, Err(e)) => return Err(e),
+    };
 
-    Ok(Json(UserBody {
-        user: User {
-            email: user.email,
-            token: AuthUser {
-                user_id: user.id,
+    // the session starts once the second factor is checked, the failures are only
+    // cleared then, or a right password would reset the guesses of the codes
+    if user.totp_enabled {
+        return Ok((SessionCookies::none(), Json(LoginResp::TwoFactor(TwoFactorBody {
+            two_factor: two_factor::challenge(&user, &ctx),
+        }))));
+    }
+    attempt.succeed(&ctx).await?;
+
+    let user = start_session(user, &ctx).await?;
+    Ok((SessionCookies::issue(&user, &ctx), Json(LoginResp::User(UserBody { user }))))
+}
+
+// Exchange a refresh token for a new access token and refresh token. A refresh token
+// works once, an exchanged one coming back may have been stolen, so every token of
+// its user is revoked. Without one in the body, the one of a cookie session is taken.
+async fn refresh_user(
+    ctx: Extension<ApiContext>,
+    headers: HeaderMap,
+    Json(req): Json<RefreshReq>,
+) -> Result<(SessionCookies, Json<UserBody<User>>)> {
+    let refresh_token = match req.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => cookie_refresh_token(&headers, &ctx)?.ok_or(CustomError::Unauthorized)?.to_string(),
+    };
+    let token_hash = refresh_token_hash(&refresh_token);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let token = match RefreshTokens::exchange(&token_hash, now, &ctx.db).await? {
+        Some(token) => token,
+        None => {
+            if let Some(reused) = RefreshTokens::find_by_hash(&token_hash, &ctx.db).await? {
+                if reused.exchanged {
+                    log::warn!("exchanged refresh token of user {} was used again", reused.user_id);
+                    DbUser::revoke_tokens(reused.user_id, &ctx.db).await?;
+                }
             }
-            .to_jwt(&ctx),
-            username: user.name,
-        },
-    }))
+            return Err(CustomError::Unauthorized);
+        }
+    };
+
+    let user = DbUser::find_by_id(token.user_id, &ctx.db).await?;
+    // issued before a password change, which raced the revocation
+    if user.token_generation != token.generation {
+        return Err(CustomError::Unauthorized);
+    }
+
+    session_response(user, &ctx).await
+}
+
+// end the session of a refresh token, or with `all` every session of the user,
+// the cookies of a cookie session are removed
+async fn logout_user(
+    auth_user: AuthUser,
+    ctx: Extension<ApiContext>,
+    headers: HeaderMap,
+    Json(req): Json<LogoutReq>,
+) -> Result<(SessionCookies, ())> {
+    let refresh_token = match req.refresh_token {
+        Some(refresh_token) => Some(refresh_token),
+        None => cookie_refresh_token(&headers, &ctx)?.map(str::to_string),
+    };
+    if req.all {
+        DbUser::revoke_tokens(auth_user.user_id, &ctx.db).await?;
+    } else if let Some(refresh_token) = refresh_token {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        RefreshTokens::revoke(&refresh_token_hash(&refresh_token), auth_user.user_id, now, &ctx.db).await?;
+    }
+    Ok((SessionCookies::clear(&ctx), ()))
+}
+
+// the refresh token of a cookie session, which is only taken with the csrf token
+fn cookie_refresh_token<'a>(headers: &'a HeaderMap, ctx: &ApiContext) -> Result<Option<&'a str>> {
+    if !ctx.config.cookie_sessions {
+        return Ok(None);
+    }
+    match session_cookies::get_cookie(headers, REFRESH_COOKIE) {
+        Some(refresh_token) => {
+            session_cookies::check_csrf(headers)?;
+            Ok(Some(refresh_token))
+        }
+        None => Ok(None),
+    }
 }
 
 async fn get_current_user(
     auth_user: AuthUser,
     ctx: Extension<ApiContext>,
+    headers: HeaderMap,
 ) -> Result<Json<UserBody<User>>> {
     let user = DbUser::find_by_id(auth_user.user_id, &ctx.db).await?;
 
     Ok(Json(UserBody {
         user: User {
             email: user.email,
-            token: auth_user.to_jwt(&ctx),
+            token: AuthUser::presented_token(&ctx, &headers),
+            refresh_token: None,
             username: user.name,
+            email_verified: user.email_verified,
         },
     }))
 }
@@ -76,10 +196,11 @@ async fn get_current_user(
 async fn update_user(
     auth_user: AuthUser,
     ctx: Extension<ApiContext>,
+    headers: HeaderMap,
     Json(req): Json<UserBody<UpdateUser>>,
-) -> Result<Json<UserBody<User>>> {
+) -> Result<(SessionCookies, Json<UserBody<User>>)> {
     if req.user == UpdateUser::default() {
-        return get_current_user(auth_user, ctx).await;
+        return Ok((SessionCookies::none(), get_current_user(auth_user, ctx, headers).await?));
     }
 
     let password_hash = if let Some(password) = req.user.password {
@@ -88,19 +209,72 @@ async fn update_user(
         None
     };
 
-    // TODO: if the user update password, then the token should be updated
-    let user = DbUser::update_by_id(auth_user.user_id, req.user.email, req.user.username, password_hash, &ctx.db).await?;
+    // a new password revokes every token, this session goes on with new ones
+    let password_changed = password_hash.is_some();
+    let email_changed = req.user.email.is_some();
+    let user = DbUser::update_by_id(auth_user.user_id, req.user.username, req.user.email, password_hash, &ctx.db).await?;
+    // a new email is verified again
+    if email_changed && !user.email_verified {
+        email_tokens::send_verification(&user, &ctx).await;
+    }
+    if password_changed {
+        return session_response(user, &ctx).await;
+    }
 
-    Ok(Json(UserBody {
+    Ok((SessionCookies::none(), Json(UserBody {
         user: User {
             email: user.email,
-            token: auth_user.to_jwt(&ctx),
+            token: AuthUser::presented_token(&ctx, &headers),
+            refresh_token: None,
             username: user.name,
+            email_verified: user.email_verified,
         },
-    }))
+    })))
+}
+
+/// Start a session for the user, in the cookies too when cookie sessions are on.
+pub(crate) async fn session_response(user: DbUser, ctx: &ApiContext) -> Result<(SessionCookies, Json<UserBody<User>>)> {
+    let user = start_session(user, ctx).await?;
+    Ok((SessionCookies::issue(&user, ctx), Json(UserBody { user })))
 }
 
-async fn hash_password(password: String) -> Result<String> {
+// an access token and a new refresh token for the user
+pub(crate) async fn start_session(user: DbUser, ctx: &ApiContext) -> Result<User> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let refresh_token = URL_SAFE_NO_PAD.encode(bytes);
+
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    RefreshTokens::delete_expired(user.id, now, &ctx.db).await?;
+    RefreshTokens::new(
+        user.id,
+        refresh_token_hash(&refresh_token),
+        user.token_generation,
+        now + ctx.config.refresh_token_ttl as i64,
+    )
+    .insert(&ctx.db)
+    .await?;
+
+    Ok(User {
+        email: user.email,
+        token: AuthUser {
+            user_id: user.id,
+            generation: user.token_generation,
+            grant: None,
+        }
+        .to_jwt(ctx)?,
+        refresh_token: Some(refresh_token),
+        username: user.name,
+        email_verified: user.email_verified,
+    })
+}
+
+// only the hash of a refresh token is stored
+fn refresh_token_hash(refresh_token: &str) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+pub(crate) async fn hash_password(password: String) -> Result<String> {
     // Argon2 hashing is designed to be computationally intensive,
     // so we need to do this on a blocking thread.
     Ok(tokio::task::spawn_blocking(move || -> Result<String> {
@@ -115,7 +289,19 @@ async fn hash_password(password: String) -> Result<String> {
     .expect("panic in generating password hash")?)
 }
 
-async fn verify_password(password: String, password_hash: String) -> Result<()> {
+// a hash no password is checked against but the ones of unknown emails
+async fn dummy_password_hash() -> Result<String> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash.clone());
+    }
+    let mut password = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut password);
+    let hash = hash_password(URL_SAFE_NO_PAD.encode(password)).await?;
+    Ok(DUMMY_HASH.get_or_init(|| hash).clone())
+}
+
+pub(crate) async fn verify_password(password: String, password_hash: String) -> Result<()> {
     Ok(tokio::task::spawn_blocking(move || -> Result<()> {
         let hash = PasswordHash::new(&password_hash)
             .map_err(|e| anyhow::anyhow!("invalid password hash: {}", e))?;
@@ -0,0 +1,83 @@
+use crate::api::{error::CustomError, Result};
+use crate::config::Config;
+use std::borrow::Cow;
+
+/// the longest name most file systems can store
+pub(crate) const MAX_NAME_BYTES: usize = 255;
+
+// characters Windows doesn't allow in a name
+const WINDOWS_FORBIDDEN_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+// device names Windows doesn't allow as a name, with or without an extension
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The problems of a file or dir name, empty for a valid one.
+///
+/// A name must never be empty, "." or "..", contain "/", NUL or another control
+/// character, or be longer than `MAX_NAME_BYTES`. With `windows_compatible_names`
+/// it must also be valid on Windows, and it must not be one of `reserved_names`.
+pub(crate) fn name_errors(name: &str, config: &Config) -> Vec<Cow<'static, str>> {
+    let mut errors: Vec<Cow<'static, str>> = Vec::new();
+    if name.is_empty() {
+        errors.push("must not be empty".into());
+        return errors;
+    }
+    if name == "." || name == ".." {
+        errors.push("must not be . or ..".into());
+    }
+    if name.contains('/') {
+        errors.push("must not contain /".into());
+    }
+    if name.chars().any(char::is_control) {
+        errors.push("must not contain NUL or control characters".into());
+    }
+    if name.len() > MAX_NAME_BYTES {
+        errors.push(format!("must not be longer than {} bytes", MAX_NAME_BYTES).into());
+    }
+
+    if config.windows_compatible_names {
+        if name.contains(WINDOWS_FORBIDDEN_CHARS) {
+            errors.push("must not contain any of < > : \" \\ | ? *".into());
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            errors.push("must not end with a dot or a space".into());
+        }
+        let stem = name.split('.').next().unwrap_or_default().trim_end();
+        if WINDOWS_RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+            errors.push(format!("{} is reserved on Windows", stem.to_uppercase()).into());
+        }
+    }
+    if config.reserved_names.iter().any(|reserved| reserved.to_lowercase() == name.to_lowercase()) {
+        errors.push("is a reserved name".into());
+    }
+    errors
+}
+
+/// Check a file or dir name, the errors are reported under `field`.
+pub(crate) fn check_name(field: &'static str, name: &str, config: &Config) -> Result<()> {
+    let errors = name_errors(name, config);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(CustomError::unprocessable_entity(errors.into_iter().map(|error| (field, error))))
+}
+
+/// Check every component of a path, the errors name the component.
+pub(crate) fn check_path(field: &'static str, components: &[String], config: &Config) -> Result<()> {
+    let errors = components
+        .iter()
+        .flat_map(|component| {
+            name_errors(component, config)
+                .into_iter()
+                .map(move |error| (field, format!("{:?} {}", component, error)))
+        })
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(CustomError::unprocessable_entity(errors))
+}
+
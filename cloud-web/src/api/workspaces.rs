@@ -32,8 +32,9 @@ async fn create_ws(
 ) -> Result<Json<WsBody<Ws>>> {
     let id = Uuid::now_v7();
 
-    let ws = Ws::new(id, ws_req.ws.name.clone(), auth_user.user_id, false);
-    ws.insert(&ctx.db).await?;
+    let mut ws = Ws::new(id, ws_req.ws.name.clone(), auth_user.user_id, false);
+    ws.case_insensitive = ws_req.ws.case_insensitive;
+    let ws = ws.insert(&ctx.db).await?;
 
     Ok(Json(WsBody { ws }))
 }
@@ -62,9 +63,7 @@ async fn update_ws(
     ws.name = ws_req.ws.name.clone();
     let ws = ws.update(&ctx.db).await?;
 
-    Ok(Json(WsBody {
-        ws: Ws::new(ws_id, ws.name, auth_user.user_id, false)
-    }))
+    Ok(Json(WsBody { ws }))
 }
 
 async fn delete_ws(
@@ -1,3 +1,6 @@
 pub mod users;
 pub mod storages;
 pub mod workspaces;
+pub mod tokens;
+pub mod admin;
+pub mod jwks;
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteBody<T> {
+    pub invite: T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    User,
+    /// manages the users and the invites
+    Admin,
+}
+
+impl UserRole {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "user" => Some(Self::User),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListUsersReq {
+    /// a part of the name or the email
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// A user as an admin sees it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserAccount {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+    pub role: UserRole,
+    pub disabled: bool,
+    /// bytes the files may take, none for no limit
+    pub quota: Option<i64>,
+    /// bytes the files take
+    pub usage: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdateAccountReq {
+    pub role: Option<UserRole>,
+    pub disabled: Option<bool>,
+    /// `null` removes the quota, a missing field keeps it
+    #[serde(deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Option<i64>>,
+}
+
+// a field which is present, even as `null`, is `Some`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPasswordReq {
+    pub password: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CreateInviteReq {
+    /// who the invite is for, shown in the list
+    #[serde(default)]
+    pub note: String,
+    /// unix timestamp, the code never expires without it
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: Uuid,
+    pub note: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub used_by: Option<Uuid>,
+    pub used_at: Option<i64>,
+    /// the code itself, only sent when it's created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
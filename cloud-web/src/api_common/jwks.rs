@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// The public keys the access tokens are verified with, as a JSON Web Key Set.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwk {
+    /// OKP or EC
+    pub kty: String,
+    /// Ed25519 or P-256
+    pub crv: String,
+    /// EdDSA or ES256
+    pub alg: String,
+    /// the `kid` in the header of the tokens the key signed
+    pub kid: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub x: String,
+    /// only in EC keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
@@ -1,4 +1,5 @@
 use bytes::Bytes;
+use cloud_core::db_schema::files::{SortBy, SortOrder};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -13,9 +14,83 @@ pub struct StorageReq {
     pub filename: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ListStorageReq {
     pub parent_dir_id: Option<i64>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    pub sort: Option<SortBy>,
+    pub order: Option<SortOrder>,
+    pub dirs_first: Option<bool>,
+    /// file or dir
+    #[serde(rename = "type")]
+    pub file_type: Option<String>,
+    pub ext: Option<String>,
+    /// unix timestamp in seconds
+    pub modified_after: Option<i64>,
+    /// unix timestamp in seconds
+    pub modified_before: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TreeReq {
+    /// levels below the dir to return, default to 1
+    pub depth: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeNode {
+    /// `size` of a dir is the total size of all files below it
+    pub storage: Storage,
+    /// number of files below a dir, 1 for a file
+    pub file_count: String,
+    pub children: Vec<TreeNode>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListStorageResp {
+    pub storages: Vec<StorageBody<Storage>>,
+    /// number of all entries matching the filters
+    pub total: i64,
+    /// pass it as `cursor` to get the next page, `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// What to do when the name is already taken in the target dir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// fail with 409 Conflict
+    #[default]
+    Fail,
+    /// take the first free name like "report (1).pdf"
+    Rename,
+    /// store the content as a new version of the existing file
+    Overwrite,
+    /// keep the existing file or dir and return it
+    Skip,
+}
+
+impl ConflictPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "fail" => Some(Self::Fail),
+            "rename" => Some(Self::Rename),
+            "overwrite" => Some(Self::Overwrite),
+            "skip" => Some(Self::Skip),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fail => "fail",
+            Self::Rename => "rename",
+            Self::Overwrite => "overwrite",
+            Self::Skip => "skip",
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -23,6 +98,48 @@ pub struct UploadFileReq {
     pub filename: String,
     pub is_dir: bool,
     pub parent_dir_id: i64,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveReq {
+    /// comma separated ids of the files and dirs to put into the archive
+    pub ids: String,
+    /// zip or tar, zip by default
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchUploadReq {
+    /// the dir the relative paths start from
+    #[serde(default = "default_parent_dir_id")]
+    pub parent_dir_id: i64,
+    /// applies to every entry
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+fn default_parent_dir_id() -> i64 {
+    -1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchEntry {
+    /// the relative path of the part, as sent
+    pub path: String,
+    /// the status code the entry would get as a single upload
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<Storage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchUploadResp {
+    /// one entry per part, in the order they were sent
+    pub entries: Vec<BatchEntry>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,6 +147,57 @@ pub struct UpdateFileReq {
     pub filename: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MoveFileReq {
+    pub parent_dir_id: i64,
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CreatePathReq {
+    pub is_dir: bool,
+    /// create missing parent dirs like `mkdir -p`
+    pub parents: bool,
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MovePathReq {
+    /// new full path of the file or dir
+    pub destination: String,
+    /// create missing parent dirs of the destination
+    #[serde(default)]
+    pub parents: bool,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyFileReq {
+    pub parent_dir_id: i64,
+    /// target workspace, default to the workspace of the source
+    pub ws_id: Option<Uuid>,
+    pub filename: Option<String>,
+    /// directories are never merged, overwrite applies to files only
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CopyJob {
+    pub job_id: Uuid,
+    pub user_id: Uuid,
+    /// running, done or failed
+    pub status: String,
+    pub total: usize,
+    pub copied: usize,
+    /// id of the copy, set when the job is done
+    pub id: Option<String>,
+}
+
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Storage {
@@ -38,6 +206,9 @@ pub struct Storage {
     pub filename: String,
     pub parent_dir_id: String,
     pub size: String,
+    /// full path from the root dir of the workspace, like "/photos/2023/a.jpg"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 impl Storage {
@@ -51,8 +222,14 @@ impl Storage {
             filename,
             parent_dir_id,
             size,
+            path: None,
         }
     }
+
+    pub fn with_path(mut self, path: String) -> Self {
+        self.path = Some(path);
+        self
+    }
 }
 
 
@@ -61,11 +238,33 @@ pub struct CreateSessionReq {
     pub filename: String,
     pub ws_id: Uuid,
     pub parent_dir_id: i64,
+    // size in bytes and hex sha256 of the whole file, checked when the upload is finished
+    pub total_size: u64,
+    pub file_hash: String,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Session {
-    pub session_id: Uuid
+    pub session_id: Uuid,
+    // set when the content is already stored, answering it with
+    // InstantUploadReq creates the file without uploading it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<InstantChallenge>,
+}
+
+// a range of the file the client must prove to have
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct InstantChallenge {
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstantUploadReq {
+    // hex sha256 of the challenged range
+    pub proof: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,6 +273,29 @@ pub struct SessionInfo {
     pub ws_id: Uuid,
     pub filename: String,
     pub parent_dir_id: i64,
+    pub total_size: u64,
+    // empty for tus uploads, which don't declare it
+    pub file_hash: String,
+    // the file a new version is uploaded for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<i64>,
+    // applied when the session is finished
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub session_id: Uuid,
+    pub ws_id: Uuid,
+    pub filename: String,
+    pub parent_dir_id: i64,
+    pub total_size: u64,
+    pub file_hash: String,
+    // indexes of the received chunks, ascending
+    pub chunks: Vec<usize>,
+    // unix timestamp after which the session is garbage-collected
+    pub expires_at: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -82,6 +304,24 @@ pub struct BlockInfo {
     pub block_index: usize,
     pub block_size: usize,
     pub block_hash: String,
+    // the block is a slice of a stored file, it's never deleted with the session
+    #[serde(default)]
+    pub shared: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeltaReq {
+    // sha256 of each slice of the new version, in order
+    pub slices_hash: Vec<String>,
+    pub slices_size: Vec<usize>,
+    pub file_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeltaResp {
+    pub session_id: Uuid,
+    // indexes of the slices the server doesn't have, ascending
+    pub missing: Vec<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenBody<T> {
+    pub token: T,
+}
+
+/// What a personal access token may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// list and download files
+    Read,
+    /// upload files and create dirs, without reading or changing what's stored
+    Upload,
+    /// everything on files and workspaces
+    Write,
+}
+
+impl TokenScope {
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Self::Read),
+            "upload" => Some(Self::Upload),
+            "write" => Some(Self::Write),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Upload => "upload",
+            Self::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateTokenReq {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    /// limit the token to one workspace
+    #[serde(default)]
+    pub ws_id: Option<Uuid>,
+    /// unix timestamp, the token never expires without it
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    /// the start of the token, to tell the tokens apart
+    pub prefix: String,
+    pub scopes: Vec<TokenScope>,
+    pub ws_id: Option<Uuid>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    /// the token itself, only sent when it's created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
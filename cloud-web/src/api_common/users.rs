@@ -11,6 +11,9 @@ pub struct NewUser {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// required when the registration needs an invite
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -31,7 +34,93 @@ pub struct UpdateUser {
 pub struct User {
     pub email: String,
     pub token: String,
+    /// exchanged for a new access token at `/api/users/refresh`, only sent when a session starts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
     pub username: String,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+/// The answer of a login, a user with two-factor authentication gets a challenge
+/// to answer at `/api/users/login/two_factor` first.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum LoginResp {
+    User(UserBody<User>),
+    TwoFactor(TwoFactorBody<TwoFactorChallenge>),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TwoFactorBody<T> {
+    pub two_factor: T,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TwoFactorChallenge {
+    pub challenge_token: String,
+    /// unix timestamp
+    pub expires_at: i64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TwoFactorLoginReq {
+    pub challenge_token: String,
+    /// a code of the authenticator app or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TotpEnrollment {
+    /// base32, for apps which can't read the uri
+    pub secret: String,
+    /// otpauth:// uri, usually shown as a qr code
+    pub provisioning_uri: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TotpCodeReq {
+    pub code: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RecoveryCodes {
+    /// each works once in place of a code, they are only shown here
+    pub recovery_codes: Vec<String>,
+}
+
+/// The token of a verification or password reset mail
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct EmailTokenReq {
+    pub token: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PasswordResetReq {
+    pub email: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct NewPasswordReq {
+    /// the token of the password reset mail
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct RefreshReq {
+    /// taken from the cookie of a cookie session when it's missing
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+#[serde(default)]
+pub struct LogoutReq {
+    /// the refresh token of the session to end
+    pub refresh_token: Option<String>,
+    /// end every session of the user, the access tokens included
+    pub all: bool,
 }
 
 
@@ -6,4 +6,7 @@ pub struct WsBody<T> {
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct WsReq {
     pub name: String,
+    /// compare file names in lower case, only taken when the workspace is created
+    #[serde(default)]
+    pub case_insensitive: bool,
 }
\ No newline at end of file
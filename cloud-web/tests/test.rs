@@ -12,13 +12,24 @@ use sqlx::postgres::PgPoolOptions;
 use cloud_core::db_schema::workspaces::Workspaces;
 use cloud_core::utils::snowflake::SnowFlake;
 use cloud_web::api::{api_router, ApiContext};
-use cloud_web::api_common::users::{LoginUser, NewUser, UserBody, User};
+use cloud_web::api_common::users::{LoginUser, LogoutReq, NewUser, RefreshReq, UserBody, User, TotpCodeReq,
+                                   TotpEnrollment, TwoFactorBody, RecoveryCodes, TwoFactorLoginReq,
+                                   EmailTokenReq, PasswordResetReq, NewPasswordReq};
 use cloud_web::api_common::workspaces::{WsBody, WsReq};
+use cloud_web::api_common::tokens::{ApiToken, CreateTokenReq, TokenBody, TokenScope};
+use cloud_web::api_common::jwks::JwkSet;
+use cloud_web::api_common::admin::{CreateInviteReq, Invite, InviteBody, SetPasswordReq, UpdateAccountReq,
+                                   UserAccount, UserRole};
 use cloud_web::api_common::storages::{Storage, StorageBody, UpdateFileReq, UploadFileReq,
-                                      CreateSessionReq, Session, UploadFinishReq};
+                                      CreateSessionReq, Session, UploadFinishReq, MoveFileReq,
+                                      CopyFileReq, CopyJob, MovePathReq, ListStorageResp, TreeNode,
+                                      SessionStatus, InstantUploadReq, DeltaReq, DeltaResp,
+                                      BatchUploadResp, ConflictPolicy};
 use cloud_web::api::extractor::{AuthUser, AuthUploadInfo};
-use cloud_web::config::Config;
+use cloud_web::config::{Config, JwtAlgorithm, MailerKind, Registration, SameSite, SessionStoreKind};
+use cloud_core::block::BlockHandler;
 use cloud_core::block::fs_handler::FsHandler;
+use uuid::Uuid;
 
 fn load_config() -> Config {
     let file_path = env::var("CONFIG_TEST_PATH").unwrap_or("config-test.yaml".to_string());
@@ -30,7 +41,14 @@ fn load_config() -> Config {
 }
 
 async fn init_env() -> Router {
-    let config = load_config();
+    init_env_with(load_config()).await
+}
+
+async fn init_env_with(config: Config) -> Router {
+    api_router(init_ctx(config).await.unwrap())
+}
+
+async fn init_ctx(config: Config) -> Result<ApiContext, Box<dyn Error + Send + Sync>> {
     let redis = redis::Client::open(config.redis_connection_str.as_str()).unwrap();
 
     let pool = PgPoolOptions::new()
@@ -43,8 +61,7 @@ async fn init_env() -> Router {
     let snowflake = SnowFlake::new(config.worker_id, config.datacenter_id);
     let block_handler = FsHandler::new(&config.data_dir);
 
-    let api_ctx = ApiContext::new(config, pool, snowflake, block_handler, redis);
-    api_router(api_ctx)
+    Ok(ApiContext::new(config, pool, snowflake, block_handler, redis)?)
 }
 
 #[tokio::test]
@@ -56,6 +73,7 @@ async fn test_create_user() {
         username: "name".to_string(),
         password: "password".to_string(),
         email: "email@test.com".to_string(),
+        invite_code: None,
     };
 
     let user = UserBody { user };
@@ -103,6 +121,7 @@ async fn test_create_ws() {
     let ws_req = WsBody {
         ws: WsReq {
             name: "ws1".to_string(),
+            case_insensitive: false,
         }
     };
     // let ws_req = serde_json::to_string(&ws_req).unwrap();
@@ -151,6 +170,7 @@ async fn test_update_ws() {
     let ws_req = WsBody {
         ws: WsReq {
             name: "ws4".to_string(),
+            case_insensitive: false,
         }
     };
     // let ws_req = serde_json::to_string(&ws_req).unwrap();
@@ -181,6 +201,7 @@ async fn test_mkdir_under_root() {
         filename: "test_dir2".to_string(),
         is_dir: true,
         parent_dir_id: -1,
+        conflict: ConflictPolicy::Fail,
     };
     let upload_file_req_str = serde_json::to_string(&upload_file_req).unwrap();
     let res = client
@@ -193,6 +214,356 @@ async fn test_mkdir_under_root() {
     assert_eq!(status_code, StatusCode::OK);
 }
 
+async fn create_dir(client: &TestClient, token: &str, ws_id: Uuid, parent_dir_id: i64, filename: &str) -> Storage {
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages";
+    let upload_file_req = UploadFileReq {
+        filename: filename.to_string(),
+        is_dir: true,
+        parent_dir_id,
+        conflict: ConflictPolicy::Fail,
+    };
+    let upload_file_req_str = serde_json::to_string(&upload_file_req).unwrap();
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + token)
+        .header("x-mycloud", upload_file_req_str)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<StorageBody<Storage>>().await.storage
+}
+
+async fn upload_file(client: &TestClient, token: &str, ws_id: Uuid, parent_dir_id: i64, filename: &str, content: &'static str) -> Storage {
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages";
+    let upload_file_req = UploadFileReq {
+        filename: filename.to_string(),
+        is_dir: false,
+        parent_dir_id,
+        conflict: ConflictPolicy::Fail,
+    };
+    let upload_file_req_str = serde_json::to_string(&upload_file_req).unwrap();
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + token)
+        .header("x-mycloud", upload_file_req_str)
+        .body(Body::from(content))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<StorageBody<Storage>>().await.storage
+}
+
+#[tokio::test]
+async fn test_move_storage() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let suffix = Uuid::now_v7().to_string();
+    let parent = create_dir(&client, &user.token, ws_id, -1, &("move_parent_".to_string() + &suffix)).await;
+    let child = create_dir(&client, &user.token, ws_id, -1, &("move_child_".to_string() + &suffix)).await;
+    let parent_id = parent.id.parse::<i64>().unwrap();
+    let child_id = child.id.parse::<i64>().unwrap();
+
+    // move child into parent and rename it in the same step
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + child.id.as_str() + "/move";
+    let req = MoveFileReq {
+        parent_dir_id: parent_id,
+        filename: Some("moved_child".to_string()),
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let moved = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(moved.parent_dir_id, parent.id);
+    assert_eq!(moved.filename, "moved_child");
+
+    // moving parent into its own child must be rejected
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + parent.id.as_str() + "/move";
+    let req = MoveFileReq {
+        parent_dir_id: child_id,
+        filename: None,
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_copy_storage() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let suffix = Uuid::now_v7().to_string();
+    let dir = create_dir(&client, &user.token, ws_id, -1, &("copy_src_".to_string() + &suffix)).await;
+    let dir_id = dir.id.parse::<i64>().unwrap();
+    upload_file(&client, &user.token, ws_id, dir_id, "a.txt", "copy me").await;
+
+    // 1. start copying the dir to the root with another name
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + dir.id.as_str() + "/copy";
+    let req = CopyFileReq {
+        parent_dir_id: -1,
+        ws_id: None,
+        filename: Some("copy_dst_".to_string() + &suffix),
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let copy_job = res.json::<CopyJob>().await;
+
+    // 2. wait for the job
+    let url = "/api/copy_jobs/".to_string() + copy_job.job_id.to_string().as_str();
+    let mut copy_job = copy_job;
+    for _ in 0..50 {
+        let res = client
+            .get(&url)
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        copy_job = res.json::<CopyJob>().await;
+        if copy_job.status != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(copy_job.status, "done");
+    assert_eq!(copy_job.total, 2);
+
+    // 3. the copy contains the file
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages?parent_dir_id=" + copy_job.id.unwrap().as_str();
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let storages = res.json::<ListStorageResp>().await.storages;
+    assert_eq!(storages.len(), 1);
+    assert_eq!(storages[0].storage.filename, "a.txt");
+}
+
+#[tokio::test]
+async fn test_path_storage() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+    let paths_url = "/api/".to_string() + ws_id.to_string().as_str() + "/paths";
+    let root = "/path_".to_string() + &Uuid::now_v7().to_string();
+
+    // 1. mkdir -p
+    let url = paths_url.clone() + &root + "/2023?is_dir=true&parents=true";
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let dir = res.json::<StorageBody<Storage>>().await.storage;
+    assert!(dir.is_dir);
+    assert_eq!(dir.path, Some(root.clone() + "/2023"));
+
+    // 2. create a file by path and resolve it
+    let url = paths_url.clone() + &root + "/2023/a.jpg";
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .body(Body::from("jpg content"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let file = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(file.parent_dir_id, dir.id);
+    assert_eq!(file.size, "11");
+
+    // 3. move it by path
+    let req = MovePathReq {
+        destination: root.clone() + "/b.jpg",
+        parents: false,
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .put(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let moved = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(moved.id, file.id);
+    assert_eq!(moved.path, Some(root.clone() + "/b.jpg"));
+
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_list_storage_pagination() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let dir = create_dir(&client, &user.token, ws_id, -1, &("list_".to_string() + &Uuid::now_v7().to_string())).await;
+    let dir_id = dir.id.parse::<i64>().unwrap();
+    upload_file(&client, &user.token, ws_id, dir_id, "a.txt", "a").await;
+    upload_file(&client, &user.token, ws_id, dir_id, "b.pdf", "b").await;
+    upload_file(&client, &user.token, ws_id, dir_id, "c.txt", "c").await;
+    create_dir(&client, &user.token, ws_id, dir_id, "z_dir").await;
+
+    // 1. two pages of two entries, dirs first
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages?parent_dir_id=" + dir.id.as_str()
+        + "&limit=2&sort=name&dirs_first=true";
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = res.json::<ListStorageResp>().await;
+    assert_eq!(page.total, 4);
+    let filenames = page.storages.iter().map(|s| s.storage.filename.as_str()).collect::<Vec<_>>();
+    assert_eq!(filenames, ["z_dir", "a.txt"]);
+
+    let res = client
+        .get(&(url.clone() + "&cursor=" + page.next_cursor.unwrap().as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = res.json::<ListStorageResp>().await;
+    let filenames = page.storages.iter().map(|s| s.storage.filename.as_str()).collect::<Vec<_>>();
+    assert_eq!(filenames, ["b.pdf", "c.txt"]);
+    assert!(page.next_cursor.is_none());
+
+    // a cursor outside of the dir is refused
+    let res = client
+        .get(&(url + "&cursor=" + dir.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 2. filter by extension, newest name first
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages?parent_dir_id=" + dir.id.as_str()
+        + "&type=file&ext=txt&order=desc";
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = res.json::<ListStorageResp>().await;
+    assert_eq!(page.total, 2);
+    let filenames = page.storages.iter().map(|s| s.storage.filename.as_str()).collect::<Vec<_>>();
+    assert_eq!(filenames, ["c.txt", "a.txt"]);
+}
+
+async fn get_tree(client: &TestClient, token: &str, ws_id: Uuid, id: &str, depth: i32) -> TreeNode {
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + id + "/tree?depth=" + depth.to_string().as_str();
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + token)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<TreeNode>().await
+}
+
+#[tokio::test]
+async fn test_storage_tree() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let dir = create_dir(&client, &user.token, ws_id, -1, &("tree_".to_string() + &Uuid::now_v7().to_string())).await;
+    let dir_id = dir.id.parse::<i64>().unwrap();
+    upload_file(&client, &user.token, ws_id, dir_id, "a.txt", "1234567").await;
+    let sub_dir = create_dir(&client, &user.token, ws_id, dir_id, "sub").await;
+    let file = upload_file(&client, &user.token, ws_id, sub_dir.id.parse::<i64>().unwrap(), "b.txt", "123").await;
+
+    // 1. the sizes are aggregated up to the top dir
+    let tree = get_tree(&client, &user.token, ws_id, &dir.id, 2).await;
+    assert_eq!(tree.storage.size, "10");
+    assert_eq!(tree.file_count, "2");
+    assert_eq!(tree.children.len(), 2);
+    let sub_tree = tree.children.iter().find(|node| node.storage.id == sub_dir.id).unwrap();
+    assert_eq!(sub_tree.storage.size, "3");
+    assert_eq!(sub_tree.children.len(), 1);
+
+    // 2. depth limits the levels
+    let tree = get_tree(&client, &user.token, ws_id, &dir.id, 1).await;
+    assert!(tree.children.iter().all(|node| node.children.is_empty()));
+
+    // 3. deleting a file updates all ancestors
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + file.id.as_str();
+    let res = client
+        .delete(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let tree = get_tree(&client, &user.token, ws_id, &dir.id, 1).await;
+    assert_eq!(tree.storage.size, "7");
+    assert_eq!(tree.file_count, "1");
+
+    // 4. a file of a deleted dir was taken from the ancestors with the dir
+    let file = upload_file(&client, &user.token, ws_id, sub_dir.id.parse::<i64>().unwrap(), "c.txt", "12").await;
+    for id in [&sub_dir.id, &file.id] {
+        let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + id.as_str();
+        let res = client
+            .delete(&url)
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let tree = get_tree(&client, &user.token, ws_id, &dir.id, 1).await;
+        assert_eq!(tree.storage.size, "7");
+        assert_eq!(tree.file_count, "1");
+    }
+}
+
 async fn list_storages() -> Vec<StorageBody<Storage>> {
     let app = init_env().await;
     let client = TestClient::new(app);
@@ -208,7 +579,7 @@ async fn list_storages() -> Vec<StorageBody<Storage>> {
         .await;
     let status_code = res.status();
     assert_eq!(status_code, StatusCode::OK);
-    let storages = res.json::<Vec<StorageBody<Storage>>>().await;
+    let storages = res.json::<ListStorageResp>().await.storages;
     assert_ne!(storages.len(), 0);
     storages
 }
@@ -249,6 +620,7 @@ async fn test_upload_small_file() {
         filename: "test_file1.txt".to_string(),
         is_dir: false,
         parent_dir_id: -1,
+        conflict: ConflictPolicy::Fail,
     };
     let upload_file_req_str = serde_json::to_string(&upload_file_req).unwrap();
     let res = client
@@ -279,7 +651,10 @@ async fn test_upload_big_file() {
     let upload_session_req = CreateSessionReq {
         filename: "test_file2.txt".to_string(),
         parent_dir_id: -1,
-        ws_id
+        ws_id,
+        total_size: "test file content".len() as u64,
+        file_hash: cloud_utils::digest::sha256_digest(&Bytes::from("test file content")),
+        conflict: ConflictPolicy::Fail,
     };
     // let upload_session_req = serde_json::to_string(&upload_session_req).unwrap();
     // println!("upload_session_req: {}", upload_session_req);
@@ -356,3 +731,1837 @@ async fn test_upload_big_file() {
 //     let status_code = res.status();
 //     assert_eq!(status_code, StatusCode::OK);
 // }
+
+#[tokio::test]
+async fn test_stream_upload() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let suffix = Uuid::now_v7().to_string();
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages";
+
+    // 1. a file larger than two blocks
+    let content = vec![7u8; 2 * 4 * 1024 * 1024 + 1];
+    let upload_file_req = UploadFileReq {
+        filename: "stream_".to_string() + &suffix,
+        is_dir: false,
+        parent_dir_id: -1,
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("x-mycloud", serde_json::to_string(&upload_file_req).unwrap())
+        .body(Body::from(content.clone()))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let storage = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(storage.size, content.len().to_string());
+
+    let res = client
+        .get(&(url.clone() + "/" + storage.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, content.len().to_string());
+
+    // 2. a dir can't have content
+    let upload_file_req = UploadFileReq {
+        filename: "stream_dir_".to_string() + &suffix,
+        is_dir: true,
+        parent_dir_id: -1,
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("x-mycloud", serde_json::to_string(&upload_file_req).unwrap())
+        .body(Body::from("not empty"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn upload_chunk(client: &TestClient, token: &str, session_id: Uuid, chunk_num: usize, content: &'static str) -> StatusCode {
+    let data = Bytes::from(content);
+    let session_value = AuthUploadInfo {
+        session_id,
+        chunk_size: data.len(),
+        chunk_num,
+        hash: cloud_utils::digest::sha256_digest(&data),
+    };
+    let res = client
+        .post("/api/upload_sessions/chunks")
+        .header("Authorization", "Token ".to_string() + token)
+        .header("x-cloud-session", serde_json::to_string(&session_value).unwrap())
+        .body(Body::from(content))
+        .send()
+        .await;
+    res.status()
+}
+
+#[tokio::test]
+async fn test_upload_session_lifecycle() {
+    check_upload_session_lifecycle(init_env().await).await;
+    check_upload_session_finish(init_env().await).await;
+}
+
+#[tokio::test]
+async fn test_upload_session_pg_store() {
+    let mut config = load_config();
+    config.upload_session_store = SessionStoreKind::Postgres;
+    check_upload_session_lifecycle(init_env_with(config.clone()).await).await;
+    check_upload_session_finish(init_env_with(config).await).await;
+}
+
+async fn create_upload_session(client: &TestClient, token: &str, ws_id: Uuid, filename: &str, content: &'static str) -> Uuid {
+    let upload_session_req = CreateSessionReq {
+        filename: filename.to_string(),
+        parent_dir_id: -1,
+        ws_id,
+        total_size: content.len() as u64,
+        file_hash: cloud_utils::digest::sha256_digest(&Bytes::from(content)),
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post("/api/upload_sessions")
+        .header("Authorization", "Token ".to_string() + token)
+        .json(&upload_session_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<Session>().await.session_id
+}
+
+async fn check_upload_session_finish(app: Router) {
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let filename = "session_".to_string() + &Uuid::now_v7().to_string();
+    let session_id = create_upload_session(&client, &user.token, ws_id, &filename, "bbbcc").await;
+
+    // 1. a re-sent chunk replaces the old one
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 0, "aaa").await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 1, "cc").await, StatusCode::OK);
+
+    let url = "/api/upload_sessions/".to_string() + session_id.to_string().as_str();
+    // the content is "aaacc" and doesn't match the declared hash
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 2 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 0, "bbb").await, StatusCode::OK);
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 2 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + filename.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, "5");
+
+    // 2. a finished session is gone
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 2 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // 3. the chunks must add up to the declared size
+    let filename = "session_".to_string() + &Uuid::now_v7().to_string();
+    let session_id = create_upload_session(&client, &user.token, ws_id, &filename, "abcdef").await;
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 0, "abc").await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 1, "def").await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 2, "g").await, StatusCode::OK);
+
+    let url = "/api/upload_sessions/".to_string() + session_id.to_string().as_str();
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 3 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+async fn check_upload_session_lifecycle(app: Router) {
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let filename = "session_".to_string() + &Uuid::now_v7().to_string();
+    let session_id = create_upload_session(&client, &user.token, ws_id, &filename, "firstsecondthird").await;
+
+    // 1. upload chunk 0 and 2, chunk 1 is missing
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 2, "third").await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 0, "first").await, StatusCode::OK);
+
+    let url = "/api/upload_sessions/".to_string() + session_id.to_string().as_str();
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let status = res.json::<SessionStatus>().await;
+    assert_eq!(status.chunks, vec![0, 2]);
+    assert!(status.expires_at > time::OffsetDateTime::now_utc().unix_timestamp());
+
+    // 2. finishing with a missing chunk fails but keeps the session
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 3 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 3. abort the session
+    let res = client
+        .delete(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 1, "second").await, StatusCode::UNAUTHORIZED);
+}
+
+fn tus_checksum(data: &[u8]) -> String {
+    use base64::Engine;
+    use sha1::Digest;
+    "sha1 ".to_string() + &base64::engine::general_purpose::STANDARD.encode(sha1::Sha1::digest(data))
+}
+
+async fn tus_patch(client: &TestClient, token: &str, location: &str, offset: u64, data: &'static str, checksum: Option<String>) -> axum_test_helper::TestResponse {
+    let mut req = client
+        .patch(location)
+        .header("Authorization", "Token ".to_string() + token)
+        .header("Tus-Resumable", "1.0.0")
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", offset.to_string());
+    if let Some(checksum) = checksum {
+        req = req.header("Upload-Checksum", checksum);
+    }
+    req.body(Body::from(data)).send().await
+}
+
+#[tokio::test]
+async fn test_tus_upload() {
+    use base64::Engine;
+    let base64 = base64::engine::general_purpose::STANDARD;
+
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+    let filename = "tus_".to_string() + &Uuid::now_v7().to_string();
+
+    // 1. create an upload, the version must be given
+    let metadata = format!("filename {},ws_id {}", base64.encode(&filename), base64.encode(ws_id.to_string()));
+    let res = client
+        .post("/api/tus")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Upload-Length", "11")
+        .header("Upload-Metadata", metadata.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+    let res = client
+        .post("/api/tus")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", "11")
+        .header("Upload-Metadata", metadata.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert!(res.headers().contains_key("upload-expires"));
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+
+    // 2. append in two requests, checking offsets and checksums,
+    // only one of concurrent requests at the same offset appends
+    let (first, second) = tokio::join!(
+        tus_patch(&client, &user.token, &location, 0, "hello ", Some(tus_checksum(b"hello "))),
+        tus_patch(&client, &user.token, &location, 0, "hello ", Some(tus_checksum(b"hello "))),
+    );
+    let mut statuses = vec![first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(statuses, vec![StatusCode::NO_CONTENT, StatusCode::CONFLICT]);
+    let res = if first.status() == StatusCode::NO_CONTENT { first } else { second };
+    assert_eq!(res.headers()["upload-offset"], "6");
+
+    let res = tus_patch(&client, &user.token, &location, 0, "hello ", None).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let res = tus_patch(&client, &user.token, &location, 6, "world", Some(tus_checksum(b"other"))).await;
+    assert_eq!(res.status().as_u16(), 460);
+
+    let res = client
+        .head(&location)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Tus-Resumable", "1.0.0")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["upload-offset"], "6");
+    assert_eq!(res.headers()["upload-length"], "11");
+
+    let res = tus_patch(&client, &user.token, &location, 6, "world", Some(tus_checksum(b"world"))).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers()["upload-offset"], "11");
+
+    // 3. the completed upload is a file
+    let res = client
+        .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + filename.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, "11");
+
+    // 4. terminate another upload
+    let res = client
+        .post("/api/tus")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", "3")
+        .header("Upload-Metadata", format!("filename {},ws_id {}",
+            base64.encode(filename.clone() + "_2"), base64.encode(ws_id.to_string())))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+
+    let res = client
+        .delete(&location)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Tus-Resumable", "1.0.0")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = client
+        .head(&location)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("Tus-Resumable", "1.0.0")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_instant_upload() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    // 1. store the content once, it's unique to this test
+    let suffix = Uuid::now_v7().to_string();
+    let content: &'static str = Box::leak(format!("instant upload content {}", suffix).into_boxed_str());
+    upload_file(&client, &user.token, ws_id, -1, &("instant_src_".to_string() + &suffix), content).await;
+
+    // 2. a session for the same content gets a challenge
+    let filename = "instant_dst_".to_string() + &suffix;
+    let upload_session_req = CreateSessionReq {
+        filename: filename.clone(),
+        parent_dir_id: -1,
+        ws_id,
+        total_size: content.len() as u64,
+        file_hash: cloud_utils::digest::sha256_digest(&Bytes::from(content)),
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post("/api/upload_sessions")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&upload_session_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let session = res.json::<Session>().await;
+    let challenge = session.challenge.unwrap();
+
+    // 3. the hash alone is not enough
+    let url = "/api/upload_sessions/".to_string() + session.session_id.to_string().as_str() + "/instant";
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&InstantUploadReq { proof: upload_session_req.file_hash.clone() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let range = &content.as_bytes()[challenge.offset as usize..(challenge.offset + challenge.length) as usize];
+    let res = client
+        .post(&url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&InstantUploadReq { proof: cloud_utils::digest::sha256_digest(&Bytes::copy_from_slice(range)) })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, content.len().to_string());
+
+    let res = client
+        .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + filename.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // 4. unknown content gets no challenge
+    let res = client
+        .post("/api/upload_sessions")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&CreateSessionReq {
+            filename: "instant_unknown_".to_string() + &suffix,
+            parent_dir_id: -1,
+            ws_id,
+            total_size: 3,
+            file_hash: cloud_utils::digest::sha256_digest(&Bytes::from(suffix.clone())),
+            conflict: ConflictPolicy::Fail,
+        })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.json::<Session>().await.challenge.is_none());
+}
+
+#[tokio::test]
+async fn test_delta_upload() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    // 1. store the first version in three slices, they're unique to this test
+    let suffix = Uuid::now_v7().to_string();
+    let slices: Vec<&'static str> = ["a", "b", "x"].iter()
+        .map(|prefix| &*Box::leak(format!("{}{}", prefix, suffix).into_boxed_str()))
+        .collect();
+    let content: &'static str = Box::leak(format!("{}{}c", slices[0], slices[1]).into_boxed_str());
+    let filename = "delta_".to_string() + &suffix;
+    let session_id = create_upload_session(&client, &user.token, ws_id, &filename, content).await;
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 0, slices[0]).await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 1, slices[1]).await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, session_id, 2, "c").await, StatusCode::OK);
+    let res = client
+        .post(&("/api/upload_sessions/".to_string() + session_id.to_string().as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 3 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let path_url = "/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + filename.as_str();
+    let res = client
+        .get(&path_url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    let storage = res.json::<StorageBody<Storage>>().await.storage;
+
+    // 2. only the changed slice of the new version is missing, the size claimed for
+    // the shared slice is ignored
+    let tail: &'static str = Box::leak(suffix[..8].to_string().into_boxed_str());
+    let new_slices = [slices[0], slices[2], tail];
+    let new_content = new_slices.concat();
+    let delta_req = DeltaReq {
+        slices_hash: new_slices.iter().map(|slice| cloud_utils::digest::sha256_digest(&Bytes::from(*slice))).collect(),
+        slices_size: new_slices.iter().enumerate().map(|(index, slice)| if index == 0 { 1 } else { slice.len() }).collect(),
+        file_hash: cloud_utils::digest::sha256_digest(&Bytes::from(new_content.clone())),
+    };
+    let res = client
+        .post(&("/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + storage.id.as_str() + "/delta"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&delta_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let delta = res.json::<DeltaResp>().await;
+    assert_eq!(delta.missing, vec![1, 2]);
+
+    // 3. upload the missing slices and finish the session
+    assert_eq!(upload_chunk(&client, &user.token, delta.session_id, 1, slices[2]).await, StatusCode::OK);
+    assert_eq!(upload_chunk(&client, &user.token, delta.session_id, 2, tail).await, StatusCode::OK);
+    let res = client
+        .post(&("/api/upload_sessions/".to_string() + delta.session_id.to_string().as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UploadFinishReq { total_chunk_num: 3 })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(&path_url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    let updated = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(updated.id, storage.id);
+    assert_eq!(updated.size, new_content.len().to_string());
+}
+
+#[tokio::test]
+async fn test_batch_upload() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let dir_name = "batch_".to_string() + &Uuid::now_v7().to_string();
+    let dir = create_dir(&client, &user.token, ws_id, -1, &dir_name).await;
+
+    let part = |path: &str, content: &'static str| {
+        reqwest::multipart::Part::bytes(content.as_bytes()).file_name(path.to_string())
+    };
+    let form = reqwest::multipart::Form::new()
+        .part("file", part("photos/2023/a.jpg", "aaa"))
+        .part("file", part("photos/2023/b.jpg", "bb"))
+        .part("file", part("photos/empty/", ""))
+        .part("file", part("photos/../c.jpg", "c"))
+        .part("file", part("photos/2023/a.jpg", "again"))
+        .part("file", part("readme.txt", "hello"));
+    let res = client
+        .post(&("/api/".to_string() + ws_id.to_string().as_str() + "/storages/batch?parent_dir_id=" + dir.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .multipart(form)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let entries = res.json::<BatchUploadResp>().await.entries;
+
+    let statuses: Vec<u16> = entries.iter().map(|entry| entry.status).collect();
+    assert_eq!(statuses, vec![200, 200, 200, 422, 409, 200]);
+    assert_eq!(entries[0].storage.as_ref().unwrap().size, "3");
+    // both files are in the same dir
+    assert_eq!(entries[0].storage.as_ref().unwrap().parent_dir_id, entries[1].storage.as_ref().unwrap().parent_dir_id);
+    assert!(entries[2].storage.as_ref().unwrap().is_dir);
+    assert_eq!(entries[5].storage.as_ref().unwrap().parent_dir_id, dir.id);
+    assert!(entries[4].error.is_some());
+
+    for path in ["photos/2023/a.jpg", "photos/2023/b.jpg", "photos/empty", "readme.txt"] {
+        let res = client
+            .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + dir_name.as_str() + "/" + path))
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_archive_download_and_extract() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    // 1. a dir with a sub dir and two files
+    let dir_name = "archive_".to_string() + &Uuid::now_v7().to_string();
+    let dir = create_dir(&client, &user.token, ws_id, -1, &dir_name).await;
+    let sub_dir = create_dir(&client, &user.token, ws_id, dir.id.parse().unwrap(), "docs").await;
+    upload_file(&client, &user.token, ws_id, sub_dir.id.parse().unwrap(), "a.txt", "hello").await;
+    upload_file(&client, &user.token, ws_id, dir.id.parse().unwrap(), "b.txt", "archive").await;
+
+    for format in ["zip", "tar"] {
+        // 2. download it as an archive
+        let res = client
+            .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/archives?format=" + format + "&ids=" + dir.id.as_str()))
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let disposition = res.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.ends_with(&format!("{}.{}", dir_name, format)));
+        let archive = res.bytes().await;
+
+        // 3. extract it into another dir
+        let target_name = "extract_".to_string() + &Uuid::now_v7().to_string();
+        let target = create_dir(&client, &user.token, ws_id, -1, &target_name).await;
+        let res = client
+            .post(&("/api/".to_string() + ws_id.to_string().as_str() + "/archives?parent_dir_id=" + target.id.as_str()))
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .body(archive)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let entries = res.json::<BatchUploadResp>().await.entries;
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().all(|entry| entry.status == 200), "{:?}", entries);
+
+        for (path, size) in [("docs/a.txt", "5"), ("b.txt", "7")] {
+            let res = client
+                .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + target_name.as_str() + "/" + dir_name.as_str() + "/" + path))
+                .header("Authorization", "Token ".to_string() + user.token.as_str())
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+            assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, size);
+        }
+    }
+
+    // 4. anything else is not an archive
+    let res = client
+        .post(&("/api/".to_string() + ws_id.to_string().as_str() + "/archives?parent_dir_id=" + dir.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .body("not an archive")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+async fn upload_with_policy(client: &TestClient, token: &str, ws_id: Uuid, parent_dir_id: i64, filename: &str,
+                            content: &'static str, conflict: ConflictPolicy) -> axum_test_helper::TestResponse {
+    let upload_file_req = UploadFileReq {
+        filename: filename.to_string(),
+        is_dir: false,
+        parent_dir_id,
+        conflict,
+    };
+    client
+        .post(&("/api/".to_string() + ws_id.to_string().as_str() + "/storages"))
+        .header("Authorization", "Token ".to_string() + token)
+        .header("x-mycloud", serde_json::to_string(&upload_file_req).unwrap())
+        .body(Body::from(content))
+        .send()
+        .await
+}
+
+#[tokio::test]
+async fn test_conflict_policy() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+
+    let dir = create_dir(&client, &user.token, ws_id, -1, &("conflict_".to_string() + &Uuid::now_v7().to_string())).await;
+    let dir_id = dir.id.parse::<i64>().unwrap();
+    let report = upload_file(&client, &user.token, ws_id, dir_id, "report.pdf", "v1").await;
+
+    // 1. fail by default
+    let res = upload_with_policy(&client, &user.token, ws_id, dir_id, "report.pdf", "v2", ConflictPolicy::Fail).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // 2. rename takes the first free name
+    let mut renamed = vec![];
+    for expected in ["report (1).pdf", "report (2).pdf"] {
+        let res = upload_with_policy(&client, &user.token, ws_id, dir_id, "report.pdf", "v2", ConflictPolicy::Rename).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let storage = res.json::<StorageBody<Storage>>().await.storage;
+        assert_eq!(storage.filename, expected);
+        renamed.push(storage);
+    }
+
+    // 3. overwrite stores a new version of the same file
+    let res = upload_with_policy(&client, &user.token, ws_id, dir_id, "report.pdf", "version 3", ConflictPolicy::Overwrite).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let overwritten = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(overwritten.id, report.id);
+    assert_eq!(overwritten.size, "9");
+
+    // 4. skip keeps the existing file
+    let res = upload_with_policy(&client, &user.token, ws_id, dir_id, "report.pdf", "v4", ConflictPolicy::Skip).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let skipped = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(skipped.id, report.id);
+    assert_eq!(skipped.size, "9");
+
+    // 5. moving onto a taken name fails or renames
+    let other = upload_file(&client, &user.token, ws_id, -1, &("report_".to_string() + &Uuid::now_v7().to_string()), "other").await;
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + other.id.as_str() + "/move";
+    for (conflict, status) in [(ConflictPolicy::Fail, StatusCode::CONFLICT), (ConflictPolicy::Rename, StatusCode::OK)] {
+        let res = client
+            .post(&url)
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .json(&MoveFileReq { parent_dir_id: dir_id, filename: Some("report.pdf".to_string()), conflict })
+            .send()
+            .await;
+        assert_eq!(res.status(), status);
+        if status == StatusCode::OK {
+            assert_eq!(res.json::<StorageBody<Storage>>().await.storage.filename, "report (3).pdf");
+        }
+    }
+    // or overwrites, the moved content is a new version of the target and only counts there,
+    // and the source is gone
+    let storage_url = |id: &str| "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + id;
+    let get_size = |id: String| {
+        let req = client.get(&storage_url(&id)).header("Authorization", "Token ".to_string() + user.token.as_str());
+        async move { req.send().await.json::<StorageBody<Storage>>().await.storage.size }
+    };
+    let dir_size = get_size(dir.id.clone()).await.parse::<i64>().unwrap();
+    let moved = upload_file(&client, &user.token, ws_id, -1, &("moved_".to_string() + &Uuid::now_v7().to_string()), "moved content").await;
+    let res = client
+        .post(&(storage_url(&moved.id) + "/move"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&MoveFileReq { parent_dir_id: dir_id, filename: Some("report (2).pdf".to_string()), conflict: ConflictPolicy::Overwrite })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let overwritten = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!((overwritten.id.as_str(), overwritten.size.as_str()), (renamed[1].id.as_str(), "13"));
+    let res = client.get(&storage_url(&moved.id)).header("Authorization", "Token ".to_string() + user.token.as_str()).send().await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(get_size(dir.id.clone()).await.parse::<i64>().unwrap(), dir_size + 13 - 2);
+
+    // 6. a copy onto a taken name fails, or overwrites at once without a running job
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + report.id.as_str() + "/copy";
+    for (conflict, status) in [(ConflictPolicy::Fail, StatusCode::CONFLICT), (ConflictPolicy::Overwrite, StatusCode::OK)] {
+        let req = CopyFileReq {
+            parent_dir_id: dir_id,
+            ws_id: None,
+            filename: Some("report (1).pdf".to_string()),
+            conflict,
+        };
+        let res = client
+            .post(&url)
+            .header("Authorization", "Token ".to_string() + user.token.as_str())
+            .json(&req)
+            .send()
+            .await;
+        assert_eq!(res.status(), status);
+        if status == StatusCode::OK {
+            let copy_job = res.json::<CopyJob>().await;
+            assert_eq!(copy_job.status, "done");
+            assert_eq!(copy_job.id, Some(renamed[0].id.clone()));
+        }
+    }
+    let res = client
+        .get(&("/api/".to_string() + ws_id.to_string().as_str() + "/storages/" + renamed[0].id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.size, "9");
+}
+
+async fn create_ws(client: &TestClient, token: &str, name: &str, case_insensitive: bool) -> Workspaces {
+    let ws_req = WsBody {
+        ws: WsReq {
+            name: name.to_string(),
+            case_insensitive,
+        }
+    };
+    let res = client
+        .post("/api/workspaces")
+        .header("Authorization", "Token ".to_string() + token)
+        .json(&ws_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<WsBody<Workspaces>>().await.ws
+}
+
+#[tokio::test]
+async fn test_name_uniqueness() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_a = create_ws(&client, &user.token, "names_a", false).await.id;
+    let ws_b = create_ws(&client, &user.token, "names_b", true).await;
+    assert!(ws_b.case_insensitive);
+    let ws_b = ws_b.id;
+
+    // 1. the root dirs of two workspaces don't share names
+    let notes = upload_file(&client, &user.token, ws_a, -1, "notes.txt", "a").await;
+    upload_file(&client, &user.token, ws_b, -1, "notes.txt", "b").await;
+
+    // 2. a deleted name can be taken again
+    let res = client
+        .delete(&("/api/".to_string() + ws_a.to_string().as_str() + "/storages/" + notes.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    upload_file(&client, &user.token, ws_a, -1, "notes.txt", "again").await;
+
+    // 3. only the case-insensitive workspace ignores the case
+    let res = upload_with_policy(&client, &user.token, ws_a, -1, "NOTES.txt", "a", ConflictPolicy::Fail).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = upload_with_policy(&client, &user.token, ws_b, -1, "NOTES.txt", "b", ConflictPolicy::Fail).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = client
+        .get(&("/api/".to_string() + ws_b.to_string().as_str() + "/paths/Notes.TXT"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.filename, "notes.txt");
+
+    // 4. names are stored in NFC, "e" with a combining accent is "é"
+    let url = "/api/".to_string() + ws_a.to_string().as_str() + "/paths/";
+    let res = client
+        .post(&(url.clone() + "cafe%CC%81.txt"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .body("coffee")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cafe = res.json::<StorageBody<Storage>>().await.storage;
+    assert_eq!(cafe.filename, "caf\u{e9}.txt");
+    let res = client
+        .post(&(url.clone() + "caf%C3%A9.txt"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .body("coffee")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = client
+        .get(&(url + "cafe%CC%81.txt"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // a rename answers with the stored name
+    let res = client
+        .put(&("/api/".to_string() + ws_a.to_string().as_str() + "/storages/" + cafe.id.as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&UpdateFileReq { filename: "the\u{301}.txt".to_string() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<StorageBody<Storage>>().await.storage.filename, "th\u{e9}.txt");
+}
+
+#[tokio::test]
+async fn test_name_validation() {
+    let mut config = load_config();
+    config.reserved_names = vec!["Thumbs.db".to_string()];
+    let client = TestClient::new(init_env_with(config.clone()).await);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+    let dir = create_dir(&client, &user.token, ws_id, -1, &("names_".to_string() + &Uuid::now_v7().to_string())).await;
+    let dir_id = dir.id.parse::<i64>().unwrap();
+
+    // 1. names which are never valid, invalid on windows or reserved
+    let long_name = "a".repeat(256);
+    for filename in ["", "..", "a\u{1}b", long_name.as_str(), "a:b", "con.txt", "draft.", "thumbs.DB"] {
+        let res = upload_with_policy(&client, &user.token, ws_id, dir_id, filename, "x", ConflictPolicy::Fail).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{:?}", filename);
+        let body = res.json::<serde_json::Value>().await;
+        assert!(body["errors"]["filename"].is_array(), "{:?}", filename);
+    }
+
+    // 2. every dir a path creates is checked
+    let url = "/api/".to_string() + ws_id.to_string().as_str() + "/paths/" + dir.filename.as_str();
+    let res = client
+        .post(&(url.clone() + "/a%3Fb/c.txt?parents=true"))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .body("x")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(res.json::<serde_json::Value>().await["errors"]["path"][0].as_str().unwrap().starts_with("\"a?b\""));
+
+    // 3. the windows rules can be turned off
+    config.windows_compatible_names = false;
+    let client = TestClient::new(init_env_with(config).await);
+    for filename in ["a:b", "con.txt", "draft."] {
+        let res = upload_with_policy(&client, &user.token, ws_id, dir_id, filename, "x", ConflictPolicy::Fail).await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", filename);
+    }
+    let res = upload_with_policy(&client, &user.token, ws_id, dir_id, "a/b", "x", ConflictPolicy::Fail).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+async fn get_current_user(client: &TestClient, token: &str) -> StatusCode {
+    client
+        .get("/api/users")
+        .header("Authorization", "Token ".to_string() + token)
+        .send()
+        .await
+        .status()
+}
+
+async fn refresh(client: &TestClient, refresh_token: &str) -> axum_test_helper::TestResponse {
+    client
+        .post("/api/users/refresh")
+        .json(&RefreshReq { refresh_token: Some(refresh_token.to_string()) })
+        .send()
+        .await
+}
+
+#[tokio::test]
+async fn test_refresh_tokens() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+
+    // a user of its own, its tokens get revoked
+    let email = "refresh_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let new_user = UserBody {
+        user: NewUser {
+            username: "refresh".to_string(),
+            email: email.clone(),
+            password: "password".to_string(),
+            invite_code: None,
+        }
+    };
+    let res = client.post("/api/users").json(&new_user).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let first = res.json::<UserBody<User>>().await.user;
+    let first_refresh = first.refresh_token.clone().unwrap();
+    assert_eq!(get_current_user(&client, &first.token).await, StatusCode::OK);
+    // the current user comes with the token it was asked with, only a refresh mints one
+    let res = client.get("/api/users").header("Authorization", "Bearer ".to_string() + first.token.as_str()).send().await;
+    assert_eq!(res.json::<UserBody<User>>().await.user.token, first.token);
+
+    // 1. a refresh token is exchanged once
+    let res = refresh(&client, &first_refresh).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let second = res.json::<UserBody<User>>().await.user;
+    assert_ne!(second.refresh_token, first.refresh_token);
+    assert_eq!(get_current_user(&client, &second.token).await, StatusCode::OK);
+
+    // 2. using it again revokes every token of the user
+    assert_eq!(refresh(&client, &first_refresh).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(get_current_user(&client, &second.token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(refresh(&client, second.refresh_token.as_ref().unwrap()).await.status(), StatusCode::UNAUTHORIZED);
+
+    // 3. a new password revokes the old tokens, the session goes on with the new ones
+    let login = UserBody { user: LoginUser { email: email.clone(), password: "password".to_string() } };
+    let old = client.post("/api/users/login").json(&login).send().await.json::<UserBody<User>>().await.user;
+    let res = client
+        .put("/api/users")
+        .header("Authorization", "Token ".to_string() + old.token.as_str())
+        .json(&serde_json::json!({ "user": { "password": "new password" } }))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let updated = res.json::<UserBody<User>>().await.user;
+    assert_eq!(updated.email, email);
+    assert_eq!(get_current_user(&client, &old.token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(refresh(&client, old.refresh_token.as_ref().unwrap()).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(get_current_user(&client, &updated.token).await, StatusCode::OK);
+
+    // 4. logout ends one session, or all of them
+    let res = client
+        .post("/api/users/logout")
+        .header("Authorization", "Token ".to_string() + updated.token.as_str())
+        .json(&LogoutReq { refresh_token: updated.refresh_token.clone(), all: false })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(refresh(&client, updated.refresh_token.as_ref().unwrap()).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(get_current_user(&client, &updated.token).await, StatusCode::OK);
+
+    let res = client
+        .post("/api/users/logout")
+        .header("Authorization", "Token ".to_string() + updated.token.as_str())
+        .json(&LogoutReq { refresh_token: None, all: true })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_current_user(&client, &updated.token).await, StatusCode::UNAUTHORIZED);
+}
+
+async fn create_token(client: &TestClient, token: &str, scopes: Vec<TokenScope>, ws_id: Option<Uuid>) -> ApiToken {
+    let req = TokenBody {
+        token: CreateTokenReq {
+            name: "ci".to_string(),
+            scopes,
+            ws_id,
+            expires_at: None,
+        }
+    };
+    let res = client
+        .post("/api/tokens")
+        .header("Authorization", "Token ".to_string() + token)
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<TokenBody<ApiToken>>().await.token
+}
+
+#[tokio::test]
+async fn test_api_tokens() {
+    let app = init_env().await;
+    let client = TestClient::new(app);
+    let user = user_login().await;
+
+    let ws_list = get_ws_list().await;
+    let ws_id = ws_list[0].ws.id;
+    let other_ws = create_ws(&client, &user.token, "tokens", false).await.id;
+    let list_url = |ws_id: Uuid| "/api/".to_string() + ws_id.to_string().as_str() + "/storages?parent_dir_id=-1";
+
+    // 1. a read token of one workspace lists it, and nothing else
+    let read = create_token(&client, &user.token, vec![TokenScope::Read], Some(ws_id)).await;
+    let read_secret = read.secret.clone().unwrap();
+    assert!(read_secret.starts_with(&read.prefix));
+    let res = client
+        .get(&list_url(ws_id))
+        .header("Authorization", "Token ".to_string() + read_secret.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(&list_url(other_ws))
+        .header("Authorization", "Token ".to_string() + read_secret.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = upload_with_policy(&client, &read_secret, ws_id, -1, "token_read.txt", "x", ConflictPolicy::Rename).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // 2. an upload token uploads, but can't read
+    let upload = create_token(&client, &user.token, vec![TokenScope::Upload], None).await;
+    let upload_secret = upload.secret.clone().unwrap();
+    let res = upload_with_policy(&client, &upload_secret, other_ws, -1, "token_upload.txt", "x", ConflictPolicy::Rename).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(&list_url(other_ws))
+        .header("Authorization", "Token ".to_string() + upload_secret.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // 3. no token reaches the account or the tokens
+    let write = create_token(&client, &user.token, vec![TokenScope::Write], None).await;
+    let write_secret = write.secret.clone().unwrap();
+    for url in ["/api/users", "/api/tokens"] {
+        let res = client
+            .get(url)
+            .header("Authorization", "Token ".to_string() + write_secret.as_str())
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    // 4. the list shows when a token was used, never the token itself
+    let res = client
+        .get("/api/tokens")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let tokens = res.json::<Vec<TokenBody<ApiToken>>>().await;
+    let listed = tokens.iter().find(|token| token.token.id == read.id).unwrap();
+    assert!(listed.token.last_used_at.is_some());
+    assert!(tokens.iter().all(|token| token.token.secret.is_none()));
+
+    // 5. a revoked token stops working
+    let res = client
+        .delete(&("/api/tokens/".to_string() + read.id.to_string().as_str()))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(&list_url(ws_id))
+        .header("Authorization", "Token ".to_string() + read_secret.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // 6. a token can't expire in the past
+    let req = TokenBody {
+        token: CreateTokenReq {
+            name: "expired".to_string(),
+            scopes: vec![TokenScope::Read],
+            ws_id: None,
+            expires_at: Some(1),
+        }
+    };
+    let res = client
+        .post("/api/tokens")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// the code of the authenticator app for the time step `offset` steps from now
+fn totp_code(secret: &str, offset: i64) -> String {
+    use hmac::{Hmac, Mac};
+    let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret).unwrap();
+    let step = time::OffsetDateTime::now_utc().unix_timestamp() / 30 + offset;
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(&secret).unwrap();
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    format!("{:06}", binary % 1_000_000)
+}
+
+// the challenge token of a login with two-factor authentication
+async fn login_challenge(client: &TestClient, email: &str) -> Option<String> {
+    let login = UserBody { user: LoginUser { email: email.to_string(), password: "password".to_string() } };
+    let res = client.post("/api/users/login").json(&login).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.json::<serde_json::Value>().await;
+    body["two_factor"]["challenge_token"].as_str().map(|token| token.to_string())
+}
+
+async fn login_two_factor(client: &TestClient, challenge_token: &str, code: &str) -> StatusCode {
+    client
+        .post("/api/users/login/two_factor")
+        .json(&TwoFactorLoginReq { challenge_token: challenge_token.to_string(), code: code.to_string() })
+        .send()
+        .await
+        .status()
+}
+
+#[tokio::test]
+async fn test_two_factor() {
+    let config = load_config();
+    let client = TestClient::new(init_env_with(config.clone()).await);
+
+    let email = "totp_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let new_user = UserBody {
+        user: NewUser {
+            username: "totp".to_string(),
+            email: email.clone(),
+            password: "password".to_string(),
+            invite_code: None,
+        }
+    };
+    let res = client.post("/api/users").json(&new_user).send().await;
+    let user = res.json::<UserBody<User>>().await.user;
+
+    // 1. enroll, and confirm with a code of the secret
+    let res = client
+        .post("/api/users/two_factor")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let enrollment = res.json::<TwoFactorBody<TotpEnrollment>>().await.two_factor;
+    assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/"));
+    assert!(enrollment.provisioning_uri.contains(&enrollment.secret));
+    assert!(login_challenge(&client, &email).await.is_none());
+
+    let confirm_url = "/api/users/two_factor/confirm";
+    let res = client
+        .post(confirm_url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&TotpCodeReq { code: "000000x".to_string() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let code = totp_code(&enrollment.secret, 0);
+    let res = client
+        .post(confirm_url)
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&TotpCodeReq { code: code.clone() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let recovery_codes = res.json::<TwoFactorBody<RecoveryCodes>>().await.two_factor.recovery_codes;
+    assert_eq!(recovery_codes.len(), 10);
+
+    // 2. a login needs the second step, a code works once
+    let challenge = login_challenge(&client, &email).await.unwrap();
+    assert_eq!(login_two_factor(&client, &challenge, &code).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(login_two_factor(&client, "not a token", &totp_code(&enrollment.secret, 1)).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(login_two_factor(&client, &challenge, &totp_code(&enrollment.secret, 1)).await, StatusCode::OK);
+
+    // 3. so does a recovery code, typed in any case
+    let challenge = login_challenge(&client, &email).await.unwrap();
+    assert_eq!(login_two_factor(&client, &challenge, &recovery_codes[0].to_uppercase()).await, StatusCode::OK);
+    assert_eq!(login_two_factor(&client, &challenge, &recovery_codes[0]).await, StatusCode::UNAUTHORIZED);
+
+    // 4. guessing codes to turn it off locks the account out, like guessing them at login
+    let disable = |code: String| client
+        .delete("/api/users/two_factor")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&TotpCodeReq { code })
+        .send();
+    // the reused recovery code counted already, a right password doesn't clear the guesses
+    for _ in 1..config.login_max_failures {
+        assert!(login_challenge(&client, &email).await.is_some());
+        assert_eq!(disable(totp_code(&enrollment.secret, 10)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(disable(recovery_codes[1].clone()).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    let mut redis = redis::Client::open(config.redis_connection_str.as_str()).unwrap().get_async_connection().await.unwrap();
+    redis::cmd("DEL").arg(format!("login_lock:account:{}", email)).query_async::<_, ()>(&mut redis).await.unwrap();
+
+    // 5. turning it off takes a code too
+    let res = client
+        .delete("/api/users/two_factor")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&TotpCodeReq { code: recovery_codes[1].clone() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(login_challenge(&client, &email).await.is_none());
+}
+
+// the mails the file mailer wrote to `dir`, oldest first
+fn read_mails(dir: &str) -> Vec<String> {
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect::<Vec<_>>(),
+        Err(_) => return vec![],
+    };
+    paths.sort();
+    paths.iter().map(|path| fs::read_to_string(path).unwrap()).collect()
+}
+
+// the mails of `dir` once there are `count`, the reset mails are sent after the answer
+async fn wait_mails(dir: &str, count: usize) -> Vec<String> {
+    for _ in 0..50 {
+        let mails = read_mails(dir);
+        if mails.len() >= count {
+            return mails;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    read_mails(dir)
+}
+
+// the token of the link in a mail
+fn mail_token(mail: &str) -> String {
+    let start = mail.find("token=").unwrap() + "token=".len();
+    mail[start..].split_whitespace().next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_email_verification_and_password_reset() {
+    let mut config = load_config();
+    config.mailer = MailerKind::File;
+    config.mail_dir = "/tmp/mycloud-mail-".to_string() + &Uuid::now_v7().to_string();
+    let client = TestClient::new(init_env_with(config.clone()).await);
+
+    let email = "mail_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let new_user = UserBody {
+        user: NewUser {
+            username: "mail".to_string(),
+            email: email.clone(),
+            password: "password".to_string(),
+            invite_code: None,
+        }
+    };
+    let res = client.post("/api/users").json(&new_user).send().await;
+    let user = res.json::<UserBody<User>>().await.user;
+    assert!(!user.email_verified);
+
+    // 1. the verification mail is sent on sign up, its link works once
+    let mails = read_mails(&config.mail_dir);
+    assert_eq!(mails.len(), 1);
+    assert!(mails[0].contains(&("To: ".to_string() + &email)));
+    let verify = EmailTokenReq { token: mail_token(&mails[0]) };
+    let res = client.post("/api/users/verification/confirm").json(&verify).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.post("/api/users/verification/confirm").json(&verify).send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = client
+        .get("/api/users")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert!(res.json::<UserBody<User>>().await.user.email_verified);
+
+    // 2. a reset of an unknown email looks the same, but sends nothing
+    let res = client
+        .post("/api/users/password_reset")
+        .json(&PasswordResetReq { email: "nobody_".to_string() + &email })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_mails(&config.mail_dir).len(), 1);
+
+    // 3. the reset link sets a new password once, and ends the sessions
+    let res = client
+        .post("/api/users/password_reset")
+        .json(&PasswordResetReq { email: email.clone() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let mails = wait_mails(&config.mail_dir, 2).await;
+    assert_eq!(mails.len(), 2);
+    // another request so soon doesn't mail the email again
+    let res = client
+        .post("/api/users/password_reset")
+        .json(&PasswordResetReq { email: email.clone() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(wait_mails(&config.mail_dir, 3).await.len(), 2);
+    // a verification token doesn't reset a password
+    let res = client
+        .post("/api/users/password_reset/confirm")
+        .json(&NewPasswordReq { token: verify.token.clone(), password: "new password".to_string() })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let reset = NewPasswordReq { token: mail_token(&mails[1]), password: "new password".to_string() };
+    let res = client.post("/api/users/password_reset/confirm").json(&reset).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.post("/api/users/password_reset/confirm").json(&reset).send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(get_current_user(&client, &user.token).await, StatusCode::UNAUTHORIZED);
+
+    let login = UserBody { user: LoginUser { email: email.clone(), password: "new password".to_string() } };
+    let res = client.post("/api/users/login").json(&login).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let _ = fs::remove_dir_all(&config.mail_dir);
+}
+
+// an address nobody else logs in from, the failures are counted by address too
+fn random_ip() -> String {
+    let bytes = Uuid::now_v7().into_bytes();
+    format!("10.{}.{}.{}", bytes[13], bytes[14], bytes[15])
+}
+
+async fn login_from(client: &TestClient, ip: &str, email: &str, password: &str) -> axum_test_helper::TestResponse {
+    let login = UserBody { user: LoginUser { email: email.to_string(), password: password.to_string() } };
+    client.post("/api/users/login").header("X-Forwarded-For", ip).json(&login).send().await
+}
+
+#[tokio::test]
+async fn test_brute_force_protection() {
+    let mut config = load_config();
+    config.trust_forwarded_for = true;
+    config.login_max_failures = 3;
+    config.login_max_ip_failures = 5;
+    config.login_lockout = 60;
+    config.login_max_lockout = 3600;
+    let client = TestClient::new(init_env_with(config.clone()).await);
+
+    let email = "guard_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let new_user = UserBody {
+        user: NewUser {
+            username: "guard".to_string(),
+            email: email.clone(),
+            password: "password".to_string(),
+            invite_code: None,
+        }
+    };
+    let res = client.post("/api/users").json(&new_user).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // 1. a login resets the failures of the account
+    let ip = random_ip();
+    for _ in 0..2 {
+        assert_eq!(login_from(&client, &ip, &email, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(login_from(&client, &ip, &email, "password").await.status(), StatusCode::OK);
+    for _ in 0..2 {
+        assert_eq!(login_from(&client, &ip, &email, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // 2. too many failures lock the account out, even with the right password and from elsewhere
+    assert_eq!(login_from(&client, &ip, &email, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+    let res = login_from(&client, &random_ip(), &email, "password").await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+
+    let pool = PgPoolOptions::new().connect(&config.db_connection_str).await.unwrap();
+    let (event, audit_ip): (String, Option<String>) = sqlx::query_as(
+        "SELECT a.event, a.ip FROM audit_logs a JOIN users u ON u.id = a.user_id WHERE u.email = $1")
+        .bind(&email)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(event, "login_locked_out");
+    assert_eq!(audit_ip, Some(ip.clone()));
+
+    // 3. an unknown email is just unauthorized
+    let unknown = "nobody_".to_string() + &email;
+    assert_eq!(login_from(&client, &random_ip(), &unknown, "password").await.status(), StatusCode::UNAUTHORIZED);
+
+    // 4. too many failures from an address lock it out, for every account
+    let ip = random_ip();
+    for n in 0..5 {
+        let email = format!("nobody_{}_{}", n, email);
+        assert_eq!(login_from(&client, &ip, &email, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+    }
+    let other = format!("other_{}", email);
+    assert_eq!(login_from(&client, &ip, &other, "wrong").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(login_from(&client, &random_ip(), &other, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+}
+
+async fn sign_up(client: &TestClient, email: &str, invite_code: Option<String>) -> axum_test_helper::TestResponse {
+    let new_user = UserBody {
+        user: NewUser {
+            username: "admin_test".to_string(),
+            email: email.to_string(),
+            password: "password".to_string(),
+            invite_code,
+        }
+    };
+    client.post("/api/users").json(&new_user).send().await
+}
+
+async fn update_account(client: &TestClient, token: &str, id: Uuid, req: UpdateAccountReq) -> axum_test_helper::TestResponse {
+    client
+        .put(&format!("/api/admin/users/{}", id))
+        .header("Authorization", "Token ".to_string() + token)
+        .json(&UserBody { user: req })
+        .send()
+        .await
+}
+
+#[tokio::test]
+async fn test_admin_and_registration() {
+    let config = load_config();
+    let client = TestClient::new(init_env_with(config.clone()).await);
+    let pool = PgPoolOptions::new().connect(&config.db_connection_str).await.unwrap();
+
+    let marker = Uuid::now_v7().to_string();
+    let admin_email = format!("admin_{}@test.com", marker);
+    let admin = sign_up(&client, &admin_email, None).await.json::<UserBody<User>>().await.user;
+    sqlx::query("UPDATE users SET role = 'admin' WHERE email = $1").bind(&admin_email).execute(&pool).await.unwrap();
+    let user_email = format!("user_{}@test.com", marker);
+    let user = sign_up(&client, &user_email, None).await.json::<UserBody<User>>().await.user;
+
+    // 1. only an admin reaches the admin routes
+    let res = client
+        .get("/api/admin/users")
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // 2. users are found by a part of the email
+    let res = client
+        .get(&format!("/api/admin/users?q={}", marker))
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let accounts = res.json::<Vec<UserBody<UserAccount>>>().await;
+    assert_eq!(accounts.len(), 2);
+    let account = accounts.into_iter().find(|account| account.user.email == user_email).unwrap().user;
+    assert_eq!(account.role, UserRole::User);
+    assert_eq!(account.quota, None);
+    assert_eq!(account.usage, 0);
+    let admin_id = sqlx::query_scalar::<_, Uuid>("SELECT id FROM users WHERE email = $1")
+        .bind(&admin_email)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    // 3. uploads past the quota are refused
+    let ws = create_ws(&client, &user.token, "quota", false).await;
+    let small = upload_file(&client, &user.token, ws.id, -1, "small.txt", "0123456789").await;
+    let res = update_account(&client, &admin.token, account.id, UpdateAccountReq {
+        quota: Some(Some(15)),
+        ..Default::default()
+    }).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let updated = res.json::<UserBody<UserAccount>>().await.user;
+    assert_eq!((updated.quota, updated.usage), (Some(15), 10));
+    let upload_file_req = UploadFileReq {
+        filename: "big.txt".to_string(),
+        is_dir: false,
+        parent_dir_id: -1,
+        conflict: ConflictPolicy::Fail,
+    };
+    let res = client
+        .post(&format!("/api/{}/storages", ws.id))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .header("x-mycloud", serde_json::to_string(&upload_file_req).unwrap())
+        .body(Body::from("0123456789"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+    let dir = create_dir(&client, &user.token, ws.id, -1, "dir").await;
+    upload_file(&client, &user.token, ws.id, dir.id.parse::<i64>().unwrap(), "fits.txt", "01234").await;
+    // a new version counts with what it adds
+    let delta_req = DeltaReq {
+        slices_hash: vec![cloud_utils::digest::sha256_digest(&Bytes::from("0123456789a"))],
+        slices_size: vec![11],
+        file_hash: cloud_utils::digest::sha256_digest(&Bytes::from("0123456789a")),
+    };
+    let res = client
+        .post(&format!("/api/{}/storages/{}/delta", ws.id, small.id))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .json(&delta_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+    // deleting a dir frees the size of its files
+    let res = client
+        .delete(&format!("/api/{}/storages/{}", ws.id, dir.id))
+        .header("Authorization", "Token ".to_string() + user.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = update_account(&client, &admin.token, account.id, UpdateAccountReq {
+        quota: Some(None),
+        ..Default::default()
+    }).await;
+    let updated = res.json::<UserBody<UserAccount>>().await.user;
+    assert_eq!((updated.quota, updated.usage), (None, 10));
+
+    // 4. a disabled user loses its sessions and can't log in
+    let res = update_account(&client, &admin.token, account.id, UpdateAccountReq {
+        disabled: Some(true),
+        ..Default::default()
+    }).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_current_user(&client, &user.token).await, StatusCode::UNAUTHORIZED);
+    let login = UserBody { user: LoginUser { email: user_email.clone(), password: "password".to_string() } };
+    assert_eq!(client.post("/api/users/login").json(&login).send().await.status(), StatusCode::FORBIDDEN);
+    // an admin can't lock itself out
+    let res = update_account(&client, &admin.token, admin_id, UpdateAccountReq {
+        disabled: Some(true),
+        ..Default::default()
+    }).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 5. the admin sets a new password, it works once the user is enabled again
+    update_account(&client, &admin.token, account.id, UpdateAccountReq {
+        disabled: Some(false),
+        ..Default::default()
+    }).await;
+    let res = client
+        .put(&format!("/api/admin/users/{}/password", account.id))
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .json(&UserBody { user: SetPasswordReq { password: "new password".to_string() } })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(client.post("/api/users/login").json(&login).send().await.status(), StatusCode::UNAUTHORIZED);
+    let login = UserBody { user: LoginUser { email: user_email.clone(), password: "new password".to_string() } };
+    let res = client.post("/api/users/login").json(&login).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let token = res.json::<UserBody<User>>().await.user.token;
+
+    // 6. a deleted user is gone with its workspaces, sessions and blocks
+    let session_id = create_upload_session(&client, &token, ws.id, "unfinished.txt", "0123").await;
+    assert_eq!(upload_chunk(&client, &token, session_id, 0, "0123").await, StatusCode::OK);
+    let mut redis = redis::Client::open(config.redis_connection_str.as_str()).unwrap().get_async_connection().await.unwrap();
+    let chunk_values: Vec<String> = redis::cmd("HVALS")
+        .arg(format!("{}_chunks", session_id))
+        .query_async(&mut redis)
+        .await
+        .unwrap();
+    let mut blocks: Vec<String> = sqlx::query_scalar("SELECT unnest(h.slices)::text FROM file_histories h \
+        JOIN files f ON f.id = h.fid WHERE f.uid = $1")
+        .bind(account.id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert!(!blocks.is_empty());
+    blocks.extend(chunk_values.iter().map(|value| serde_json::from_str::<serde_json::Value>(value).unwrap()["block_name"].as_str().unwrap().to_string()));
+    assert_eq!(blocks.len(), 3);
+    let res = client
+        .delete(&format!("/api/admin/users/{}", account.id))
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(&format!("/api/admin/users/{}", account.id))
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let workspaces: i64 = sqlx::query_scalar("SELECT count(*) FROM workspaces WHERE id = $1")
+        .bind(ws.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(workspaces, 0);
+    let exists: bool = redis::cmd("EXISTS").arg(session_id.to_string()).query_async(&mut redis).await.unwrap();
+    assert!(!exists);
+    let block_handler = FsHandler::new(&config.data_dir);
+    for block in &blocks {
+        assert!(block_handler.get_blocks(vec![block.as_str()]).is_err());
+    }
+
+    // 7. with invites, a code signs up one user
+    let res = client
+        .post("/api/admin/invites")
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .json(&InviteBody { invite: CreateInviteReq { note: marker.clone(), expires_at: None } })
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let invite = res.json::<InviteBody<Invite>>().await.invite;
+    let code = invite.code.clone().unwrap();
+
+    let mut invite_config = config.clone();
+    invite_config.registration = Registration::Invite;
+    let invite_client = TestClient::new(init_env_with(invite_config).await);
+    let invited_email = format!("invited_{}@test.com", marker);
+    assert_eq!(sign_up(&invite_client, &invited_email, None).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(sign_up(&invite_client, &invited_email, Some("wrong".to_string())).await.status(),
+               StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(sign_up(&invite_client, &invited_email, Some(code.clone())).await.status(), StatusCode::OK);
+    let again = format!("again_{}@test.com", marker);
+    assert_eq!(sign_up(&invite_client, &again, Some(code)).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let res = client
+        .get("/api/admin/invites")
+        .header("Authorization", "Token ".to_string() + admin.token.as_str())
+        .send()
+        .await;
+    let invites = res.json::<Vec<InviteBody<Invite>>>().await;
+    let used = invites.into_iter().find(|listed| listed.invite.id == invite.id).unwrap().invite;
+    assert!(used.code.is_none() && used.used_by.is_some());
+
+    // 8. a closed registration takes nobody
+    let mut closed_config = config.clone();
+    closed_config.registration = Registration::Closed;
+    let closed_client = TestClient::new(init_env_with(closed_config).await);
+    assert_eq!(sign_up(&closed_client, &again, None).await.status(), StatusCode::FORBIDDEN);
+    // a misspelled one fails the config rather than closing it
+    let mut value = serde_yaml::to_value(&config).unwrap();
+    value["registration"] = "opne".into();
+    assert!(serde_yaml::from_value::<Config>(value).is_err());
+}
+
+// the name=value pairs of the Set-Cookie headers
+fn set_cookies(res: &axum_test_helper::TestResponse) -> Vec<(String, String, String)> {
+    res.headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|value| {
+            let value = value.to_str().unwrap().to_string();
+            let (pair, attributes) = value.split_once(';').unwrap();
+            let (name, cookie) = pair.split_once('=').unwrap();
+            (name.to_string(), cookie.to_string(), attributes.to_string())
+        })
+        .collect()
+}
+
+fn cookie_value(cookies: &[(String, String, String)], name: &str) -> String {
+    cookies.iter().find(|(cookie, _, _)| cookie == name).unwrap().1.clone()
+}
+
+#[tokio::test]
+async fn test_cookie_sessions() {
+    let mut config = load_config();
+    config.cookie_sessions = true;
+    let client = TestClient::new(init_env_with(config.clone()).await);
+
+    let email = "cookie_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let res = sign_up(&client, &email, None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookies = set_cookies(&res);
+    assert_eq!(cookies.len(), 3);
+    for (name, _, attributes) in &cookies {
+        assert!(attributes.contains("SameSite=Lax") && attributes.contains("Secure"));
+        assert_eq!(attributes.contains("HttpOnly"), name != "mycloud_csrf");
+    }
+    let user = res.json::<UserBody<User>>().await.user;
+    let session = cookie_value(&cookies, "mycloud_session");
+    let refresh = cookie_value(&cookies, "mycloud_refresh");
+    let csrf = cookie_value(&cookies, "mycloud_csrf");
+    let cookie = format!("mycloud_session={}; mycloud_refresh={}; mycloud_csrf={}", session, refresh, csrf);
+
+    // 1. a read needs only the cookie
+    let res = client.get("/api/users").header("Cookie", cookie.as_str()).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // 2. a change needs the csrf token of the cookie in the header
+    let ws_req = WsBody { ws: WsReq { name: "cookie".to_string(), case_insensitive: false } };
+    let res = client.post("/api/workspaces").header("Cookie", cookie.as_str()).json(&ws_req).send().await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client
+        .post("/api/workspaces")
+        .header("Cookie", cookie.as_str())
+        .header("X-CSRF-Token", "wrong")
+        .json(&ws_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client
+        .post("/api/workspaces")
+        .header("Cookie", cookie.as_str())
+        .header("X-CSRF-Token", csrf.as_str())
+        .json(&ws_req)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // 3. the standard bearer scheme works like the token one, both are offered
+    let res = client.get("/api/users").header("Authorization", "Bearer ".to_string() + user.token.as_str()).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/api/users").send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let challenges = res.headers().get_all("www-authenticate").iter().map(|value| value.to_str().unwrap().to_string()).collect::<Vec<_>>();
+    assert_eq!(challenges, vec!["Token", "Bearer"]);
+
+    // 4. the refresh cookie is exchanged for new cookies, with the csrf token only
+    let res = client.post("/api/users/refresh").header("Cookie", cookie.as_str()).json(&RefreshReq::default()).send().await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client
+        .post("/api/users/refresh")
+        .header("Cookie", cookie.as_str())
+        .header("X-CSRF-Token", csrf.as_str())
+        .json(&RefreshReq::default())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookies = set_cookies(&res);
+    assert_ne!(cookie_value(&cookies, "mycloud_refresh"), refresh);
+    let csrf = cookie_value(&cookies, "mycloud_csrf");
+    let cookie = format!("mycloud_session={}; mycloud_refresh={}; mycloud_csrf={}",
+                         cookie_value(&cookies, "mycloud_session"), cookie_value(&cookies, "mycloud_refresh"), csrf);
+
+    // 5. a logout revokes the refresh cookie and removes the cookies
+    let res = client
+        .post("/api/users/logout")
+        .header("Cookie", cookie.as_str())
+        .header("X-CSRF-Token", csrf.as_str())
+        .json(&LogoutReq::default())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookies = set_cookies(&res);
+    assert_eq!(cookies.len(), 3);
+    assert!(cookies.iter().all(|(_, value, attributes)| value.is_empty() && attributes.contains("Max-Age=0")));
+    let res = client
+        .post("/api/users/refresh")
+        .header("Cookie", cookie.as_str())
+        .header("X-CSRF-Token", csrf.as_str())
+        .json(&RefreshReq::default())
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // 6. without cookie sessions the cookies are ignored
+    let client = TestClient::new(init_env().await);
+    let res = client.get("/api/users").header("Cookie", cookie.as_str()).send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // 7. SameSite=None cookies are only sent over https, without it they would be dropped
+    let mut value = serde_yaml::to_value(&config).unwrap();
+    value["cookie_same_site"] = "Lax ".into();
+    assert!(serde_yaml::from_value::<Config>(value).is_err());
+    config.cookie_same_site = SameSite::None;
+    config.cookie_secure = false;
+    assert!(init_ctx(config.clone()).await.is_err());
+    config.cookie_secure = true;
+    let client = TestClient::new(init_env_with(config).await);
+    let res = sign_up(&client, &("cookie_".to_string() + &Uuid::now_v7().to_string() + "@test.com"), None).await;
+    let cookies = set_cookies(&res);
+    assert_eq!(cookies.len(), 3);
+    assert!(cookies.iter().all(|(_, _, attributes)| attributes.contains("SameSite=None")));
+}
+
+fn jwt_header(token: &str) -> serde_json::Value {
+    use base64::Engine;
+    let header = token.split('.').next().unwrap();
+    serde_json::from_slice(&base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap()
+}
+
+async fn sign_up_token(client: &TestClient) -> String {
+    let email = "keyset_".to_string() + &Uuid::now_v7().to_string() + "@test.com";
+    let res = sign_up(client, &email, None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json::<UserBody<User>>().await.user.token
+}
+
+#[tokio::test]
+async fn test_keyset_tokens() {
+    let path = env::temp_dir().join(format!("mycloud-keys-{}.json", Uuid::now_v7()));
+    let mut config = load_config();
+    config.jwt_keyset = Some(path.to_str().unwrap().to_string());
+    let client = TestClient::new(init_env_with(config.clone()).await);
+
+    // 1. the tokens are signed by the key of the new keyset file, named by the kid
+    let token = sign_up_token(&client).await;
+    let header = jwt_header(&token);
+    assert_eq!(header["alg"], "EdDSA");
+    let kid = header["kid"].as_str().unwrap().to_string();
+    assert_eq!(get_current_user(&client, &token).await, StatusCode::OK);
+    assert!(path.exists());
+
+    // 2. the public key is published
+    let res = client.get("/.well-known/jwks.json").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("cache-control").is_some());
+    let jwks = res.json::<JwkSet>().await;
+    assert_eq!(jwks.keys.len(), 1);
+    assert_eq!((jwks.keys[0].kid.as_str(), jwks.keys[0].kty.as_str(), jwks.keys[0].crv.as_str()), (kid.as_str(), "OKP", "Ed25519"));
+
+    // 3. a key due to be replaced gets a successor, the tokens of both are trusted
+    let mut file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    file["keys"][0]["active_from"] = serde_json::json!(time::OffsetDateTime::now_utc().unix_timestamp() - config.jwt_rotation as i64);
+    fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+    let client = TestClient::new(init_env_with(config.clone()).await);
+    let jwks = client.get("/.well-known/jwks.json").send().await.json::<JwkSet>().await;
+    assert_eq!(jwks.keys.len(), 2);
+    assert_eq!(get_current_user(&client, &token).await, StatusCode::OK);
+    let new_token = sign_up_token(&client).await;
+    let new_kid = jwt_header(&new_token)["kid"].as_str().unwrap().to_string();
+    assert_ne!(new_kid, kid);
+    assert!(jwks.keys.iter().any(|key| key.kid == new_kid));
+    assert_eq!(get_current_user(&client, &new_token).await, StatusCode::OK);
+
+    // 4. an ES256 keyset doesn't trust the tokens of another keyset
+    let es_path = env::temp_dir().join(format!("mycloud-keys-{}.json", Uuid::now_v7()));
+    let mut es_config = load_config();
+    es_config.jwt_keyset = Some(es_path.to_str().unwrap().to_string());
+    es_config.jwt_algorithm = JwtAlgorithm::Es256;
+    let es_client = TestClient::new(init_env_with(es_config).await);
+    let es_token = sign_up_token(&es_client).await;
+    assert_eq!(jwt_header(&es_token)["alg"], "ES256");
+    assert_eq!(get_current_user(&es_client, &es_token).await, StatusCode::OK);
+    let jwks = es_client.get("/.well-known/jwks.json").send().await.json::<JwkSet>().await;
+    assert_eq!((jwks.keys[0].kty.as_str(), jwks.keys[0].crv.as_str()), ("EC", "P-256"));
+    assert!(jwks.keys[0].y.is_some());
+    assert_eq!(get_current_user(&es_client, &new_token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(get_current_user(&client, &es_token).await, StatusCode::UNAUTHORIZED);
+
+    // 5. an unsupported algorithm fails the config, a keyset which can't be used fails the start
+    let mut bad_config = load_config();
+    let mut value = serde_yaml::to_value(&bad_config).unwrap();
+    value["jwt_algorithm"] = "RS256".into();
+    assert!(serde_yaml::from_value::<Config>(value).is_err());
+    bad_config.jwt_keyset = Some(env::temp_dir().to_str().unwrap().to_string());
+    assert!(init_ctx(bad_config).await.is_err());
+
+    // 6. the hmac key and a keyset don't trust each other's tokens
+    let hs_client = TestClient::new(init_env().await);
+    assert_eq!(hs_client.get("/.well-known/jwks.json").send().await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(get_current_user(&hs_client, &new_token).await, StatusCode::UNAUTHORIZED);
+    let hs_token = sign_up_token(&hs_client).await;
+    assert_eq!(get_current_user(&client, &hs_token).await, StatusCode::UNAUTHORIZED);
+
+    for path in [path, es_path] {
+        fs::remove_file(path.with_extension("lock")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
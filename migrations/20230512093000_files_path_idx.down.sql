@@ -0,0 +1,2 @@
+-- Add down migration script here
+drop index if exists files_ws_id_parent_dir_id_filename_idx;
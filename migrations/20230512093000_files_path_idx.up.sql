@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- postgresql
+-- used to resolve a path level by level
+create index files_ws_id_parent_dir_id_filename_idx on files (ws_id, parent_dir_id, filename);
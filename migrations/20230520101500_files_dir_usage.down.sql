@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table files drop column total_size;
+alter table files drop column file_count;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+-- postgresql
+-- aggregated size and number of files below a dir, always 0 for files
+alter table files add column total_size bigint not null default 0;
+alter table files add column file_count bigint not null default 0;
+
+with recursive tree (root_id, id, is_dir, size) as (
+    select id, id, is_dir, size from files where is_dir and not is_deleted
+    union all
+    select t.root_id, f.id, f.is_dir, f.size from files f
+    join tree t on f.parent_dir_id = t.id
+    where not f.is_deleted
+)
+update files set total_size = usage.total_size, file_count = usage.file_count
+from (
+    select root_id,
+        coalesce(sum(size) filter (where not is_dir), 0) as total_size,
+        count(*) filter (where not is_dir) as file_count
+    from tree group by root_id
+) as usage
+where files.id = usage.root_id;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+drop table upload_session_chunks;
+drop table upload_sessions;
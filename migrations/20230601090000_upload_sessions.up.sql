@@ -0,0 +1,24 @@
+-- Add up migration script here
+-- postgresql
+-- upload sessions of deployments without redis
+create table upload_sessions (
+    id uuid not null primary key,
+    user_id uuid not null,
+    ws_id uuid not null,
+    filename varchar(255) not null,
+    parent_dir_id bigint not null,
+    -- unix timestamp, refreshed on every activity
+    expires_at bigint not null
+);
+
+create index upload_sessions_expires_at_idx on upload_sessions (expires_at);
+
+create table upload_session_chunks (
+    session_id uuid not null references upload_sessions (id) on delete cascade,
+    block_index integer not null,
+    block_name varchar(255) not null,
+    block_hash varchar(255) not null,
+    block_size bigint not null,
+
+    primary key (session_id, block_index)
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table upload_sessions drop column total_size;
+alter table upload_sessions drop column file_hash;
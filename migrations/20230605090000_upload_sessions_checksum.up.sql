@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- postgresql
+-- declared when the session is created, verified when it's finished
+alter table upload_sessions add column total_size bigint not null default 0;
+alter table upload_sessions add column file_hash varchar(64) not null default '';
@@ -0,0 +1,3 @@
+-- Add down migration script here
+drop index file_histories_file_hash_idx;
+alter table file_histories drop column file_hash;
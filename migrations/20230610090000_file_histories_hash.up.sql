@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- postgresql
+-- sha256 of the whole file, used to find content which is already stored
+alter table file_histories add column file_hash varchar(64);
+
+create index file_histories_file_hash_idx on file_histories (file_hash);
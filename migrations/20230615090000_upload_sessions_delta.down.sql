@@ -0,0 +1,3 @@
+-- Add down migration script here
+alter table upload_sessions drop column file_id;
+alter table upload_session_chunks drop column shared;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- postgresql
+-- a session with file_id uploads a new version of that file,
+-- its shared chunks are slices of stored files
+alter table upload_sessions add column file_id bigint;
+alter table upload_session_chunks add column shared boolean not null default false;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table upload_sessions drop column conflict;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- postgresql
+-- the conflict policy applied when the session is finished: fail, rename, overwrite or skip
+alter table upload_sessions add column conflict varchar(16) not null default 'fail';
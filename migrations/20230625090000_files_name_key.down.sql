@@ -0,0 +1,10 @@
+-- Add down migration script here
+-- the old constraint can only come back while no name is taken twice under its rules
+drop index files_ws_id_parent_dir_id_name_key;
+create index files_ws_id_parent_dir_id_filename_idx on files (ws_id, parent_dir_id, filename);
+alter table files add constraint files_uid_filename_parent_dir_id_key unique (uid, filename, parent_dir_id);
+drop trigger update_files_name_key on files;
+drop function update_file_name_key();
+alter table files drop column name_key;
+drop function file_name_key(uuid, text);
+alter table workspaces drop column case_insensitive;
@@ -0,0 +1,35 @@
+-- Add up migration script here
+-- postgresql
+-- names are unique among the live entries of a dir, scoped by workspace:
+-- a deleted name can be taken again, and the root dirs (-1) of two workspaces don't clash.
+-- Names are stored in unicode NFC by the application, a case-insensitive
+-- workspace compares them in lower case.
+alter table workspaces add column case_insensitive boolean not null default false;
+
+-- the key a name is compared by in workspace ws
+create function file_name_key(ws uuid, filename text) returns text as $$
+    select case when coalesce((select case_insensitive from workspaces where id = ws), false)
+        then lower(filename) else filename end
+$$ language sql stable;
+
+alter table files add column name_key varchar(255);
+-- every workspace is case sensitive so far
+update files set name_key = filename;
+alter table files alter column name_key set not null;
+
+create or replace function update_file_name_key()
+returns trigger as $$
+begin
+NEW.name_key = file_name_key(NEW.ws_id, NEW.filename);
+return NEW;
+end;
+$$ language 'plpgsql';
+
+create trigger update_files_name_key before insert or update of filename, ws_id on files
+    for each row execute procedure update_file_name_key();
+
+alter table files drop constraint files_uid_filename_parent_dir_id_key;
+drop index files_ws_id_parent_dir_id_filename_idx;
+-- also used to resolve a path level by level
+create unique index files_ws_id_parent_dir_id_name_key on files (ws_id, parent_dir_id, name_key)
+    where not is_deleted;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+drop table refresh_tokens;
+alter table users drop column token_generation;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+-- postgresql
+-- bumped to revoke every token of the user, access tokens carry the generation they were issued in
+alter table users add column token_generation integer not null default 0;
+
+create table refresh_tokens (
+    id uuid not null primary key,
+    user_id uuid not null references users (id) on delete cascade,
+    -- sha-256 of the token, the token itself is only known to the client
+    token_hash varchar(64) not null unique,
+    -- the token generation of the user when the token was issued
+    generation integer not null,
+    -- unix timestamps
+    expires_at bigint not null,
+    -- set once the token is exchanged for a new one or revoked
+    revoked_at bigint,
+    -- the token was exchanged, using it again means it may have been stolen
+    exchanged boolean not null default false
+);
+
+create index refresh_tokens_user_id_idx on refresh_tokens (user_id);
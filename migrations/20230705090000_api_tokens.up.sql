@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- postgresql
+-- personal access tokens for scripts and CI
+create table api_tokens (
+    id uuid not null primary key,
+    user_id uuid not null references users (id) on delete cascade,
+    name varchar(255) not null,
+    -- sha-256 of the token, the token itself is only shown when it's created
+    token_hash varchar(64) not null unique,
+    -- the start of the token, so the user can tell the tokens apart
+    prefix varchar(16) not null,
+    -- read, upload or write
+    scopes text[] not null,
+    -- the only workspace the token can access, every workspace when null
+    ws_id uuid references workspaces (id) on delete cascade,
+    -- unix timestamps
+    created_at bigint not null,
+    expires_at bigint,
+    last_used_at bigint
+);
+
+create index api_tokens_user_id_idx on api_tokens (user_id);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop table recovery_codes;
+alter table users drop column totp_last_step;
+alter table users drop column totp_enabled;
+alter table users drop column totp_secret;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- postgresql
+-- base32 totp secret, kept while the enrollment waits for its confirmation
+alter table users add column totp_secret varchar(64);
+alter table users add column totp_enabled boolean not null default false;
+-- the time step of the last code taken, so a code works once
+alter table users add column totp_last_step bigint not null default 0;
+
+create table recovery_codes (
+    id uuid not null primary key,
+    user_id uuid not null references users (id) on delete cascade,
+    -- argon2 hash, like a password
+    code_hash varchar(255) not null,
+    -- unix timestamp, a code works once
+    used_at bigint
+);
+
+create index recovery_codes_user_id_idx on recovery_codes (user_id);
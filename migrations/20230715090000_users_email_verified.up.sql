@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- postgresql
+-- a new email is unverified again
+alter table users add column email_verified boolean not null default false;
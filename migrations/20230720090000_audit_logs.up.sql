@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- postgresql
+-- security events, like the lockouts of a login
+create table audit_logs (
+    id uuid not null primary key,
+    -- null for the events of an ip address
+    user_id uuid references users (id) on delete set null,
+    event varchar(64) not null,
+    ip varchar(64),
+    detail text not null default '',
+    -- unix timestamp
+    created_at bigint not null
+);
+
+create index audit_logs_user_id_idx on audit_logs (user_id, created_at);
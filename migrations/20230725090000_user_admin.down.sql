@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop table invite_codes;
+alter table users drop column quota;
+alter table users drop column disabled;
+alter table users drop column role;
@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- postgresql
+-- user or admin
+alter table users add column role varchar(16) not null default 'user';
+-- a disabled user can't log in, and its tokens are revoked
+alter table users add column disabled boolean not null default false;
+-- bytes the files of the user may take, null for no limit
+alter table users add column quota bigint;
+
+-- codes to sign up with, when the registration needs an invite
+create table invite_codes (
+    id uuid not null primary key,
+    -- sha-256 of the code, in hex
+    code_hash varchar(64) not null unique,
+    created_by uuid references users (id) on delete set null,
+    note varchar(255) not null default '',
+    -- unix timestamps
+    created_at bigint not null,
+    expires_at bigint,
+    used_by uuid references users (id) on delete set null,
+    used_at bigint
+);